@@ -4,7 +4,7 @@ use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use gameboy_emulator::cartridge::{Address, AddressingError, Cartridge, MBCControllerType};
 use gameboy_emulator::cpu::CPU;
 use gameboy_emulator::gameboy::GameBoyState;
-use gameboy_emulator::{CanvasPpu, Joypad, MemoryBus, Ppu};
+use gameboy_emulator::{BasePpu, CanvasEngine, Joypad, MemoryBus, Ppu};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -26,7 +26,9 @@ fn repeat_regular_opcode(c: &mut Criterion, name: &str, opcode: u8) {
         .map_err(|e| e.to_string())
         .unwrap();
     let creator = canvas.texture_creator();
-    let canvas_ppu = Rc::new(RefCell::new(CanvasPpu::new(&creator)));
+    let canvas_ppu = Rc::new(RefCell::new(BasePpu::new(Box::new(CanvasEngine::new(
+        &creator,
+    )))));
 
     let mut cpu = CPU::new();
     let joypad = Rc::new(RefCell::new(Joypad::new()));
@@ -63,7 +65,9 @@ fn bench_gameboy_tick(c: &mut Criterion) {
         .map_err(|e| e.to_string())
         .unwrap();
     let creator = canvas.texture_creator();
-    let canvas_ppu = Rc::new(RefCell::new(CanvasPpu::new(&creator)));
+    let canvas_ppu = Rc::new(RefCell::new(BasePpu::new(Box::new(CanvasEngine::new(
+        &creator,
+    )))));
 
     let mut gameboy = GameBoyState::new(canvas_ppu);
     let cart = MockCartridge::new(vec![0; 32 * 1024]);
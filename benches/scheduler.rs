@@ -0,0 +1,36 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use gameboy_emulator::cartridge::Cartridge;
+use gameboy_emulator::gameboy::GameBoyState;
+
+/// Runs a ROM which idles in HALT between VBlanks, like most games, for a second of emulated
+/// time with and without the scheduler.
+fn bench_scheduler(c: &mut Criterion) {
+    let mut rom = vec![0; 0x8000];
+    // LCD on, VBlank interrupts on, then HALT in a loop with an empty handler
+    let code = [
+        0x3e, 0x91, 0xe0, 0x40, 0x3e, 0x01, 0xe0, 0xff, 0xfb, 0x76, 0x18, 0xfd,
+    ];
+    rom[0x100..0x100 + code.len()].copy_from_slice(&code);
+    rom[0x40] = 0xd9;
+    let mut group = c.benchmark_group("idle 60 frames");
+    for (name, scheduler_enabled) in [("per-cycle", false), ("scheduler", true)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let cartridge = Cartridge::cartridge_from_data(&rom).unwrap();
+                let (mut gameboy, _) = GameBoyState::new_headless();
+                gameboy.load_cartridge(cartridge).unwrap();
+                gameboy.set_scheduler_enabled(scheduler_enabled);
+                gameboy.run_to_frame(60);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = scheduler_benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_scheduler
+}
+
+criterion_main!(scheduler_benches);
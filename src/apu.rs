@@ -0,0 +1,695 @@
+/*!
+ * The APU (audio processing unit) generates the gameboy's sound through four channels: two square
+ * waves, a custom wave and noise. The channels are mixed into stereo samples which are buffered
+ * until the frontend takes them.
+ *
+ * Volume envelopes and frequency sweep are not emulated yet, so channels play at their initial
+ * volume until they are stopped, or until their length counter runs out.
+ */
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::component::{Address, Addressable, ElapsedTime, Steppable};
+use crate::error::{Error, Result};
+use crate::gameboy::GameBoyState;
+use crate::memory::IO_READ_MASKS;
+
+/// Number of stereo samples produced per second.
+pub const SAMPLE_RATE: u64 = 44_100;
+/// The gameboy's clock rate in T-cycles per second.
+pub const CLOCK_RATE: u64 = 4_194_304;
+/// At most one second of samples is buffered, older samples are dropped.
+const MAX_BUFFERED_SAMPLES: usize = 2 * SAMPLE_RATE as usize;
+
+// Sound on/off, along with the status of each channel
+const NR52: usize = 0xff26;
+// The first of the sound registers, NR10
+const REGISTERS_START: usize = 0xff10;
+
+// The length register (NRx1) of each channel
+const LENGTH_REGISTERS: [usize; 4] = [0xff11, 0xff16, 0xff1b, 0xff20];
+// The trigger register (NRx4) of each channel
+const TRIGGER_REGISTERS: [usize; 4] = [0xff14, 0xff19, 0xff1e, 0xff23];
+// The register controlling each channel's DAC: NRx2 for the square and noise channels, NR30 for
+// the wave channel
+const DAC_REGISTERS: [usize; 4] = [0xff12, 0xff17, 0xff1a, 0xff21];
+
+/// NR10-NR51 as the DMG boot ROM leaves them, which CGB models match. The unused registers
+/// 0xff15 and 0xff1f are 0.
+const POWER_ON_REGISTERS: [u8; 0x16] = [
+    0x80, 0xbf, 0xf3, 0xff, 0xbf, // NR10-NR14
+    0x00, 0x3f, 0x00, 0xff, 0xbf, // NR21-NR24
+    0x7f, 0xff, 0x9f, 0xff, 0xbf, // NR30-NR34
+    0x00, 0xff, 0x00, 0x00, 0xbf, // NR41-NR44
+    0x77, 0xf3, // NR50, NR51
+];
+
+/// Waveforms for each square wave duty cycle, 12.5%, 25%, 50% and 75%, played from bit 0 to 7.
+const DUTY_PATTERNS: [u8; 4] = [0b1000_0000, 0b1000_0001, 0b1110_0001, 0b0111_1110];
+
+/// Noise channel divisors, selected by the low 3 bits of NR43
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Names the equal-tempered note nearest to a frequency, with A4 at 440 Hz.
+fn note_name(hertz: f64) -> String {
+    const NAMES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    // MIDI note numbers, where A4 is 69 and C-1 is 0
+    let midi = (69.0 + 12.0 * (hertz / 440.0).log2()).round() as i32;
+    let octave = midi.div_euclid(12) - 1;
+    format!("{}{}", NAMES[midi.rem_euclid(12) as usize], octave)
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Apu {
+    /// NR10-NR51, stored as written
+    registers: [u8; 0x16],
+    /// Waveform for channel 3, 32 4-bit samples
+    wave_ram: [u8; 16],
+    /// Bit 7 of NR52, which turns all sound on or off
+    enabled: bool,
+    /// Which of the four channels are currently playing, reported in the low bits of NR52
+    channels_on: [bool; 4],
+    /// The current volume (0-15) of channels 1, 2 and 4
+    volumes: [u8; 4],
+
+    /// T-cycles elapsed since each channel last advanced its waveform
+    frequency_timers: [u32; 4],
+    /// Position within the duty pattern of channels 1 and 2, and within wave RAM for channel 3
+    wave_positions: [u8; 3],
+    /// Linear feedback shift register which produces channel 4's noise
+    lfsr: u16,
+
+    /// The next step (0-7) of the frame sequencer, which clocks the length counters on even
+    /// steps, and will clock envelopes and sweep once they are emulated
+    frame_sequencer_step: u8,
+    /// Length counter clocks left before each channel stops, if its NRx4 enables the counter
+    length_counters: [u16; 4],
+    /// Bit 4 of DIV when the APU last stepped. The frame sequencer steps when it falls, so
+    /// resetting DIV while it's set causes an extra step.
+    div_bit: bool,
+
+    /// Accumulates SAMPLE_RATE every T-cycle, emitting a sample whenever it reaches CLOCK_RATE
+    sample_clock: u64,
+    /// Interleaved left and right samples, each from -1 to 1
+    #[serde(skip)]
+    samples: VecDeque<f32>,
+
+    /// Multiplies the mixed output, before clipping
+    master_gain: f32,
+    /// If true, the output is rounded off with tanh rather than clamped to -1 to 1
+    soft_clip: bool,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Self {
+            registers: POWER_ON_REGISTERS,
+            wave_ram: [0; 16],
+            // The boot ROM's chime leaves channel 1 on, though its envelope has faded it out
+            enabled: true,
+            channels_on: [true, false, false, false],
+            volumes: [0; 4],
+
+            frequency_timers: [0; 4],
+            wave_positions: [0; 3],
+            lfsr: 0x7fff,
+
+            frame_sequencer_step: 0,
+            length_counters: [0; 4],
+            div_bit: false,
+
+            sample_clock: 0,
+            samples: VecDeque::new(),
+
+            master_gain: 1.0,
+            soft_clip: false,
+        }
+    }
+
+    /// Returns the APU to its power-on state, keeping the output settings.
+    pub fn reset(&mut self) {
+        *self = Apu {
+            master_gain: self.master_gain,
+            soft_clip: self.soft_clip,
+            ..Apu::new()
+        };
+    }
+
+    /// Replaces the APU's state with one from a save state, keeping the output settings like
+    /// `reset` does.
+    pub(crate) fn restore_state(&mut self, saved: Apu) {
+        *self = Apu {
+            master_gain: self.master_gain,
+            soft_clip: self.soft_clip,
+            ..saved
+        };
+    }
+
+    /// Scales the final mix. The mix can already reach full scale, so gains above 1 clip.
+    pub fn set_master_gain(&mut self, gain: f32) {
+        self.master_gain = gain;
+    }
+
+    /// Chooses between soft clipping the final mix with tanh, which distorts loud output less
+    /// harshly, and hard clipping it. Hard clipping is the default.
+    pub fn set_soft_clip(&mut self, enabled: bool) {
+        self.soft_clip = enabled;
+    }
+
+    /// Applies the master gain and clipping to one side of the mix.
+    fn master_output(&self, sample: f32) -> f32 {
+        let sample = sample * self.master_gain;
+        if self.soft_clip {
+            sample.tanh()
+        } else {
+            sample.clamp(-1.0, 1.0)
+        }
+    }
+
+    /// The step (0-7) the frame sequencer will run next.
+    pub fn frame_sequencer_step(&self) -> u8 {
+        self.frame_sequencer_step
+    }
+
+    /// Steps the frame sequencer on each falling edge of DIV bit 4, 512 times a second.
+    fn clock_frame_sequencer(&mut self, div: u8) {
+        let div_bit = div & 0x10 != 0;
+        if self.div_bit && !div_bit && self.enabled {
+            if self.frame_sequencer_step & 1 == 0 {
+                self.clock_length_counters();
+            }
+            self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+        }
+        self.div_bit = div_bit;
+    }
+
+    /// Counts down the length counter of each channel whose NRx4 enables it, stopping the channel
+    /// when it reaches 0.
+    fn clock_length_counters(&mut self) {
+        for (channel, nrx4) in TRIGGER_REGISTERS.into_iter().enumerate() {
+            let length_enabled = self.register(nrx4) & 0x40 != 0;
+            if !length_enabled || self.length_counters[channel] == 0 {
+                continue;
+            }
+            self.length_counters[channel] -= 1;
+            if self.length_counters[channel] == 0 {
+                self.channels_on[channel] = false;
+            }
+        }
+    }
+
+    /// The longest a channel's length counter can run, 256 for the wave channel and 64 otherwise.
+    fn max_length(channel: usize) -> u16 {
+        if channel == 2 {
+            256
+        } else {
+            64
+        }
+    }
+
+    /// True iff sound is enabled and at least one channel is playing.
+    pub fn is_producing_sound(&self) -> bool {
+        self.enabled && self.channels_on.iter().any(|on| *on)
+    }
+
+    /// The nearest musical note, e.g. "A4", played by each of the square and wave channels. None
+    /// for channels that are off, and always for the noise channel.
+    pub fn channel_notes(&self) -> [Option<String>; 4] {
+        let mut notes = [None, None, None, None];
+        for (channel, note) in notes.iter_mut().enumerate().take(3) {
+            if !self.enabled || !self.channels_on[channel] {
+                continue;
+            }
+            // A square wave is 8 steps long, while the wave channel plays 32 samples
+            let steps = if channel == 2 { 32 } else { 8 };
+            let hertz = CLOCK_RATE as f64 / f64::from(self.period(channel) * steps);
+            *note = Some(note_name(hertz));
+        }
+        notes
+    }
+
+    /// Returns and clears the stereo samples produced since the last call, interleaved as left
+    /// then right.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.samples.drain(..).collect()
+    }
+
+    fn register(&self, address: Address) -> u8 {
+        self.registers[address - REGISTERS_START]
+    }
+
+    /// The 11 bit frequency value of channels 1-3, which is split across NRx3 and NRx4.
+    fn frequency(&self, channel: usize) -> u32 {
+        let nrx3 = TRIGGER_REGISTERS[channel] - 1;
+        u32::from(self.register(nrx3)) | (u32::from(self.register(nrx3 + 1) & 0b111) << 8)
+    }
+
+    /// Number of T-cycles between steps of a channel's waveform.
+    fn period(&self, channel: usize) -> u32 {
+        match channel {
+            0 | 1 => (2048 - self.frequency(channel)) * 4,
+            2 => (2048 - self.frequency(channel)) * 2,
+            _ => {
+                let nr43 = self.register(0xff22);
+                NOISE_DIVISORS[usize::from(nr43 & 0b111)] << (nr43 >> 4)
+            }
+        }
+    }
+
+    /// True iff a channel's DAC is on. The square and noise channels' DACs are on unless the
+    /// upper 5 bits of NRx2 are all 0, and the wave channel's is bit 7 of NR30.
+    fn dac_enabled(&self, channel: usize) -> bool {
+        let value = self.register(DAC_REGISTERS[channel]);
+        match channel {
+            2 => value & 0x80 != 0,
+            _ => value & 0xf8 != 0,
+        }
+    }
+
+    fn trigger(&mut self, channel: usize) {
+        // A channel can't be started while its DAC is off
+        self.channels_on[channel] = self.dac_enabled(channel);
+        self.frequency_timers[channel] = 0;
+        if self.length_counters[channel] == 0 {
+            self.length_counters[channel] = Self::max_length(channel);
+        }
+        match channel {
+            0 => self.volumes[0] = self.register(0xff12) >> 4,
+            1 => self.volumes[1] = self.register(0xff17) >> 4,
+            2 => self.wave_positions[2] = 0,
+            _ => {
+                self.volumes[3] = self.register(0xff21) >> 4;
+                self.lfsr = 0x7fff;
+            }
+        }
+    }
+
+    /// Advances a channel's waveform by one step.
+    fn clock_waveform(&mut self, channel: usize) {
+        match channel {
+            0 | 1 => self.wave_positions[channel] = (self.wave_positions[channel] + 1) % 8,
+            2 => self.wave_positions[2] = (self.wave_positions[2] + 1) % 32,
+            _ => {
+                let xor = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+                self.lfsr = (self.lfsr >> 1) | (xor << 14);
+                // 7 bit mode also feeds back into bit 6
+                if self.register(0xff22) & 0b1000 != 0 {
+                    self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
+                }
+            }
+        }
+    }
+
+    /// The digital output of a channel, from 0 to 15.
+    fn channel_output(&self, channel: usize) -> u8 {
+        match channel {
+            0 | 1 => {
+                let duty = self.register(TRIGGER_REGISTERS[channel] - 3) >> 6;
+                let bit = (DUTY_PATTERNS[usize::from(duty)] >> self.wave_positions[channel]) & 1;
+                bit * self.volumes[channel]
+            }
+            2 => {
+                let position = usize::from(self.wave_positions[2]);
+                let byte = self.wave_ram[position / 2];
+                let sample = if position % 2 == 0 {
+                    byte >> 4
+                } else {
+                    byte & 0xf
+                };
+                // NR32 selects between mute, 100%, 50% and 25% volume
+                match (self.register(0xff1c) >> 5) & 0b11 {
+                    0 => 0,
+                    shift => sample >> (shift - 1),
+                }
+            }
+            _ => (!self.lfsr & 1) as u8 * self.volumes[3],
+        }
+    }
+
+    /// Mixes the playing channels into a left and right sample.
+    fn mix(&self) -> (f32, f32) {
+        let nr50 = self.register(0xff24);
+        let nr51 = self.register(0xff25);
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for channel in 0..4 {
+            if !self.channels_on[channel] {
+                continue;
+            }
+            // Each channel's DAC maps 0-15 onto 1 to -1
+            let analog = 1.0 - f32::from(self.channel_output(channel)) / 7.5;
+            if nr51 & (0x10 << channel) != 0 {
+                left += analog;
+            }
+            if nr51 & (0x01 << channel) != 0 {
+                right += analog;
+            }
+        }
+
+        let left_volume = f32::from((nr50 >> 4) & 0b111) + 1.0;
+        let right_volume = f32::from(nr50 & 0b111) + 1.0;
+        (
+            left / 4.0 * left_volume / 8.0,
+            right / 4.0 * right_volume / 8.0,
+        )
+    }
+
+    fn push_sample(&mut self) {
+        let (left, right) = if self.enabled {
+            let (left, right) = self.mix();
+            (self.master_output(left), self.master_output(right))
+        } else {
+            (0.0, 0.0)
+        };
+
+        if self.samples.len() >= MAX_BUFFERED_SAMPLES {
+            self.samples.pop_front();
+            self.samples.pop_front();
+        }
+        self.samples.push_back(left);
+        self.samples.push_back(right);
+    }
+
+    fn read_nr52(&self) -> u8 {
+        let mut value = 0x70;
+        if self.enabled {
+            value |= 0x80;
+        }
+        for (channel, on) in self.channels_on.iter().enumerate() {
+            if *on {
+                value |= 1 << channel;
+            }
+        }
+        value
+    }
+
+    fn write_nr52(&mut self, value: u8) {
+        // Turning sound on starts the frame sequencer from step 0
+        if !self.enabled && value & 0x80 != 0 {
+            self.frame_sequencer_step = 0;
+        }
+        self.enabled = value & 0x80 != 0;
+        // Turning sound off clears every register and stops all channels. The channel flags
+        // themselves are read-only.
+        if !self.enabled {
+            self.registers = [0; 0x16];
+            self.channels_on = [false; 4];
+        }
+    }
+
+    fn _read(&mut self, address: Address) -> Result<u8> {
+        let value = match address {
+            0xff10..=0xff25 => self.register(address),
+            NR52 => self.read_nr52(),
+            0xff27..=0xff2f => 0xff,
+            0xff30..=0xff3f => self.wave_ram[address - 0xff30],
+            _ => return Err(Error::new("invalid address")),
+        };
+        Ok(value)
+    }
+
+    /// Reads a sound register or wave RAM (0xff10-0xff3f) as the CPU sees it, with unused and
+    /// write-only bits set.
+    pub fn read_register(&mut self, address: Address) -> Result<u8> {
+        Ok(self._read(address)? | IO_READ_MASKS[address - 0xff00])
+    }
+
+    /// Writes a sound register or wave RAM (0xff10-0xff3f) as the CPU would, triggering channels
+    /// and switching sound on and off.
+    pub fn write_register(&mut self, address: Address, value: u8) -> Result<()> {
+        match address {
+            0xff10..=0xff25 => {
+                // While sound is off, the registers can't be written
+                if !self.enabled {
+                    return Ok(());
+                }
+                self.registers[address - REGISTERS_START] = value;
+
+                // Turning a DAC off stops its channel
+                if let Some(channel) = DAC_REGISTERS.iter().position(|a| *a == address) {
+                    if !self.dac_enabled(channel) {
+                        self.channels_on[channel] = false;
+                    }
+                }
+                // The wave channel's length is all of NR31, the others' the low 6 bits of NRx1
+                if let Some(channel) = LENGTH_REGISTERS.iter().position(|a| *a == address) {
+                    let length = if channel == 2 { value } else { value & 0x3f };
+                    self.length_counters[channel] = Self::max_length(channel) - u16::from(length);
+                }
+                if let Some(channel) = TRIGGER_REGISTERS.iter().position(|a| *a == address) {
+                    if value & 0x80 != 0 {
+                        self.trigger(channel);
+                    }
+                }
+            }
+            NR52 => self.write_nr52(value),
+            0xff27..=0xff2f => {}
+            0xff30..=0xff3f => self.wave_ram[address - 0xff30] = value,
+            _ => return Err(Error::new("invalid address")),
+        }
+        Ok(())
+    }
+}
+
+impl Addressable for Apu {
+    fn read(&mut self, address: Address, data: &mut [u8]) -> Result<()> {
+        for (offset, byte) in data.iter_mut().enumerate() {
+            *byte = self.read_register(address + offset)?;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, address: Address, data: &[u8]) -> Result<()> {
+        for (offset, byte) in data.iter().enumerate() {
+            self.write_register(address + offset, *byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Steppable for Apu {
+    /// Steps the APU by one M-cycle.
+    fn step(&mut self, state: &GameBoyState) -> Result<ElapsedTime> {
+        self.clock_frame_sequencer(state.timer.borrow().div());
+
+        for channel in 0..4 {
+            if !self.channels_on[channel] {
+                continue;
+            }
+            self.frequency_timers[channel] += 4;
+            let period = self.period(channel);
+            while self.frequency_timers[channel] >= period {
+                self.frequency_timers[channel] -= period;
+                self.clock_waveform(channel);
+            }
+        }
+
+        self.sample_clock += 4 * SAMPLE_RATE;
+        if self.sample_clock >= CLOCK_RATE {
+            self.sample_clock -= CLOCK_RATE;
+            self.push_sample();
+        }
+
+        Ok(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_producing_sound_follows_channel_status() {
+        let mut apu = Apu::new();
+        // Channel 1 is left on at power on, so start from sound off
+        apu.write_u8(NR52, 0x00).unwrap();
+        assert!(!apu.is_producing_sound());
+
+        // Sound on, then trigger channel 1 at full volume
+        apu.write_u8(NR52, 0x80).unwrap();
+        assert!(!apu.is_producing_sound());
+        apu.write_u8(0xff12, 0xf0).unwrap();
+        apu.write_u8(0xff14, 0x80).unwrap();
+        assert!(apu.is_producing_sound());
+        assert_eq!(0xf1, apu.read_u8(NR52).unwrap());
+
+        // Turning sound off stops every channel
+        apu.write_u8(NR52, 0x00).unwrap();
+        assert!(!apu.is_producing_sound());
+        assert_eq!(0x70, apu.read_u8(NR52).unwrap());
+    }
+
+    #[test]
+    fn test_registers_start_with_boot_rom_values() {
+        let mut apu = Apu::new();
+        assert_eq!(0xf1, apu.read_register(NR52).unwrap());
+        assert_eq!(0x80, apu.read_register(0xff10).unwrap());
+        assert_eq!(0xf3, apu.read_register(0xff12).unwrap());
+        assert_eq!(0x77, apu.read_register(0xff24).unwrap());
+    }
+
+    #[test]
+    fn test_disabling_dac_stops_channel() {
+        let mut apu = Apu::new();
+        // Sound on with every channel sent to both outputs, then trigger channel 1
+        apu.write_u8(NR52, 0x80).unwrap();
+        apu.write_u8(0xff25, 0xff).unwrap();
+        apu.write_u8(0xff12, 0xf0).unwrap();
+        apu.write_u8(0xff14, 0x80).unwrap();
+        assert_eq!(0xf1, apu.read_u8(NR52).unwrap());
+
+        // Volume 0 with a decreasing envelope turns the DAC off
+        apu.write_u8(0xff12, 0x00).unwrap();
+        assert_eq!(0xf0, apu.read_u8(NR52).unwrap());
+        assert_eq!((0.0, 0.0), apu.mix());
+
+        // Retriggering doesn't restart the channel until the DAC is back on
+        apu.write_u8(0xff14, 0x80).unwrap();
+        assert_eq!(0xf0, apu.read_u8(NR52).unwrap());
+        apu.write_u8(0xff12, 0x08).unwrap();
+        apu.write_u8(0xff14, 0x80).unwrap();
+        assert_eq!(0xf1, apu.read_u8(NR52).unwrap());
+    }
+
+    #[test]
+    fn test_registers_read_back_readable_bits() {
+        let mut apu = Apu::new();
+        apu.write_register(NR52, 0x80).unwrap();
+
+        // Frequency 0x5a5 on channel 1, with the length counter enabled
+        apu.write_register(0xff13, 0xa5).unwrap();
+        apu.write_register(0xff14, 0x45).unwrap();
+        assert_eq!(0x5a5, apu.frequency(0));
+
+        // The frequency is write-only, so only the length enable bit reads back
+        assert_eq!(0xff, apu.read_register(0xff13).unwrap());
+        assert_eq!(0xff, apu.read_register(0xff14).unwrap());
+        apu.write_register(0xff14, 0x05).unwrap();
+        assert_eq!(0xbf, apu.read_register(0xff14).unwrap());
+    }
+
+    #[test]
+    fn test_nrx4_reads_only_length_enable() {
+        let mut apu = Apu::new();
+        apu.write_register(NR52, 0x80).unwrap();
+        for nrx4 in TRIGGER_REGISTERS {
+            apu.write_register(nrx4, 0x47).unwrap();
+            assert_eq!(0xff, apu.read_register(nrx4).unwrap(), "{:#06x}", nrx4);
+            apu.write_register(nrx4, 0x87).unwrap();
+            assert_eq!(0xbf, apu.read_register(nrx4).unwrap(), "{:#06x}", nrx4);
+        }
+    }
+
+    #[test]
+    fn test_resetting_div_glitches_frame_sequencer() {
+        let gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        let mut apu = Apu::new();
+        apu.write_register(NR52, 0x80).unwrap();
+        let step = |apu: &mut Apu, m_cycles| {
+            for _ in 0..m_cycles {
+                for _ in 0..4 {
+                    gameboy.timer.borrow_mut().step(&gameboy).unwrap();
+                }
+                apu.step(&gameboy).unwrap();
+            }
+        };
+
+        // DIV counts 16 * 256 T-cycles with bit 4 clear, then the same with it set
+        step(&mut apu, 8192 / 4);
+        assert_eq!(1, apu.frame_sequencer_step());
+
+        // Resetting DIV in the first half doesn't step, but in the second half it does
+        step(&mut apu, 512);
+        gameboy.timer.borrow_mut().write_u8(0xff04, 0).unwrap();
+        step(&mut apu, 1);
+        assert_eq!(1, apu.frame_sequencer_step());
+        step(&mut apu, 1024 + 1024 / 2);
+        gameboy.timer.borrow_mut().write_u8(0xff04, 0).unwrap();
+        step(&mut apu, 1);
+        assert_eq!(2, apu.frame_sequencer_step());
+    }
+
+    #[test]
+    fn test_length_counter_stops_channel() {
+        let gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        let mut apu = Apu::new();
+        // Cycling the power stops the boot chime's channel 1
+        apu.write_register(NR52, 0x00).unwrap();
+        apu.write_register(NR52, 0x80).unwrap();
+        // Channel 2 with 2 length clocks left, triggered with its length counter enabled
+        apu.write_register(0xff16, 62).unwrap();
+        apu.write_register(0xff17, 0xf0).unwrap();
+        apu.write_register(0xff19, 0xc0).unwrap();
+        assert_eq!(0xf2, apu.read_register(NR52).unwrap());
+
+        // The length counter is clocked on every other frame sequencer step, every 8192 T-cycles
+        for _ in 0..3 * 8192 / 4 {
+            for _ in 0..4 {
+                gameboy.timer.borrow_mut().step(&gameboy).unwrap();
+            }
+            apu.step(&gameboy).unwrap();
+        }
+        assert_eq!(3, apu.frame_sequencer_step());
+        assert_eq!(0xf0, apu.read_register(NR52).unwrap());
+    }
+
+    #[test]
+    fn test_soft_clip_keeps_loud_mix_in_range() {
+        let gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        let mut apu = Apu::new();
+        apu.set_master_gain(4.0);
+        apu.set_soft_clip(true);
+
+        // Full master volume, with both square channels at full volume on both outputs
+        apu.write_u8(NR52, 0x80).unwrap();
+        apu.write_u8(0xff24, 0x77).unwrap();
+        apu.write_u8(0xff25, 0xff).unwrap();
+        for nrx2 in [0xff12, 0xff17] {
+            apu.write_u8(nrx2, 0xf0).unwrap();
+            apu.write_u8(nrx2 + 2, 0x87).unwrap();
+        }
+        for _ in 0..10_000 {
+            apu.step(&gameboy).unwrap();
+        }
+
+        let samples = apu.take_samples();
+        assert!(!samples.is_empty());
+        assert!(samples.iter().all(|sample| (-1.0..=1.0).contains(sample)));
+        // The gain still makes the output louder than without it
+        assert!(samples.iter().any(|sample| sample.abs() > 0.9));
+    }
+
+    #[test]
+    fn test_channel_notes_names_square_channel_frequency() {
+        let mut apu = Apu::new();
+        apu.write_u8(NR52, 0x00).unwrap();
+        apu.write_u8(NR52, 0x80).unwrap();
+        assert_eq!([None, None, None, None], apu.channel_notes());
+
+        // 131072 / (2048 - 1750) is about 440 Hz, then trigger channel 1
+        apu.write_u8(0xff12, 0xf0).unwrap();
+        apu.write_u8(0xff13, (1750 & 0xff) as u8).unwrap();
+        apu.write_u8(0xff14, 0x80 | (1750 >> 8) as u8).unwrap();
+        assert_eq!(
+            [Some("A4".to_string()), None, None, None],
+            apu.channel_notes()
+        );
+
+        // An octave up
+        apu.write_u8(0xff13, (1899 & 0xff) as u8).unwrap();
+        apu.write_u8(0xff14, (1899 >> 8) as u8).unwrap();
+        assert_eq!(Some("A5".to_string()), apu.channel_notes()[0]);
+    }
+}
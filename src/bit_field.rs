@@ -1,7 +1,8 @@
+use serde::{Deserialize, Serialize};
 use std::{marker::PhantomData, mem::size_of, slice::SliceIndex};
 
 /// The generic parameter should be an integer type
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BitField<T> {
     values: Vec<bool>,
     register_type: PhantomData<T>,
@@ -1,27 +1,388 @@
 use crate::bit_field::BitField;
+use crate::error::{Error, Result as EmulatorResult};
 use log::*;
+use memmap2::MmapMut;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub type Address = usize;
 
+/// Value of every byte of external RAM before it's first written, as on most real cartridges
+pub const INITIAL_RAM_VALUE: u8 = 0xff;
+
 #[derive(Debug)]
 pub struct AddressingError(pub Address);
 
+/// Storage for a cartridge's external RAM. Anything which can be viewed as a byte slice works,
+/// so besides the default `Vec<u8>`, a frontend can hand over a memory-mapped save file and
+/// avoid copying large saves in and out.
+pub trait RamBacking: Send {
+    fn bytes(&self) -> &[u8];
+    fn bytes_mut(&mut self) -> &mut [u8];
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]> + Send> RamBacking for T {
+    fn bytes(&self) -> &[u8] {
+        self.as_ref()
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        self.as_mut()
+    }
+}
+
+/// External RAM kept in a memory-mapped save file, so writes reach the file without copying the
+/// RAM out.
+pub struct MmapRamBacking {
+    map: MmapMut,
+}
+
+impl MmapRamBacking {
+    /// Maps the first `size` bytes of the save file at `path`. A missing or short file is
+    /// extended with `INITIAL_RAM_VALUE`, and anything after the RAM is left untouched.
+    pub fn open(path: impl AsRef<Path>, size: usize) -> EmulatorResult<Self> {
+        let open = || -> std::io::Result<MmapMut> {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            let len = file.metadata()?.len() as usize;
+            if len < size {
+                file.seek(SeekFrom::End(0))?;
+                file.write_all(&vec![INITIAL_RAM_VALUE; size - len])?;
+            }
+            // Safety: the save file is only expected to be changed through this mapping
+            unsafe { memmap2::MmapOptions::new().len(size).map_mut(&file) }
+        };
+        let map = open().map_err(|err| Error::new(&format!("Can't map save file: {}", err)))?;
+        Ok(Self { map })
+    }
+
+    /// Writes any changes still held in memory out to the save file.
+    pub fn flush(&self) -> EmulatorResult<()> {
+        self.map
+            .flush()
+            .map_err(|err| Error::new(&format!("Can't flush save file: {}", err)))
+    }
+}
+
+impl AsRef<[u8]> for MmapRamBacking {
+    fn as_ref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+impl AsMut<[u8]> for MmapRamBacking {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.map
+    }
+}
+
 pub struct Cartridge {
     mbc: Box<dyn MemoryBankController + Send>,
     rom: Vec<u8>,
-    ram: Vec<u8>,
+    ram: Box<dyn RamBacking>,
+    features: CartridgeFeatures,
 }
 
 impl Cartridge {
     pub fn read(&self, address: Address) -> Result<u8, AddressingError> {
-        self.mbc.read(address, &self.rom, &self.ram)
+        self.mbc.read(address, &self.rom, self.ram.bytes())
     }
     pub fn write(&mut self, address: Address, value: u8) -> Result<(), AddressingError> {
-        self.mbc.write(address, value, &mut self.rom, &mut self.ram)
+        self.mbc
+            .write(address, value, &mut self.rom, self.ram.bytes_mut())
     }
     pub fn cartridge_from_data(data: &[u8]) -> Option<Cartridge> {
+        Cartridge::cartridge_from_data_with_initial_ram(data, INITIAL_RAM_VALUE)
+    }
+
+    /// Like `cartridge_from_data`, but fills the cartridge's external RAM with `initial_ram`
+    /// instead of the default 0xff, e.g. to match a particular cartridge when there's no save.
+    pub fn cartridge_from_data_with_initial_ram(data: &[u8], initial_ram: u8) -> Option<Cartridge> {
+        let features = CartridgeFeatures::from_header(data).ok()?;
         let cartridge_type = CartridgeType::from_data(data)?;
-        Some(cartridge_type.build(data))
+        Some(cartridge_type.build(data, initial_ram, features))
+    }
+
+    /// True iff the cartridge's rumble motor is currently switched on.
+    pub fn rumble_active(&self) -> bool {
+        self.mbc.rumble_active()
+    }
+
+    /// Runs hardware on the cartridge, such as a real-time clock, for some T-cycles of emulated
+    /// time.
+    pub fn step(&mut self, t_cycles: u64) {
+        self.mbc.step(t_cycles);
+    }
+
+    /// The state of the cartridge's real-time clock, e.g. to store in a save file. None if the
+    /// cartridge has no clock.
+    pub fn rtc_state(&self) -> Option<RtcState> {
+        self.mbc.rtc_state()
+    }
+
+    /// Restores the real-time clock from a state returned by `rtc_state`.
+    pub fn set_rtc_state(&mut self, state: RtcState) -> EmulatorResult<()> {
+        self.mbc.set_rtc_state(state)
+    }
+
+    /// Size of the cartridge's external RAM in bytes, as given by its header.
+    pub fn ram_size(&self) -> usize {
+        self.ram.bytes().len()
+    }
+
+    /// Replaces the storage behind external RAM, whose contents become the RAM's contents. The
+    /// backing must be exactly `ram_size` bytes.
+    pub fn set_ram_backing(&mut self, backing: Box<dyn RamBacking>) -> EmulatorResult<()> {
+        if backing.bytes().len() != self.ram_size() {
+            return Err(Error::new(
+                "RAM backing doesn't match the cartridge's RAM size",
+            ));
+        }
+        self.ram = backing;
+        Ok(())
+    }
+
+    /// True iff the cartridge has a battery keeping external RAM or a real-time clock, which
+    /// should be saved to disk.
+    pub fn has_battery_save(&self) -> bool {
+        self.features.has_battery && (self.ram_size() > 0 || self.features.has_rtc)
+    }
+
+    /// A copy of the cartridge's battery-backed RAM to write to a save file, followed by the
+    /// real-time clock if there is one. None if the cartridge has no battery, or neither RAM nor
+    /// a clock, so there's nothing to save.
+    pub fn save_ram(&self) -> Option<Vec<u8>> {
+        self.has_battery_save().then(|| {
+            let mut save = self.ram.bytes().to_vec();
+            if let Some(rtc) = self.rtc_state() {
+                save.extend(rtc.to_save_bytes());
+            }
+            save
+        })
+    }
+
+    /// Fills the cartridge's RAM from a save file. A real-time clock saved after the RAM, by
+    /// `save_ram` or by most other emulators, is restored too. Any other extra data is ignored.
+    pub fn load_ram(&mut self, data: &[u8]) {
+        let ram = self.ram.bytes_mut();
+        let length = ram.len().min(data.len());
+        ram[..length].copy_from_slice(&data[..length]);
+
+        let rtc = data.get(length..).and_then(RtcState::from_save_bytes);
+        if let (Some(rtc), Some(_)) = (rtc, self.rtc_state()) {
+            self.set_rtc_state(rtc)
+                .expect("cartridge has a real-time clock");
+        }
+    }
+
+    /// Hardware and console features the cartridge's header says it uses.
+    pub fn features(&self) -> CartridgeFeatures {
+        self.features
+    }
+
+    /// Where the cartridge was sold, as given by the header's destination code.
+    pub fn region(&self) -> Region {
+        Region::from_destination_code(self.rom[0x14a])
+    }
+
+    /// Encodes the MBC's registers and the external RAM for a save state. The ROM isn't included,
+    /// so the state can only be loaded back into the same game.
+    pub(crate) fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(&(self.mbc.save_state(), self.ram.bytes()))
+            .expect("error encoding cartridge state")
+    }
+
+    /// Restores a state from `save_state`.
+    pub(crate) fn load_state(&mut self, state: &[u8]) -> EmulatorResult<()> {
+        let (mbc_state, ram): (Vec<u8>, Vec<u8>) =
+            bincode::deserialize(state).map_err(|_| Error::new("Invalid cartridge state"))?;
+        if ram.len() != self.ram_size() {
+            return Err(Error::new(
+                "Saved RAM doesn't match the cartridge's RAM size",
+            ));
+        }
+        self.mbc.load_state(&mbc_state)?;
+        self.ram.bytes_mut().copy_from_slice(&ram);
+        Ok(())
+    }
+}
+
+/// The region a cartridge was sold in. The Game Boy itself ignores this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Japan,
+    NonJapan,
+}
+
+impl Region {
+    /// Decodes the destination code at 0x14a, where 0x00 means Japan and anything else (normally
+    /// 0x01) means overseas.
+    pub fn from_destination_code(code: u8) -> Region {
+        match code {
+            0x00 => Region::Japan,
+            _ => Region::NonJapan,
+        }
+    }
+}
+
+/// Describes everything in a ROM's header, one field per line, without building a cartridge.
+/// Works for cartridge types which can't be emulated yet.
+pub fn describe_header(data: &[u8]) -> EmulatorResult<String> {
+    // Fails if the ROM is too short to have a header
+    let features = CartridgeFeatures::from_header(data)?;
+    // Newer titles give up their last bytes to the manufacturer code and CGB flag
+    let title_end = if features.supports_cgb { 0x143 } else { 0x144 };
+    let title: String = data[0x134..title_end]
+        .iter()
+        .take_while(|byte| **byte != 0)
+        .map(|byte| char::from(*byte))
+        .collect();
+    let ram_size = match data[0x149] {
+        0 => "none".to_string(),
+        // Unused by official cartridges, but listed by some homebrew
+        1 => "2 KiB".to_string(),
+        2 => "8 KiB".to_string(),
+        3 => "32 KiB".to_string(),
+        4 => "128 KiB".to_string(),
+        5 => "64 KiB".to_string(),
+        code => format!("unknown ({:#04x})", code),
+    };
+    let rom_size = match data[0x148] {
+        code @ 0..=8 => format!("{} KiB", 32 << code),
+        code => format!("unknown ({:#04x})", code),
+    };
+
+    let header_checksum = data[0x134..0x14d]
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1));
+    let global_checksum = data
+        .iter()
+        .enumerate()
+        .filter(|(address, _)| !matches!(address, 0x14e | 0x14f))
+        .fold(0u16, |sum, (_, byte)| sum.wrapping_add(u16::from(*byte)));
+    let stored_global_checksum = u16::from_be_bytes([data[0x14e], data[0x14f]]);
+    let checksum_status = |valid: bool| if valid { "ok" } else { "mismatch" };
+    let cgb = if features.cgb_only {
+        "required"
+    } else if features.supports_cgb {
+        "supported"
+    } else {
+        "no"
+    };
+    let sgb = if features.supports_sgb {
+        "supported"
+    } else {
+        "no"
+    };
+
+    Ok([
+        format!("Title: {}", title),
+        format!(
+            "Cartridge type: {} ({:#04x})",
+            cartridge_type_name(data[0x147]),
+            data[0x147]
+        ),
+        format!("ROM size: {}", rom_size),
+        format!("RAM size: {}", ram_size),
+        format!("CGB: {}", cgb),
+        format!("SGB: {}", sgb),
+        format!("Region: {:?}", Region::from_destination_code(data[0x14a])),
+        format!(
+            "Header checksum: {:#04x} ({})",
+            data[0x14d],
+            checksum_status(header_checksum == data[0x14d])
+        ),
+        format!(
+            "Global checksum: {:#06x} ({})",
+            stored_global_checksum,
+            checksum_status(global_checksum == stored_global_checksum)
+        ),
+    ]
+    .join("\n"))
+}
+
+/// Names the hardware given by the cartridge type byte at 0x147, as listed in Pan Docs.
+fn cartridge_type_name(code: u8) -> &'static str {
+    match code {
+        0x00 => "ROM ONLY",
+        0x01 => "MBC1",
+        0x02 => "MBC1+RAM",
+        0x03 => "MBC1+RAM+BATTERY",
+        0x05 => "MBC2",
+        0x06 => "MBC2+BATTERY",
+        0x08 => "ROM+RAM",
+        0x09 => "ROM+RAM+BATTERY",
+        0x0b => "MMM01",
+        0x0c => "MMM01+RAM",
+        0x0d => "MMM01+RAM+BATTERY",
+        0x0f => "MBC3+TIMER+BATTERY",
+        0x10 => "MBC3+TIMER+RAM+BATTERY",
+        0x11 => "MBC3",
+        0x12 => "MBC3+RAM",
+        0x13 => "MBC3+RAM+BATTERY",
+        0x19 => "MBC5",
+        0x1a => "MBC5+RAM",
+        0x1b => "MBC5+RAM+BATTERY",
+        0x1c => "MBC5+RUMBLE",
+        0x1d => "MBC5+RUMBLE+RAM",
+        0x1e => "MBC5+RUMBLE+RAM+BATTERY",
+        0x20 => "MBC6",
+        0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+        0xfc => "POCKET CAMERA",
+        0xfd => "BANDAI TAMA5",
+        0xfe => "HuC3",
+        0xff => "HuC1+RAM+BATTERY",
+        _ => "unknown",
+    }
+}
+
+/// Features of a cartridge which a frontend may want to know about, e.g. to decide whether to
+/// offer save files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CartridgeFeatures {
+    /// Cartridge RAM is kept powered by a battery, so it should be saved
+    pub has_battery: bool,
+    /// The cartridge has a real-time clock
+    pub has_rtc: bool,
+    /// The cartridge has a rumble motor
+    pub has_rumble: bool,
+    /// The game has color features (it may also run on a DMG)
+    pub supports_cgb: bool,
+    /// The game only runs on a CGB
+    pub cgb_only: bool,
+    /// The game has Super Game Boy features
+    pub supports_sgb: bool,
+}
+
+impl CartridgeFeatures {
+    /// Decodes the features from a cartridge header. This works for every cartridge type, even
+    /// those which can't be emulated yet. Fails if `data` is too short to have a header.
+    pub fn from_header(data: &[u8]) -> EmulatorResult<CartridgeFeatures> {
+        if data.len() < 0x150 {
+            return Err(Error::new("ROM is too short to have a header"));
+        }
+        let cartridge_type = data[0x147];
+        Ok(CartridgeFeatures {
+            has_battery: matches!(
+                cartridge_type,
+                0x03 | 0x06 | 0x09 | 0x0d | 0x0f | 0x10 | 0x13 | 0x1b | 0x1e | 0x22 | 0xff
+            ),
+            has_rtc: matches!(cartridge_type, 0x0f | 0x10 | 0xfe),
+            has_rumble: matches!(cartridge_type, 0x1c..=0x1e | 0x22),
+            // 0x80 marks games which also run on a DMG, 0xc0 marks CGB-only games
+            supports_cgb: data[0x143] & 0x80 != 0,
+            cgb_only: data[0x143] == 0xc0,
+            // SGB features are ignored unless the old licensee code says to use the new one
+            supports_sgb: data[0x146] == 0x03 && data[0x14b] == 0x33,
+        })
     }
 }
 
@@ -41,6 +402,36 @@ trait MemoryBankController {
         ram: &mut [u8],
     ) -> Result<(), AddressingError>;
     fn get_type(&self) -> MbcType;
+
+    /// True iff the cartridge has a rumble motor which is currently switched on.
+    fn rumble_active(&self) -> bool {
+        false
+    }
+
+    /// Advances any clocked hardware on the cartridge by some T-cycles.
+    fn step(&mut self, _t_cycles: u64) {}
+
+    fn rtc_state(&self) -> Option<RtcState> {
+        None
+    }
+
+    fn set_rtc_state(&mut self, _state: RtcState) -> EmulatorResult<()> {
+        Err(Error::new("cartridge has no real-time clock"))
+    }
+
+    /// Encodes the controller's registers for a save state.
+    fn save_state(&self) -> Vec<u8>;
+
+    /// Restores the registers from `save_state`.
+    fn load_state(&mut self, state: &[u8]) -> EmulatorResult<()>;
+}
+
+fn encode_mbc<T: Serialize>(mbc: &T) -> Vec<u8> {
+    bincode::serialize(mbc).expect("error encoding MBC state")
+}
+
+fn decode_mbc<T: DeserializeOwned>(state: &[u8]) -> EmulatorResult<T> {
+    bincode::deserialize(state).map_err(|_| Error::new("Invalid MBC state"))
 }
 
 /// Examines cartridge data (the header) to get the size of the rom located
@@ -72,11 +463,15 @@ fn get_ram_size(data: &[u8]) -> usize {
 }
 
 /// A Gameboy cartridge that only has a single ROM bank, with no switching.
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 struct NoMbc {}
 impl MemoryBankController for NoMbc {
     fn read(&self, address: Address, rom: &[u8], ram: &[u8]) -> Result<u8, AddressingError> {
-        rom.get(address).ok_or(AddressingError(address)).copied()
+        match address {
+            // Missing RAM reads as open bus
+            0xa000..=0xbfff => Ok(ram.get(address - 0xa000).copied().unwrap_or(0xff)),
+            _ => rom.get(address).ok_or(AddressingError(address)).copied(),
+        }
     }
 
     fn write(
@@ -86,7 +481,12 @@ impl MemoryBankController for NoMbc {
         rom: &mut [u8],
         ram: &mut [u8],
     ) -> Result<(), AddressingError> {
-        if let Some(elem) = rom.get_mut(address) {
+        if let 0xa000..=0xbfff = address {
+            if let Some(byte) = ram.get_mut(address - 0xa000) {
+                *byte = value;
+            }
+            Ok(())
+        } else if let Some(elem) = rom.get_mut(address) {
             *elem = value;
             Ok(())
         } else {
@@ -97,8 +497,18 @@ impl MemoryBankController for NoMbc {
     fn get_type(&self) -> MbcType {
         MbcType::RomOnly
     }
+
+    fn save_state(&self) -> Vec<u8> {
+        encode_mbc(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> EmulatorResult<()> {
+        *self = decode_mbc(state)?;
+        Ok(())
+    }
 }
 
+#[derive(Serialize, Deserialize)]
 struct Mbc1 {
     ram_gate: BitField<u8>,
     bank_register_1: BitField<u8>,
@@ -133,7 +543,8 @@ impl Mbc1 {
 
     fn read_banked_rom(&self, address: Address, rom: &[u8]) -> Result<u8, AddressingError> {
         let bank_number = self.bank_number(address);
-        let rom_address = bank_number << 14 | address & 0x3fff;
+        // Bank numbers wrap around on ROMs with fewer banks than the registers can select
+        let rom_address = (bank_number << 14 | address & 0x3fff) % rom.len().max(1);
 
         if let Some(value) = rom.get(rom_address) {
             Ok(*value)
@@ -142,8 +553,31 @@ impl Mbc1 {
         }
     }
 
-    fn read_banked_ram(&self, _address: Address, _ram: &[u8]) -> Result<u8, AddressingError> {
-        todo!("Mbc1 ram is not yet implemented")
+    /// RAM is only accessible after 0xa is written to the RAM gate.
+    fn ram_enabled(&self) -> bool {
+        self.ram_gate.as_value() == 0xa
+    }
+
+    /// Where a RAM address falls in the cartridge's RAM, or None if it has none. Bank register 2
+    /// only selects the RAM bank in mode 1, and smaller RAMs are mirrored.
+    fn ram_address(&self, address: Address, ram: &[u8]) -> Option<usize> {
+        if ram.is_empty() {
+            return None;
+        }
+        let bank_number = if self.mode_register.as_value() == 0 {
+            0
+        } else {
+            usize::from(self.bank_register_2.as_value())
+        };
+        Some((bank_number << 13 | address & 0x1fff) % ram.len())
+    }
+
+    fn read_banked_ram(&self, address: Address, ram: &[u8]) -> Result<u8, AddressingError> {
+        // Disabled or missing RAM reads as open bus
+        match self.ram_address(address, ram) {
+            Some(ram_address) if self.ram_enabled() => Ok(ram[ram_address]),
+            _ => Ok(0xff),
+        }
     }
 }
 
@@ -161,7 +595,7 @@ impl MemoryBankController for Mbc1 {
         address: Address,
         mut value: u8,
         _rom: &mut [u8],
-        _ram: &mut [u8],
+        ram: &mut [u8],
     ) -> Result<(), AddressingError> {
         match address {
             0..=0x1fff => {
@@ -194,6 +628,14 @@ impl MemoryBankController for Mbc1 {
                 self.mode_register.set_range_value(0..=0, value);
                 Ok(())
             }
+            0xa000..=0xbfff => {
+                if let (true, Some(ram_address)) =
+                    (self.ram_enabled(), self.ram_address(address, ram))
+                {
+                    ram[ram_address] = value;
+                }
+                Ok(())
+            }
             _ => panic!("Address {:#x} is out of bounds for rom", address),
         }
     }
@@ -201,6 +643,369 @@ impl MemoryBankController for Mbc1 {
     fn get_type(&self) -> MbcType {
         MbcType::Mbc1
     }
+
+    fn save_state(&self) -> Vec<u8> {
+        encode_mbc(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> EmulatorResult<()> {
+        *self = decode_mbc(state)?;
+        Ok(())
+    }
+}
+
+/// The time kept by an MBC3's real-time clock, as seen in its registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct RtcRegisters {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    /// 9 bit day counter
+    pub days: u16,
+    /// While halted the clock doesn't count
+    pub halted: bool,
+    /// Set when the day counter overflows, until cleared by the game
+    pub day_carry: bool,
+}
+
+impl RtcRegisters {
+    /// T-cycles in one second of emulated time.
+    const CYCLES_PER_SECOND: u64 = 4_194_304;
+
+    fn read(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0a => self.hours,
+            0x0b => self.days as u8,
+            _ => (self.day_carry as u8) << 7 | (self.halted as u8) << 6 | (self.days >> 8) as u8,
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => self.seconds = value & 0x3f,
+            0x09 => self.minutes = value & 0x3f,
+            0x0a => self.hours = value & 0x1f,
+            0x0b => self.days = self.days & 0x100 | u16::from(value),
+            _ => {
+                self.days = self.days & 0xff | u16::from(value & 1) << 8;
+                self.halted = value & 0x40 != 0;
+                self.day_carry = value & 0x80 != 0;
+            }
+        }
+    }
+
+    /// Counts one second. Out of range values written by the game count up to the register's
+    /// limit before wrapping to 0, without carrying into the next register.
+    fn tick_second(&mut self) {
+        fn count(value: u8, last: u8, mask: u8) -> u8 {
+            if value == last {
+                0
+            } else {
+                (value + 1) & mask
+            }
+        }
+
+        self.seconds = count(self.seconds, 59, 0x3f);
+        if self.seconds != 0 {
+            return;
+        }
+        self.minutes = count(self.minutes, 59, 0x3f);
+        if self.minutes != 0 {
+            return;
+        }
+        self.hours = count(self.hours, 23, 0x1f);
+        if self.hours != 0 {
+            return;
+        }
+        if self.days == 0x1ff {
+            self.days = 0;
+            self.day_carry = true;
+        } else {
+            self.days += 1;
+        }
+    }
+}
+
+/// Everything needed to persist an MBC3's real-time clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct RtcState {
+    /// The running clock
+    pub clock: RtcRegisters,
+    /// The copy of the clock the game reads, taken when it last latched the clock
+    pub latched: RtcRegisters,
+    /// T-cycles counted towards the next second
+    pub cycles: u64,
+}
+
+impl RtcState {
+    /// Encodes the clock the way most emulators append it to a save file: the five clock
+    /// registers then the five latched registers as 32 bit little-endian values, followed by a
+    /// 64 bit UNIX timestamp. The T-cycles towards the next second aren't kept.
+    fn to_save_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(48);
+        for registers in [&self.clock, &self.latched] {
+            for register in 0x08..=0x0c {
+                bytes.extend(u32::from(registers.read(register)).to_le_bytes());
+            }
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        bytes.extend(timestamp.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes a clock saved by `to_save_bytes`. Some emulators save a 32 bit timestamp, so only
+    /// the registers are required. The timestamp is ignored, so the clock doesn't count the time
+    /// spent switched off.
+    fn from_save_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 40 {
+            return None;
+        }
+        let mut state = RtcState::default();
+        let values = bytes.chunks_exact(4).take(10);
+        for (i, value) in values.enumerate() {
+            let registers = if i < 5 {
+                &mut state.clock
+            } else {
+                &mut state.latched
+            };
+            registers.write(0x08 + (i % 5) as u8, value[0]);
+        }
+        Some(state)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Mbc3 {
+    ram_enabled: bool,
+    /// 7 bit number of the ROM bank mapped to 0x4000-0x7fff
+    rom_bank: usize,
+    /// Maps a RAM bank (0x00-0x03) or a clock register (0x08-0x0c) to 0xa000-0xbfff
+    bank_select: u8,
+    /// None if the cartridge has no clock
+    rtc: Option<RtcState>,
+    /// True after 0x00 is written to the latch register, so a following 0x01 latches the clock
+    latch_primed: bool,
+}
+
+impl Mbc3 {
+    fn new(has_timer: bool) -> Self {
+        Self {
+            ram_enabled: false,
+            rom_bank: 1,
+            bank_select: 0,
+            rtc: has_timer.then(RtcState::default),
+            latch_primed: false,
+        }
+    }
+
+    fn ram_address(&self, address: Address, ram: &[u8]) -> Option<usize> {
+        let ram_address = usize::from(self.bank_select) << 13 | address & 0x1fff;
+        (ram_address < ram.len()).then_some(ram_address)
+    }
+}
+
+impl MemoryBankController for Mbc3 {
+    fn read(&self, address: Address, rom: &[u8], ram: &[u8]) -> Result<u8, AddressingError> {
+        match address {
+            0x0000..=0x3fff => rom.get(address).copied().ok_or(AddressingError(address)),
+            0x4000..=0x7fff => rom
+                .get((self.rom_bank << 14 | address & 0x3fff) % rom.len().max(1))
+                .copied()
+                .ok_or(AddressingError(address)),
+            // Disabled or missing RAM and clock registers read as open bus
+            0xa000..=0xbfff if !self.ram_enabled => Ok(0xff),
+            0xa000..=0xbfff => match (self.bank_select, &self.rtc) {
+                (0x00..=0x03, _) => Ok(self
+                    .ram_address(address, ram)
+                    .map_or(0xff, |ram_address| ram[ram_address])),
+                (register @ 0x08..=0x0c, Some(rtc)) => Ok(rtc.latched.read(register)),
+                _ => Ok(0xff),
+            },
+            _ => Err(AddressingError(address)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        address: Address,
+        value: u8,
+        _rom: &mut [u8],
+        ram: &mut [u8],
+    ) -> Result<(), AddressingError> {
+        match address {
+            0x0000..=0x1fff => self.ram_enabled = value & 0xf == 0xa,
+            0x2000..=0x3fff => self.rom_bank = usize::from(value & 0x7f).max(1),
+            0x4000..=0x5fff => self.bank_select = value,
+            0x6000..=0x7fff => {
+                if let (true, 0x01, Some(rtc)) = (self.latch_primed, value, self.rtc.as_mut()) {
+                    rtc.latched = rtc.clock;
+                }
+                self.latch_primed = value == 0x00;
+            }
+            0xa000..=0xbfff if !self.ram_enabled => {}
+            0xa000..=0xbfff => match (self.bank_select, self.rtc.as_mut()) {
+                (0x00..=0x03, _) => {
+                    if let Some(ram_address) = self.ram_address(address, ram) {
+                        ram[ram_address] = value;
+                    }
+                }
+                (register @ 0x08..=0x0c, Some(rtc)) => {
+                    // Writes go to the running clock, and writing the seconds restarts the
+                    // current second
+                    rtc.clock.write(register, value);
+                    rtc.latched.write(register, value);
+                    if register == 0x08 {
+                        rtc.cycles = 0;
+                    }
+                }
+                _ => {}
+            },
+            _ => return Err(AddressingError(address)),
+        }
+        Ok(())
+    }
+
+    fn get_type(&self) -> MbcType {
+        MbcType::Mbc3
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        encode_mbc(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> EmulatorResult<()> {
+        *self = decode_mbc(state)?;
+        Ok(())
+    }
+
+    fn step(&mut self, t_cycles: u64) {
+        let Some(rtc) = self.rtc.as_mut() else {
+            return;
+        };
+        if rtc.clock.halted {
+            return;
+        }
+        rtc.cycles += t_cycles;
+        while rtc.cycles >= RtcRegisters::CYCLES_PER_SECOND {
+            rtc.cycles -= RtcRegisters::CYCLES_PER_SECOND;
+            rtc.clock.tick_second();
+        }
+    }
+
+    fn rtc_state(&self) -> Option<RtcState> {
+        self.rtc
+    }
+
+    fn set_rtc_state(&mut self, state: RtcState) -> EmulatorResult<()> {
+        match self.rtc.as_mut() {
+            Some(rtc) => {
+                *rtc = state;
+                Ok(())
+            }
+            None => Err(Error::new("cartridge has no real-time clock")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Mbc5 {
+    ram_enabled: bool,
+    /// 9 bit number of the ROM bank mapped to 0x4000-0x7fff
+    rom_bank: usize,
+    /// Number of the RAM bank mapped to 0xa000-0xbfff
+    ram_bank: usize,
+    /// Rumble cartridges use bit 3 of the RAM bank register to drive the motor
+    has_rumble: bool,
+    rumble_active: bool,
+}
+
+impl Mbc5 {
+    fn new(has_rumble: bool) -> Self {
+        Self {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumble_active: false,
+        }
+    }
+
+    fn ram_address(&self, address: Address) -> usize {
+        self.ram_bank << 13 | address & 0x1fff
+    }
+}
+
+impl MemoryBankController for Mbc5 {
+    fn read(&self, address: Address, rom: &[u8], ram: &[u8]) -> Result<u8, AddressingError> {
+        match address {
+            0x0000..=0x3fff => rom.get(address).copied().ok_or(AddressingError(address)),
+            // Bank numbers wrap around on ROMs with fewer banks than the register can select
+            0x4000..=0x7fff => rom
+                .get((self.rom_bank << 14 | address & 0x3fff) % rom.len().max(1))
+                .copied()
+                .ok_or(AddressingError(address)),
+            // Disabled or missing RAM reads as open bus
+            0xa000..=0xbfff if self.ram_enabled => {
+                Ok(ram.get(self.ram_address(address)).copied().unwrap_or(0xff))
+            }
+            0xa000..=0xbfff => Ok(0xff),
+            _ => Err(AddressingError(address)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        address: Address,
+        value: u8,
+        _rom: &mut [u8],
+        ram: &mut [u8],
+    ) -> Result<(), AddressingError> {
+        match address {
+            0x0000..=0x1fff => self.ram_enabled = value & 0xf == 0xa,
+            0x2000..=0x2fff => self.rom_bank = (self.rom_bank & 0x100) | usize::from(value),
+            0x3000..=0x3fff => {
+                self.rom_bank = (self.rom_bank & 0xff) | usize::from(value & 1) << 8;
+            }
+            0x4000..=0x5fff => {
+                if self.has_rumble {
+                    self.rumble_active = value & 0b1000 != 0;
+                    self.ram_bank = usize::from(value & 0b0111);
+                } else {
+                    self.ram_bank = usize::from(value & 0xf);
+                }
+            }
+            0x6000..=0x7fff => {}
+            0xa000..=0xbfff => {
+                let ram_address = self.ram_address(address);
+                if let (true, Some(byte)) = (self.ram_enabled, ram.get_mut(ram_address)) {
+                    *byte = value;
+                }
+            }
+            _ => return Err(AddressingError(address)),
+        }
+        Ok(())
+    }
+
+    fn get_type(&self) -> MbcType {
+        MbcType::Mbc5
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        encode_mbc(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> EmulatorResult<()> {
+        *self = decode_mbc(state)?;
+        Ok(())
+    }
+
+    fn rumble_active(&self) -> bool {
+        self.rumble_active
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -256,6 +1061,24 @@ impl CartridgeType {
                 rom_size,
                 ram_size,
             },
+            0x0f..=0x13 => CartridgeType {
+                mbc_controller_type: MbcType::Mbc3,
+                has_ram: matches!(data[0x0147], 0x10 | 0x12 | 0x13),
+                has_battery: matches!(data[0x0147], 0x0f | 0x10 | 0x13),
+                has_timer: matches!(data[0x0147], 0x0f | 0x10),
+                has_rumble: false,
+                rom_size,
+                ram_size,
+            },
+            0x19..=0x1e => CartridgeType {
+                mbc_controller_type: MbcType::Mbc5,
+                has_ram: matches!(data[0x0147], 0x1a | 0x1b | 0x1d | 0x1e),
+                has_battery: matches!(data[0x0147], 0x1b | 0x1e),
+                has_timer: false,
+                has_rumble: matches!(data[0x0147], 0x1c..=0x1e),
+                rom_size,
+                ram_size,
+            },
             _ => {
                 warn!("catridge indicated by {:#x} is not supported", data[0x0147]);
                 return None;
@@ -264,32 +1087,23 @@ impl CartridgeType {
         Some(cartridge_type)
     }
 
-    fn build(&self, rom_data: &[u8]) -> Cartridge {
+    fn build(&self, rom_data: &[u8], initial_ram: u8, features: CartridgeFeatures) -> Cartridge {
         let mbc_controller: Box<dyn MemoryBankController + Send> = match self.mbc_controller_type {
             MbcType::RomOnly => Box::new(NoMbc::default()),
             MbcType::Mbc1 => Box::new(Mbc1::default()),
+            MbcType::Mbc3 => Box::new(Mbc3::new(self.has_timer)),
+            MbcType::Mbc5 => Box::new(Mbc5::new(self.has_rumble)),
         };
         let mut rom = vec![0; self.rom_size];
         // Copy provided data into rom. Panics if the provided data exceeds the rom's size.
         rom[0..rom_data.len()].copy_from_slice(rom_data);
-        let ram = vec![0; self.ram_size];
-
-        if self.has_battery {
-            warn!(
-                "batteries not included (cartridge requires a battery which isn't implemented yet)"
-            );
-        }
-        if self.has_timer {
-            warn!("cartridge requires a timer which isn't implemented yet");
-        }
-        if self.has_rumble {
-            warn!("cartridge requires rumble which isn't implemented yet");
-        }
+        let ram = vec![initial_ram; self.ram_size];
 
         Cartridge {
             mbc: mbc_controller,
             rom,
-            ram,
+            ram: Box::new(ram),
+            features,
         }
     }
 }
@@ -298,11 +1112,12 @@ impl CartridgeType {
 pub enum MbcType {
     RomOnly,
     Mbc1,
+    Mbc3,
+    Mbc5,
 }
 
 fn cartridge_from_data(data: &[u8]) -> Option<Cartridge> {
-    let cartridge_type = CartridgeType::from_data(data)?;
-    Some(cartridge_type.build(data))
+    Cartridge::cartridge_from_data(data)
 }
 
 #[cfg(test)]
@@ -316,7 +1131,8 @@ mod tests {
         let mut cartridge = Cartridge {
             mbc: Box::new(Mbc1::default()),
             rom: rom_bytes,
-            ram: Vec::new(),
+            ram: Box::new(Vec::new()),
+            features: CartridgeFeatures::default(),
         };
 
         // Store 0b00100 into bank 1, 0b10 into bank 2, and 0b0 into mode
@@ -328,6 +1144,106 @@ mod tests {
         assert_eq!(0xfe, cartridge.read(0x72a7).unwrap());
     }
 
+    #[test]
+    fn test_mbc1_switches_banks_of_64_kib_rom() {
+        // MBC1 with 4 banks of ROM, each filled with its own number
+        let mut bytes: Vec<u8> = (0..4).flat_map(|bank| [bank; 0x4000]).collect();
+        bytes[0x0147] = 0x01;
+        bytes[0x0148] = 0x01;
+        let mut cartridge = cartridge_from_data(&bytes).unwrap();
+
+        assert_eq!(1, cartridge.read(0x4000).unwrap());
+        cartridge.write(0x2000, 2).unwrap();
+        assert_eq!(2, cartridge.read(0x4000).unwrap());
+        assert_eq!(2, cartridge.read(0x7fff).unwrap());
+        // Bank 0 can't be mapped to 0x4000-0x7fff, selecting it gives bank 1
+        cartridge.write(0x2000, 0).unwrap();
+        assert_eq!(1, cartridge.read(0x5000).unwrap());
+        // Bits beyond the ROM's size are ignored
+        cartridge.write(0x2000, 7).unwrap();
+        assert_eq!(3, cartridge.read(0x4000).unwrap());
+    }
+
+    #[test]
+    fn test_mbc1_ram_is_gated_and_banked() {
+        // MBC1+RAM with 4 banks of RAM
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x0147] = 0x02;
+        bytes[0x0149] = 0x03;
+        let mut cartridge = cartridge_from_data(&bytes).unwrap();
+
+        cartridge.write(0xa000, 0x12).unwrap();
+        assert_eq!(0xff, cartridge.read(0xa000).unwrap());
+
+        cartridge.write(0x0000, 0x0a).unwrap();
+        cartridge.write(0xa000, 0x12).unwrap();
+        assert_eq!(0x12, cartridge.read(0xa000).unwrap());
+
+        // Bank 1 is only selected in mode 1
+        cartridge.write(0x4000, 1).unwrap();
+        assert_eq!(0x12, cartridge.read(0xa000).unwrap());
+        cartridge.write(0x6000, 1).unwrap();
+        assert_eq!(INITIAL_RAM_VALUE, cartridge.read(0xa000).unwrap());
+        cartridge.write(0xa000, 0x34).unwrap();
+        cartridge.write(0x4000, 0).unwrap();
+        assert_eq!(0x12, cartridge.read(0xa000).unwrap());
+
+        cartridge.write(0x0000, 0x00).unwrap();
+        assert_eq!(0xff, cartridge.read(0xa000).unwrap());
+    }
+
+    #[test]
+    fn test_mbc3_rtc_counts_seconds_between_latches() {
+        // MBC3+TIMER+RAM+BATTERY
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x0147] = 0x10;
+        bytes[0x0149] = 0x03;
+        let mut cartridge = cartridge_from_data(&bytes).unwrap();
+        let latch_seconds = |cartridge: &mut Cartridge| {
+            cartridge.write(0x6000, 0x00).unwrap();
+            cartridge.write(0x6000, 0x01).unwrap();
+            cartridge.read(0xa000).unwrap()
+        };
+
+        cartridge.write(0x0000, 0x0a).unwrap();
+        cartridge.write(0x4000, 0x08).unwrap();
+        assert_eq!(0, latch_seconds(&mut cartridge));
+
+        cartridge.step(3 * 4_194_304 + 100);
+        // The latched value doesn't change until the clock is latched again
+        assert_eq!(0, cartridge.read(0xa000).unwrap());
+        assert_eq!(3, latch_seconds(&mut cartridge));
+
+        // Nothing is counted while halted
+        cartridge.write(0x4000, 0x0c).unwrap();
+        cartridge.write(0xa000, 0x40).unwrap();
+        cartridge.step(2 * 4_194_304);
+        cartridge.write(0x4000, 0x08).unwrap();
+        assert_eq!(3, latch_seconds(&mut cartridge));
+
+        let state = cartridge.rtc_state().unwrap();
+        assert_eq!(3, state.clock.seconds);
+        assert!(state.clock.halted);
+    }
+
+    #[test]
+    fn test_rtc_carries_into_the_day_counter() {
+        let mut clock = RtcRegisters {
+            seconds: 59,
+            minutes: 59,
+            hours: 23,
+            days: 0x1ff,
+            ..Default::default()
+        };
+        clock.tick_second();
+        let expected = RtcRegisters {
+            day_carry: true,
+            ..Default::default()
+        };
+        assert_eq!(expected, clock);
+        assert_eq!(0x80, clock.read(0x0c));
+    }
+
     #[test]
     fn test_cartridge_builder_correct_mbc_type() {
         let bytes = [0; 32_000];
@@ -352,4 +1268,252 @@ mod tests {
         bytes[0x0147] = 1;
         cartridge_from_data(&bytes);
     }
+
+    #[test]
+    fn test_features_decoded_from_header() {
+        // MBC3+TIMER+RAM+BATTERY
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x0147] = 0x10;
+        let features = CartridgeFeatures::from_header(&bytes).unwrap();
+        assert!(features.has_battery);
+        assert!(features.has_rtc);
+        assert!(!features.has_rumble);
+        assert!(!features.supports_cgb);
+
+        // MBC1+RAM+BATTERY with color and SGB support
+        bytes[0x0143] = 0x80;
+        bytes[0x0146] = 0x03;
+        bytes[0x0147] = 0x03;
+        bytes[0x0149] = 0x02;
+        bytes[0x014b] = 0x33;
+        let features = cartridge_from_data(&bytes).unwrap().features();
+        assert!(features.has_battery);
+        assert!(!features.has_rtc);
+        assert!(features.supports_cgb);
+        assert!(!features.cgb_only);
+        assert!(features.supports_sgb);
+
+        // Too short to have a header
+        assert!(CartridgeFeatures::from_header(&bytes[..0x14f]).is_err());
+        assert!(cartridge_from_data(&bytes[..0x14f]).is_none());
+    }
+
+    #[test]
+    fn test_only_battery_backed_ram_is_saved() {
+        // MBC1+RAM+BATTERY with 8 KiB of RAM
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x0147] = 0x03;
+        bytes[0x0149] = 0x02;
+        let mut cartridge = cartridge_from_data(&bytes).unwrap();
+        let mut save = vec![0x42; 0x2000];
+        save[0x123] = 0x99;
+        cartridge.load_ram(&save);
+
+        cartridge.write(0x0000, 0x0a).unwrap();
+        assert_eq!(0x99, cartridge.read(0xa123).unwrap());
+        assert_eq!(Some(save), cartridge.save_ram());
+
+        // MBC1+RAM, which loses its RAM when switched off
+        bytes[0x0147] = 0x02;
+        assert_eq!(None, cartridge_from_data(&bytes).unwrap().save_ram());
+        // ROM only
+        bytes[0x0147] = 0x00;
+        bytes[0x0149] = 0x00;
+        assert_eq!(None, cartridge_from_data(&bytes).unwrap().save_ram());
+    }
+
+    #[test]
+    fn test_rtc_is_saved_after_the_ram() {
+        // MBC3+TIMER+RAM+BATTERY with 8 KiB of RAM
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x0147] = 0x10;
+        bytes[0x0149] = 0x02;
+        let mut cartridge = cartridge_from_data(&bytes).unwrap();
+        let clock = RtcRegisters {
+            seconds: 12,
+            minutes: 34,
+            hours: 5,
+            days: 0x1ab,
+            halted: false,
+            day_carry: true,
+        };
+        let rtc = RtcState {
+            clock,
+            latched: RtcRegisters { hours: 4, ..clock },
+            cycles: 0,
+        };
+        cartridge.set_rtc_state(rtc).unwrap();
+
+        let save = cartridge.save_ram().unwrap();
+        assert_eq!(0x2000 + 48, save.len());
+        let mut loaded = cartridge_from_data(&bytes).unwrap();
+        loaded.load_ram(&save);
+        assert_eq!(Some(rtc), loaded.rtc_state());
+
+        // Saves without a clock leave it alone
+        loaded.load_ram(&save[..0x2000]);
+        assert_eq!(Some(rtc), loaded.rtc_state());
+
+        // MBC3+TIMER+BATTERY has no RAM, so the save is just the clock
+        bytes[0x0147] = 0x0f;
+        bytes[0x0149] = 0x00;
+        let mut cartridge = cartridge_from_data(&bytes).unwrap();
+        assert!(cartridge.has_battery_save());
+        cartridge.set_rtc_state(rtc).unwrap();
+        let save = cartridge.save_ram().unwrap();
+        assert_eq!(48, save.len());
+        let mut loaded = cartridge_from_data(&bytes).unwrap();
+        loaded.load_ram(&save);
+        assert_eq!(Some(rtc), loaded.rtc_state());
+    }
+
+    #[test]
+    fn test_region_decoded_from_destination_code() {
+        let mut bytes = vec![0; 0x8000];
+        assert_eq!(Region::Japan, cartridge_from_data(&bytes).unwrap().region());
+
+        bytes[0x014a] = 0x01;
+        assert_eq!(
+            Region::NonJapan,
+            cartridge_from_data(&bytes).unwrap().region()
+        );
+    }
+
+    #[test]
+    fn test_mbc5_rumble_bit_is_not_a_ram_bank_bit() {
+        // MBC5+RUMBLE+RAM+BATTERY with 4 banks of RAM
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x0147] = 0x1e;
+        bytes[0x0149] = 0x03;
+        let mut cartridge = cartridge_from_data(&bytes).unwrap();
+        cartridge.write(0x0000, 0x0a).unwrap();
+
+        // Motor on, RAM bank 1
+        cartridge.write(0x4000, 0b1001).unwrap();
+        assert!(cartridge.rumble_active());
+        cartridge.write(0xa000, 0x42).unwrap();
+
+        // Motor off, still RAM bank 1
+        cartridge.write(0x4000, 0b0001).unwrap();
+        assert!(!cartridge.rumble_active());
+        assert_eq!(0x42, cartridge.read(0xa000).unwrap());
+
+        cartridge.write(0x4000, 0b1000).unwrap();
+        assert!(cartridge.rumble_active());
+        assert_eq!(0xff, cartridge.read(0xa000).unwrap());
+    }
+
+    #[test]
+    fn test_mbc5_rom_bank_wraps_to_rom_size() {
+        // MBC5 with 4 banks of ROM
+        let mut bytes = vec![0; 0x10000];
+        bytes[0x0147] = 0x19;
+        bytes[0x0148] = 0x01;
+        bytes[0x8000] = 0x22;
+        let mut cartridge = cartridge_from_data(&bytes).unwrap();
+
+        // Bank 6 wraps to bank 2, bank 0x102 to bank 2 as well
+        cartridge.write(0x2000, 0x06).unwrap();
+        assert_eq!(0x22, cartridge.read(0x4000).unwrap());
+        cartridge.write(0x2000, 0x02).unwrap();
+        cartridge.write(0x3000, 0x01).unwrap();
+        assert_eq!(0x22, cartridge.read(0x4000).unwrap());
+    }
+
+    #[test]
+    fn test_fresh_external_ram_reads_initial_value() {
+        // MBC5+RAM+BATTERY with 1 bank of RAM
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x0147] = 0x1b;
+        bytes[0x0149] = 0x02;
+
+        let mut cartridge = cartridge_from_data(&bytes).unwrap();
+        cartridge.write(0x0000, 0x0a).unwrap();
+        assert_eq!(0xff, cartridge.read(0xa000).unwrap());
+        assert_eq!(0xff, cartridge.read(0xbfff).unwrap());
+
+        let mut cartridge = Cartridge::cartridge_from_data_with_initial_ram(&bytes, 0x00).unwrap();
+        cartridge.write(0x0000, 0x0a).unwrap();
+        assert_eq!(0x00, cartridge.read(0xa000).unwrap());
+    }
+
+    #[test]
+    fn test_ram_backing_is_banked() {
+        // MBC5+RAM+BATTERY with 4 banks of RAM
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x0147] = 0x1b;
+        bytes[0x0149] = 0x03;
+        let mut cartridge = cartridge_from_data(&bytes).unwrap();
+        assert!(cartridge
+            .set_ram_backing(Box::new(vec![0; 0x2000]))
+            .is_err());
+
+        let mut save = vec![0; 4 * 0x2000];
+        save[2 * 0x2000 + 5] = 0x42;
+        cartridge.set_ram_backing(Box::new(save)).unwrap();
+        cartridge.write(0x0000, 0x0a).unwrap();
+
+        cartridge.write(0x4000, 2).unwrap();
+        assert_eq!(0x42, cartridge.read(0xa005).unwrap());
+        cartridge.write(0xa005, 0x24).unwrap();
+        cartridge.write(0x4000, 3).unwrap();
+        assert_eq!(0x00, cartridge.read(0xa005).unwrap());
+        cartridge.write(0x4000, 2).unwrap();
+        assert_eq!(0x24, cartridge.read(0xa005).unwrap());
+    }
+
+    #[test]
+    fn test_mmap_ram_backing_is_banked_and_written_to_the_file() {
+        let path = std::env::temp_dir().join(format!("mmap_ram_{}.sav", std::process::id()));
+        let mut save = vec![0; 4 * 0x2000];
+        save[2 * 0x2000 + 5] = 0x42;
+        std::fs::write(&path, &save).unwrap();
+
+        // MBC5+RAM+BATTERY with 4 banks of RAM
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x0147] = 0x1b;
+        bytes[0x0149] = 0x03;
+        let mut cartridge = cartridge_from_data(&bytes).unwrap();
+        let backing = MmapRamBacking::open(&path, cartridge.ram_size()).unwrap();
+        cartridge.set_ram_backing(Box::new(backing)).unwrap();
+        cartridge.write(0x0000, 0x0a).unwrap();
+
+        cartridge.write(0x4000, 2).unwrap();
+        assert_eq!(0x42, cartridge.read(0xa005).unwrap());
+        cartridge.write(0xa005, 0x24).unwrap();
+        cartridge.write(0x4000, 3).unwrap();
+        cartridge.write(0xa000, 0x33).unwrap();
+        drop(cartridge);
+
+        let save = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(0x24, save[2 * 0x2000 + 5]);
+        assert_eq!(0x33, save[3 * 0x2000]);
+    }
+
+    #[test]
+    fn test_describe_header_lists_title_and_type() {
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x134..0x13c].copy_from_slice(b"TESTGAME");
+        bytes[0x0147] = 0x13;
+        bytes[0x0148] = 0x01;
+        bytes[0x0149] = 0x03;
+        bytes[0x014a] = 0x01;
+        bytes[0x014d] = 0x75;
+
+        let info = describe_header(&bytes).unwrap();
+        assert!(info.contains("Title: TESTGAME\n"));
+        assert!(info.contains("Cartridge type: MBC3+RAM+BATTERY (0x13)"));
+        assert!(info.contains("ROM size: 64 KiB"));
+        assert!(info.contains("RAM size: 32 KiB"));
+        assert!(info.contains("Region: NonJapan"));
+        assert!(info.contains("Header checksum: 0x75 (ok)"));
+        assert!(info.contains("Global checksum: 0x0000 (mismatch)"));
+
+        bytes[0x0149] = 0x01;
+        let info = describe_header(&bytes).unwrap();
+        assert!(info.contains("RAM size: 2 KiB"));
+
+        assert!(describe_header(&bytes[..0x100]).is_err());
+    }
 }
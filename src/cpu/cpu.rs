@@ -1,9 +1,12 @@
 use crate::component::{Addressable, ElapsedTime, Steppable};
 use crate::cpu::{instruction::*, register::*};
 use crate::error::Result;
+use crate::gameboy::Interrupt;
 use crate::memory::MemoryBus;
 use log::{debug, info, trace};
+use serde::{Deserialize, Serialize};
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CPU {
     pub registers: Registers,
     pub sp: u16,
@@ -11,6 +14,13 @@ pub struct CPU {
     pub(crate) interrupt_enabled: bool,
     pub(crate) halted: bool,
     pub(crate) halt_bug_opcode: Option<u8>,
+    /// True while in STOP standby, which only a button press ends.
+    pub(crate) stopped: bool,
+    /// The interrupt dispatched during the last step, along with the PC it interrupted.
+    pub(crate) serviced_interrupt: Option<(Interrupt, u16)>,
+    /// True after HALT with interrupts enabled but none selected in IE. Nothing can wake the
+    /// CPU again, so it stays stuck until reset.
+    pub(crate) locked_up: bool,
 }
 
 impl CPU {
@@ -22,6 +32,9 @@ impl CPU {
             interrupt_enabled: false,
             halted: false,
             halt_bug_opcode: None,
+            stopped: false,
+            serviced_interrupt: None,
+            locked_up: false,
         };
         cpu.emulate_bootrom();
         cpu
@@ -45,9 +58,10 @@ impl CPU {
         bit: u8,
         address: u16,
     ) -> Result<()> {
-        // Check IME flag and relevant bit in IE flag.
-        let ie_flag = memory_bus.read_u8(address.into())?;
-        if self.interrupt_enabled && ((ie_flag >> bit) & 1 == 1) {
+        // Check IME flag and that the interrupt is both enabled in IE and requested in IF.
+        let ie_flag = memory_bus.read_u8(0xffff)?;
+        let if_flag = memory_bus.read_u8(0xff0f)?;
+        if self.interrupt_enabled && (((ie_flag & if_flag) >> bit) & 1 == 1) {
             info!(
                 "Handling interrupt: {}",
                 match bit {
@@ -61,12 +75,15 @@ impl CPU {
             );
 
             // Reset interrupt bit in IF flag
-            let if_flag = memory_bus.read_u8(0xff0f)?;
             memory_bus.write_u8(0xff0f, if_flag & !(1 << bit))?;
 
             // Reset IME flag
             self.interrupt_enabled = false;
 
+            if let Some(interrupt) = Interrupt::from_bit(bit) {
+                self.serviced_interrupt = Some((interrupt, self.pc));
+            }
+
             // Push PC onto stack. LSB is last/top of the stack.
             let bytes = self.pc.to_le_bytes();
             self.push(memory_bus, bytes[1]).unwrap();
@@ -92,6 +109,7 @@ impl CPU {
     }
 
     fn handle_interrupts(&mut self, memory_bus: &mut MemoryBus) -> Result<()> {
+        self.serviced_interrupt = None;
         // If IE and IF
         if memory_bus.read_u8(0xFFFF)? & memory_bus.read_u8(0xFF0F)? != 0 {
             // Unhalt
@@ -206,6 +224,15 @@ impl Steppable for CPU {
     fn step(&mut self, state: &crate::gameboy::GameBoyState) -> Result<ElapsedTime> {
         let mut memory_bus = state.memory_bus.borrow_mut();
 
+        // STOP standby ends when one of the selected buttons is pressed, pulling its P1 line low
+        if self.stopped {
+            if memory_bus.read_u8(0xff00)? & 0x0f != 0x0f {
+                info!("Leaving STOP standby");
+                self.stopped = false;
+            }
+            return Ok(1);
+        }
+
         let elapsed_cycles = if !self.halted {
             // Get and execute opcode
             let pc = self.pc;
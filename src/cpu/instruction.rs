@@ -1,7 +1,7 @@
 use crate::component::Addressable;
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::{cpu::CPU, memory::MemoryBus};
-use log::{debug, error, info};
+use log::{debug, info};
 use strum_macros::AsRefStr;
 
 #[allow(non_camel_case_types)]
@@ -250,14 +250,15 @@ impl CPUWritable<u8> for GoodAddress {
 
 impl CPUWritable<u16> for GoodAddress {
     fn set(&self, cpu: &mut CPU, memory_bus: &mut MemoryBus, value: u16) -> Result<()> {
-        let addr: usize = match *self {
-            GoodAddress::Direct(addr) => addr.into(),
-            GoodAddress::WordRegister(word_reg) => word_reg.get(cpu, memory_bus)?.into(),
+        let addr: u16 = match *self {
+            GoodAddress::Direct(addr) => addr,
+            GoodAddress::WordRegister(word_reg) => word_reg.get(cpu, memory_bus)?,
         };
 
+        // The high byte wraps around to 0x0000 when storing to 0xffff
         let bytes = value.to_le_bytes();
-        memory_bus.write_u8(addr, bytes[0])?;
-        memory_bus.write_u8(addr + 1, bytes[1])?;
+        memory_bus.write_u8(addr.into(), bytes[0])?;
+        memory_bus.write_u8(addr.wrapping_add(1).into(), bytes[1])?;
 
         Ok(())
     }
@@ -362,6 +363,11 @@ enum BranchStatus {
     NoBranch,
 }
 
+/// The error for opcodes which don't exist, which lock up the CPU on hardware.
+fn illegal_opcode(opcode: u8) -> Error {
+    Error::new(&format!("illegal opcode {:#04x}", opcode))
+}
+
 fn get_opcode_delay(opcode: u8) -> u8 {
     match opcode {
         0x00 => 1,
@@ -922,6 +928,25 @@ impl CPU {
         }
     }
 
+    /// Computes SP plus a signed immediate, setting flags the way both `ADD SP,e8` and
+    /// `LD HL,SP+e8` do.
+    ///
+    /// Z and N are always cleared. H and C come from an unsigned addition of the immediate's
+    /// byte to the low byte of SP (carries out of bits 3 and 7), even when the immediate is
+    /// negative.
+    fn add_signed_to_sp(&mut self, imm: SignedImmediate) -> u16 {
+        let sp = self.sp;
+        let imm = i8::from(imm);
+        let unsigned_imm = imm as u8;
+
+        self.registers.f.zero = false;
+        self.registers.f.subtract = false;
+        self.registers.f.half_carry = (sp & 0xf) + u16::from(unsigned_imm & 0xf) > 0xf;
+        self.registers.f.carry = (sp & 0xff) + u16::from(unsigned_imm) > 0xff;
+
+        sp.wrapping_add_signed(imm.into())
+    }
+
     fn test_flag(&self, flag: Flag) -> bool {
         match flag {
             Flag::Z => self.registers.f.zero,
@@ -948,17 +973,8 @@ impl CPU {
                 target.set(self, memory_bus, value)?
             }
             Instruction::LDHL_SP(signed_immediate) => {
-                let sum = self
-                    .sp
-                    .wrapping_add_signed(i8::from(signed_immediate) as i16);
+                let sum = self.add_signed_to_sp(signed_immediate);
                 self.set_word_register(WordRegister::HL, sum);
-
-                self.registers.f.zero = false;
-                self.registers.f.subtract = false;
-                self.registers.f.half_carry =
-                    (self.sp ^ i8::from(signed_immediate) as u16 ^ sum) & 0x10 == 0x10;
-                self.registers.f.carry =
-                    (self.sp ^ i8::from(signed_immediate) as u16 ^ sum) & 0x100 == 0x100;
             }
             Instruction::LDD_A_FROM_HL => {
                 let value = WordRegister::HL.into_address().get(self, memory_bus)?;
@@ -1022,18 +1038,8 @@ impl CPU {
                 self.registers.set_hl(sum);
             }
             Instruction::ADD_SP(imm) => {
-                let sp = self.get_word_register(WordRegister::SP);
-                let imm: i16 = i8::from(imm).into();
-
-                let sum = sp.wrapping_add_signed(imm);
+                let sum = self.add_signed_to_sp(imm);
                 self.set_word_register(WordRegister::SP, sum);
-
-                let unsigned_imm = imm as u16;
-
-                self.registers.f.zero = false;
-                self.registers.f.subtract = false;
-                self.registers.f.half_carry = (sp ^ unsigned_imm ^ (sum & 0xFFFF)) & 0x10 == 0x10;
-                self.registers.f.carry = (sp ^ unsigned_imm ^ (sum & 0xFFFF)) & 0x100 == 0x100;
             }
             Instruction::ADC(arith_target) => {
                 let value = self.get_arithmetic_value(memory_bus, &arith_target)?;
@@ -1201,9 +1207,14 @@ impl CPU {
             Instruction::NOP => {}
             Instruction::HALT => {
                 info!("Halting");
-                let interrupt_pending =
-                    memory_bus.read_u8(0xffff)? & memory_bus.read_u8(0xff0f)? & 0x1f != 0;
-                self.halted = true;
+                let interrupt_enable = memory_bus.read_u8(0xffff)? & 0x1f;
+                // Nothing can wake the CPU again, so the hardware locks up until it's reset
+                let interrupt_pending = interrupt_enable & memory_bus.read_u8(0xff0f)? != 0;
+                if self.interrupt_enabled && interrupt_enable == 0 {
+                    self.locked_up = true;
+                } else {
+                    self.halted = true;
+                }
                 if !self.interrupt_enabled && interrupt_pending {
                     let byte = memory_bus.read_u8(self.pc.into())?;
                     info!("Performing halt bug with byte {:#04x}", byte);
@@ -1211,7 +1222,14 @@ impl CPU {
                     self.halt_bug_opcode = Some(byte);
                 }
             }
-            Instruction::STOP => error!("STOP is not implemented"),
+            Instruction::STOP => {
+                // There is no speed switch to arm, so STOP always enters standby. It's followed
+                // by a padding byte, and resets DIV like a write would.
+                info!("Entering STOP standby");
+                self.pc = self.pc.wrapping_add(1);
+                memory_bus.write_u8(0xff04, 0)?;
+                self.stopped = true;
+            }
             Instruction::DI => self.interrupt_enabled = false,
             Instruction::EI => self.interrupt_enabled = true,
 
@@ -1416,7 +1434,9 @@ impl CPU {
                 }
             }
             Instruction::RETI => {
-                self.execute(memory_bus, Instruction::EI)?;
+                // Unlike EI, IME is set straight away, so an interrupt which is already pending
+                // is serviced as soon as the return completes
+                self.interrupt_enabled = true;
                 self.execute(memory_bus, Instruction::RET)?;
             }
         }
@@ -1785,8 +1805,8 @@ impl CPU {
             0xF2 => Instruction::LD(Box::new(Register::A), Box::new(Offset(Register::C))),
 
             0xC3 => Instruction::JP(Address(self.get_word_from_pc(memory_bus)?)),
-            0xD3 => unimplemented!(),
-            0xE3 => unimplemented!(),
+            0xD3 => return Err(illegal_opcode(opcode)),
+            0xE3 => return Err(illegal_opcode(opcode)),
             0xF3 => Instruction::DI,
 
             0xC4 => {
@@ -1795,8 +1815,8 @@ impl CPU {
             0xD4 => {
                 Instruction::CALL_CONDITION(Flag::NC, Address(self.get_word_from_pc(memory_bus)?))
             }
-            0xE4 => unimplemented!(),
-            0xF4 => unimplemented!(),
+            0xE4 => return Err(illegal_opcode(opcode)),
+            0xF4 => return Err(illegal_opcode(opcode)),
 
             0xC5 => Instruction::PUSH(WordRegister::BC),
             0xD5 => Instruction::PUSH(WordRegister::DE),
@@ -1837,8 +1857,8 @@ impl CPU {
             ),
 
             0xCB => unimplemented!(),
-            0xDB => unimplemented!(),
-            0xEB => unimplemented!(),
+            0xDB => return Err(illegal_opcode(opcode)),
+            0xEB => return Err(illegal_opcode(opcode)),
             0xFB => Instruction::EI,
 
             0xCC => {
@@ -1847,13 +1867,13 @@ impl CPU {
             0xDC => {
                 Instruction::CALL_CONDITION(Flag::C, Address(self.get_word_from_pc(memory_bus)?))
             }
-            0xEC => unimplemented!(),
-            0xFC => unimplemented!(),
+            0xEC => return Err(illegal_opcode(opcode)),
+            0xFC => return Err(illegal_opcode(opcode)),
 
             0xCD => Instruction::CALL(Address(self.get_word_from_pc(memory_bus)?)),
-            0xDD => unimplemented!(),
-            0xED => unimplemented!(),
-            0xFD => unimplemented!(),
+            0xDD => return Err(illegal_opcode(opcode)),
+            0xED => return Err(illegal_opcode(opcode)),
+            0xFD => return Err(illegal_opcode(opcode)),
 
             0xCE => Instruction::ADC(Immediate(self.get_byte_from_pc(memory_bus)?).into()),
             0xDE => Instruction::SBC(Immediate(self.get_byte_from_pc(memory_bus)?).into()),
@@ -2168,3 +2188,199 @@ impl CPU {
         Ok(get_cb_opcode_delay(opcode))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::Cartridge;
+    use crate::component::Addressable;
+    use crate::gameboy::GameBoyState;
+
+    /// Runs `code` from 0xc000 for the given number of instructions.
+    fn run_code(code: &[u8], instructions: usize) -> GameBoyState {
+        let mut gameboy = GameBoyState::new_raw(code, 0xc000, 0xc000).unwrap();
+        for _ in 0..instructions {
+            gameboy.tick();
+        }
+        gameboy
+    }
+
+    #[test]
+    fn test_add_sp_negative_offset_flags() {
+        // LD SP,0xfff8; ADD SP,-8
+        let gameboy = run_code(&[0x31, 0xf8, 0xff, 0xe8, 0xf8], 2);
+
+        let cpu = gameboy.cpu.borrow();
+        assert_eq!(0xfff0, cpu.sp);
+        // 0xf8 + 0xf8 carries out of both bit 3 and bit 7
+        assert!(!cpu.registers.f.zero);
+        assert!(!cpu.registers.f.subtract);
+        assert!(cpu.registers.f.half_carry);
+        assert!(cpu.registers.f.carry);
+    }
+
+    #[test]
+    fn test_ldhl_sp_negative_offset_flags() {
+        // LD SP,0xfff8; LD HL,SP-8
+        let gameboy = run_code(&[0x31, 0xf8, 0xff, 0xf8, 0xf8], 2);
+
+        let cpu = gameboy.cpu.borrow();
+        assert_eq!(0xfff0, cpu.registers.get_hl());
+        assert_eq!(0xfff8, cpu.sp);
+        assert!(!cpu.registers.f.zero);
+        assert!(!cpu.registers.f.subtract);
+        assert!(cpu.registers.f.half_carry);
+        assert!(cpu.registers.f.carry);
+
+        // LD SP,0x0000; LD HL,SP-1: 0x00 + 0xff carries out of neither bit
+        let gameboy = run_code(&[0x31, 0x00, 0x00, 0xf8, 0xff], 2);
+
+        let cpu = gameboy.cpu.borrow();
+        assert_eq!(0xffff, cpu.registers.get_hl());
+        assert!(!cpu.registers.f.half_carry);
+        assert!(!cpu.registers.f.carry);
+    }
+
+    #[test]
+    fn test_pop_af_masks_low_nibble_of_f() {
+        // LD BC,0xffff; PUSH BC; POP AF; PUSH AF; POP DE
+        let gameboy = run_code(&[0x01, 0xff, 0xff, 0xc5, 0xf1, 0xf5, 0xd1], 5);
+
+        let cpu = gameboy.cpu.borrow();
+        assert_eq!(0xfff0, cpu.registers.get_af());
+        // The masked value is what gets pushed back onto the stack
+        assert_eq!(0xfff0, cpu.registers.get_de());
+    }
+
+    #[test]
+    fn test_reti_enables_interrupts_immediately() {
+        // RETI, returning to 0xc123
+        let setup = |pending_interrupts: u8| {
+            let gameboy = GameBoyState::new_raw(&[0xd9], 0xc000, 0xc000).unwrap();
+            gameboy.set_sp(0xdff0);
+            let mut memory_bus = gameboy.memory_bus.borrow_mut();
+            memory_bus.write(0xdff0, &[0x23, 0xc1]).unwrap();
+            memory_bus.write_u8(0xffff, 0x01).unwrap();
+            memory_bus.write_u8(0xff0f, pending_interrupts).unwrap();
+            drop(memory_bus);
+            gameboy
+        };
+
+        let mut gameboy = setup(0);
+        gameboy.tick();
+        let cpu = gameboy.cpu.borrow();
+        assert!(cpu.interrupt_enabled);
+        assert_eq!(0xc123, cpu.pc);
+        assert_eq!(0xdff2, cpu.sp);
+        drop(cpu);
+
+        // A pending VBlank interrupt is serviced straight after the return, from the address
+        // RETI returned to
+        let mut gameboy = setup(0x01);
+        gameboy.tick();
+        let cpu = gameboy.cpu.borrow();
+        assert_eq!(0x40, cpu.pc);
+        assert!(!cpu.interrupt_enabled);
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+        assert_eq!(0x23, memory_bus.read_u8(0xdff0).unwrap());
+        assert_eq!(0xc1, memory_bus.read_u8(0xdff1).unwrap());
+        assert_eq!(0xdff0, cpu.sp);
+    }
+
+    #[test]
+    fn test_inc_hl_memory_sets_half_carry_and_preserves_carry() {
+        // LD HL,0xc100; LD (HL),0x0f; SCF; INC (HL)
+        let gameboy = run_code(&[0x21, 0x00, 0xc1, 0x36, 0x0f, 0x37, 0x34], 4);
+
+        assert_eq!(
+            0x10,
+            gameboy.memory_bus.borrow_mut().read_u8(0xc100).unwrap()
+        );
+        let flags = gameboy.cpu.borrow().registers.f;
+        assert!(!flags.zero);
+        assert!(!flags.subtract);
+        assert!(flags.half_carry);
+        assert!(flags.carry);
+    }
+
+    #[test]
+    fn test_dec_hl_memory_sets_half_carry_and_preserves_carry() {
+        // LD HL,0xc100; LD (HL),0x10; SCF; CCF; DEC (HL)
+        let gameboy = run_code(&[0x21, 0x00, 0xc1, 0x36, 0x10, 0x37, 0x3f, 0x35], 5);
+
+        assert_eq!(
+            0x0f,
+            gameboy.memory_bus.borrow_mut().read_u8(0xc100).unwrap()
+        );
+        let flags = gameboy.cpu.borrow().registers.f;
+        assert!(!flags.zero);
+        assert!(flags.subtract);
+        assert!(flags.half_carry);
+        assert!(!flags.carry);
+    }
+
+    #[test]
+    fn test_bit_hl_takes_fewer_cycles_than_res_hl() {
+        // LD HL,0xc100; BIT 0,(HL); RES 0,(HL)
+        let mut gameboy = run_code(&[0x21, 0x00, 0xc1, 0xcb, 0x46, 0xcb, 0x86], 1);
+
+        // BIT only reads (HL), while RES also has to write the result back
+        assert_eq!(12, gameboy.tick());
+        assert_eq!(16, gameboy.tick());
+    }
+
+    #[test]
+    fn test_cp_flags() {
+        // (A, value, zero, half carry, carry)
+        let cases = [
+            (0x00, 0x00, true, false, false),
+            (0x42, 0x42, true, false, false),
+            (0xff, 0xff, true, false, false),
+            (0x41, 0x42, false, true, true),
+            (0x00, 0x01, false, true, true),
+            (0x10, 0x01, false, true, false),
+            (0x1f, 0x0f, false, false, false),
+            (0x0f, 0x1f, false, false, true),
+            (0x80, 0x7f, false, true, false),
+            (0x7f, 0x80, false, false, true),
+            (0xff, 0x00, false, false, false),
+            (0x00, 0xff, false, true, true),
+        ];
+        for (a, value, zero, half_carry, carry) in cases {
+            // LD A,a; CP value
+            let gameboy = run_code(&[0x3e, a, 0xfe, value], 2);
+
+            let cpu = gameboy.cpu.borrow();
+            let flags = cpu.registers.f;
+            let case = format!("CP {a:#04x},{value:#04x}");
+            assert_eq!(a, cpu.registers.a, "{case} changed A");
+            assert_eq!(zero, flags.zero, "{case} zero");
+            assert!(flags.subtract, "{case} subtract");
+            assert_eq!(half_carry, flags.half_carry, "{case} half carry");
+            assert_eq!(carry, flags.carry, "{case} carry");
+        }
+    }
+
+    #[test]
+    fn test_ld_a16_sp_wraps_high_byte_to_0000() {
+        let mut gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        let cartridge = Cartridge::cartridge_from_data(&[0; 0x8000]).unwrap();
+        gameboy.load_cartridge(cartridge).unwrap();
+        // LD SP,0x1234; LD (0xffff),SP
+        let code = [0x31, 0x34, 0x12, 0x08, 0xff, 0xff];
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write(0xc000, &code)
+            .unwrap();
+        gameboy.memory_bus.borrow_mut().add_write_watchpoint(0x0000);
+        gameboy.set_pc(0xc000);
+        gameboy.tick();
+        gameboy.tick();
+
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+        assert_eq!(0x34, memory_bus.read_u8(0xffff).unwrap());
+        let hits = memory_bus.take_watchpoint_hits();
+        assert_eq!(1, hits.len());
+        assert_eq!(0x12, hits[0].new_value);
+    }
+}
@@ -1,6 +1,8 @@
+use serde::{Deserialize, Serialize};
+
 /// The eight 8-bit CPU registers. Does not include the 16-bit SP and PC registers.
 /// Some registers can be paired up and treated as 16-bit registers.
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct Registers {
     pub a: u8,
     pub b: u8,
@@ -34,7 +36,7 @@ macro_rules! set_joint_register {
 }
 
 impl Registers {
-    // AF
+    // AF. F only stores its four flags, so its low nibble always reads as 0.
     get_joint_register!(get_af, a, f);
     set_joint_register!(set_af, a, f);
 
@@ -52,7 +54,7 @@ impl Registers {
 }
 
 /// The flag register has meanings assigned to its bits.
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 pub struct FlagRegister {
     /// This bit is set when the result of a math op is zero or two values match when using the CP
     /// instruction.
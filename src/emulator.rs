@@ -1,26 +1,50 @@
+mod audio_sync;
+mod auto_fire;
+pub mod breakpoints;
+mod debug_overlay;
 pub mod events;
+mod frame_histogram;
+mod frame_limiter;
+mod framebuffer;
+mod gamepad;
+mod lcd_ghosting;
 mod texture_book;
 
+use crate::apu::SAMPLE_RATE;
 use crate::cartridge::Cartridge;
 use crate::gameboy::Interrupt;
-use crate::gameboy::{GameBoyState, GameboyDebugInfo};
+use crate::gameboy::{GameBoyState, GameboyDebugInfo, CYCLES_PER_FRAME};
 use crate::joypad::JoypadInput;
-use crate::ppu::{CanvasPpu, NoGuiPpu};
+use crate::ppu::{BasePpu, CanvasEngine, NoGuiEngine, Palette};
 use log::warn;
 use sdl2::render::BlendMode;
+use std::any::Any;
 use std::cell::RefCell;
-use std::io::Write;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::sync::mpsc;
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 use strum::IntoEnumIterator;
 
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::controller::Axis;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::rect::Rect;
 
+use self::audio_sync::AudioSync;
+use self::auto_fire::AutoFire;
+use self::breakpoints::Breakpoints;
+use self::debug_overlay::render_overlay;
 use self::events::{EmulationControlEvent, EmulationEvent};
+use self::frame_histogram::FrameTimeHistogram;
+use self::frame_limiter::FrameLimiter;
+use self::framebuffer::{render_screen, ColorAdjustment};
+use self::gamepad::{AnalogStick, HeldInputs, InputSource};
+use self::lcd_ghosting::LcdGhosting;
 use self::texture_book::TextureBook;
 
 pub const WIDTH: usize = 8 * (16 + 32);
@@ -33,6 +57,60 @@ pub struct GameboyEmulator {
     // read in a value from stdin.
     target_pc: Option<u16>,
     debug: bool,
+    breakpoints: Breakpoints,
+    /// The breakpoint emulation is paused at, if any
+    paused_at: Option<u16>,
+}
+
+/// Settings for the gui frontend, which don't affect emulation.
+#[derive(Debug, Clone)]
+pub struct GuiOptions {
+    /// How much of each frame persists into the next, from 0 to 1
+    pub lcd_ghosting: f32,
+    /// How far analog sticks must be pushed to register a direction, from 0 to 1
+    pub stick_deadzone: f32,
+    /// File to log the CPU state to before every instruction
+    pub cpu_log: Option<PathBuf>,
+    /// Record how long each frame takes and print a histogram on exit
+    pub frame_histogram: bool,
+    /// Integer factor the window is scaled up by
+    pub scale: u32,
+    /// Buttons which auto-fire while held. F2 switches auto-fire on and off
+    pub auto_fire: Vec<JoypadInput>,
+    /// Presses per second made by auto-fire buttons
+    pub auto_fire_rate: f32,
+    /// Multiplies the brightness of the screen's colors
+    pub brightness: f32,
+    /// Gamma applied to the screen's colors, where values above 1 brighten the midtones
+    pub gamma: f32,
+    /// Play audio and pace emulation by it, waiting for the audio queue to drain after each frame
+    pub sync_to_audio: bool,
+    /// The most frames shown per second, however fast emulation runs. Uncapped if None
+    pub max_fps: Option<f32>,
+    /// File the cartridge's battery-backed RAM is written to on exit, if it has any
+    pub save_path: Option<PathBuf>,
+    /// Colors the four shades of the screen are shown as
+    pub palette: Palette,
+}
+
+impl Default for GuiOptions {
+    fn default() -> Self {
+        Self {
+            lcd_ghosting: 0.0,
+            stick_deadzone: 0.25,
+            cpu_log: None,
+            frame_histogram: false,
+            scale: 2,
+            auto_fire: Vec::new(),
+            auto_fire_rate: 15.0,
+            brightness: 1.0,
+            gamma: 1.0,
+            sync_to_audio: false,
+            max_fps: None,
+            save_path: None,
+            palette: Palette::default(),
+        }
+    }
 }
 
 struct EmulatorDebugInfo {
@@ -54,37 +132,76 @@ fn map_joypad_to_keys(input: JoypadInput) -> Vec<Keycode> {
     }
 }
 
+/// Draws the CPU registers and PPU state over the LCD display.
+fn draw_debug_overlay(
+    canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
+    gameboy_state: &GameBoyState,
+    texture_book: &mut TextureBook,
+) -> Result<(), String> {
+    // The CPU log line starts with the registers, e.g. "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 ..."
+    let cpu_log_line = gameboy_state.cpu_log_line();
+    let registers: Vec<&str> = cpu_log_line.split(' ').take(10).collect();
+    let ppu = gameboy_state.ppu.borrow();
+    let lines: Vec<String> = registers
+        .chunks(4)
+        .map(|chunk| chunk.join(" "))
+        .chain([format!("LY:{:02X} MODE:{}", ppu.ly(), ppu.mode())])
+        .collect();
+
+    texture_book
+        .debug_overlay
+        .update(None, &render_overlay(&lines, 160, 144), 160 * 4)
+        .map_err(|e| e.to_string())?;
+    canvas.copy(
+        &texture_book.debug_overlay,
+        None,
+        Some(Rect::new(128 + 32 * 8, 0, 160, 144)),
+    )
+}
+
 fn update_frame(
     canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
-    canvas_ppu: &mut CanvasPpu,
+    ppu: &mut BasePpu,
     texture_book: &mut TextureBook,
+    lcd_ghosting: &mut LcdGhosting,
+    color_adjustment: &mut ColorAdjustment,
+    scale: u32,
+    debug_pixels: bool,
 ) -> Result<(), String> {
-    canvas_ppu
+    // The display is scaled here rather than by SDL so that it stays crisp
+    let mut lcd_pixels = render_screen(ppu, color_adjustment, scale, debug_pixels);
+    lcd_ghosting.apply(&mut lcd_pixels);
+    texture_book
+        .lcd_display
+        .update(None, &lcd_pixels, 160 * 3 * scale as usize)
+        .map_err(|e| e.to_string())?;
+
+    let (data, engine) = ppu.split_mut();
+
+    // The debug views can only be drawn by an engine which renders to SDL textures
+    let Some(canvas_engine) = (engine as &mut dyn Any).downcast_mut::<CanvasEngine>() else {
+        return Ok(());
+    };
+
+    canvas_engine
         .render_tile_map(canvas)
         .expect("error rendering tile map");
 
     canvas
         .with_texture_canvas(&mut texture_book.background_map, |mut texture_canvas| {
-            canvas_ppu
-                .render_background_map(&mut texture_canvas)
+            canvas_engine
+                .render_background_map(data, &mut texture_canvas)
                 .expect("error rendering background map");
         })
         .map_err(|e| e.to_string())?;
 
-    canvas
-        .with_texture_canvas(&mut texture_book.lcd_display, |texture_canvas| {
-            texture_canvas.set_draw_color(sdl2::pixels::Color::RGBA(255, 0, 0, 255));
-            texture_canvas.clear();
-        })
-        .map_err(|e| e.to_string())?;
-
     canvas
         .with_texture_canvas(&mut texture_book.sprite_map, |mut texture_canvas| {
             texture_canvas.set_draw_color(sdl2::pixels::Color::RGBA(0, 0, 0, 0));
             texture_canvas.clear();
             // Render sprites over background map for now
-            canvas_ppu
-                .render_sprites(&mut texture_canvas)
+            canvas_engine
+                .render_sprites(data, &mut texture_canvas)
                 .expect("error rendering sprite");
         })
         .map_err(|e| e.to_string())?;
@@ -113,10 +230,40 @@ impl GameboyEmulator {
         Self {
             target_pc: None,
             debug,
+            breakpoints: Breakpoints::new(),
+            paused_at: None,
+        }
+    }
+
+    /// Pauses emulation whenever the CPU is about to run the instruction at `address`.
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.add(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) {
+        self.breakpoints.remove(address);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// The breakpoint emulation is paused at, if any.
+    pub fn paused_at(&self) -> Option<u16> {
+        self.paused_at
+    }
+
+    /// Continues after a breakpoint, running its instruction. Does nothing unless paused.
+    pub fn resume(&mut self) {
+        if let Some(pc) = self.paused_at.take() {
+            self.breakpoints.resume_from(pc);
         }
     }
 
+    /// Runs the gameboy on a new thread without a gui, using this emulator's breakpoints. While
+    /// paused at a breakpoint the thread waits for a control event to resume or quit.
     pub fn gameboy_thread_no_gui(
+        mut self,
         cartridge: Cartridge,
     ) -> Result<
         (
@@ -131,9 +278,7 @@ impl GameboyEmulator {
             mpsc::channel::<EmulationControlEvent>();
 
         let join_handle = thread::spawn(move || -> Result<(), String> {
-            let mut emulator = GameboyEmulator::new(false);
-
-            let ppu = NoGuiPpu::new();
+            let ppu = BasePpu::new(Box::new(NoGuiEngine::new()));
 
             let mut gameboy_state = GameBoyState::new(
                 Rc::new(RefCell::new(ppu)),
@@ -144,7 +289,13 @@ impl GameboyEmulator {
                 .map_err(|e| e.to_string())?;
             let mut total_cycles: u128 = 0;
             loop {
-                let elapsed_cycles = emulator.update(&mut gameboy_state, total_cycles);
+                if self.paused_at.is_some() {
+                    match control_event_receiver.recv() {
+                        Ok(EmulationControlEvent::Resume) => self.resume(),
+                        Ok(EmulationControlEvent::Quit) | Err(_) => return Ok(()),
+                    }
+                }
+                let elapsed_cycles = self.update(&mut gameboy_state, total_cycles);
                 total_cycles += elapsed_cycles as u128;
             }
         });
@@ -152,8 +303,12 @@ impl GameboyEmulator {
         Ok((join_handle, control_event_sender, event_receiver))
     }
 
+    /// Runs the gameboy on a new thread with a gui, using this emulator's breakpoints. While
+    /// paused at a breakpoint the gui stays responsive, and a control event resumes or quits.
     pub fn gameboy_thread(
+        mut self,
         cartridge: Cartridge,
+        options: GuiOptions,
     ) -> Result<
         (
             JoinHandle<Result<(), String>>,
@@ -163,17 +318,40 @@ impl GameboyEmulator {
         String,
     > {
         let (event_sender, event_receiver) = mpsc::channel();
-        let (control_event_sender, _control_event_receiver) =
+        let (control_event_sender, control_event_receiver) =
             mpsc::channel::<EmulationControlEvent>();
 
         let join_handle = thread::spawn(move || -> Result<(), String> {
-            let mut emulator = GameboyEmulator::new(false);
-
             let sdl_context = sdl2::init()?;
             let video_subsystem = sdl_context.video()?;
+            // Gamepads are optional, so carry on with just the keyboard if SDL can't offer them
+            let controller_subsystem = match sdl_context.game_controller() {
+                Ok(controller_subsystem) => Some(controller_subsystem),
+                Err(err) => {
+                    warn!("Game controllers are unavailable: {}", err);
+                    None
+                }
+            };
+
+            // Use the first connected game controller, if there is one
+            let _controller = controller_subsystem
+                .as_ref()
+                .and_then(|controller_subsystem| {
+                    (0..controller_subsystem.num_joysticks().ok()?)
+                        .filter(|i| controller_subsystem.is_game_controller(*i))
+                        .find_map(|i| controller_subsystem.open(i).ok())
+                });
+            let mut stick = AnalogStick::new(options.stick_deadzone)?;
+            let mut held_inputs = HeldInputs::default();
+            let mut auto_fire = AutoFire::new(options.auto_fire.clone(), options.auto_fire_rate)?;
+            let mut auto_fire_enabled = !options.auto_fire.is_empty();
     
             let window = video_subsystem
-                .window("Gameboy Emulator", 1200, 900)
+                .window(
+                    "Gameboy Emulator",
+                    (128 + 32 * 8 + 160) * options.scale,
+                    32 * 8 * options.scale,
+                )
                 .position_centered()
                 .opengl()
                 .build()
@@ -184,24 +362,54 @@ impl GameboyEmulator {
                 .set_logical_size(128 + 32 * 8 + 160, 32 * 8)
                 .map_err(|e| e.to_string())?;
             canvas.set_blend_mode(BlendMode::Blend);
-            let mut texture_book = TextureBook::new(&canvas)?;
+            let mut texture_book = TextureBook::new(&canvas, options.scale)?;
+            let mut lcd_ghosting = LcdGhosting::new(options.lcd_ghosting)?;
+            let frame_limiter = options.max_fps.map(FrameLimiter::new).transpose()?;
     
             let canvas = Rc::new(RefCell::new(canvas));
     
-            let canvas_ppu = Rc::new(RefCell::new(CanvasPpu::new(&texture_book.texture_creator)));
+            let ppu = Rc::new(RefCell::new(BasePpu::new(Box::new(CanvasEngine::new(
+                &texture_book.texture_creator,
+            )))));
+            ppu.borrow_mut().set_palette(options.palette);
+            let mut color_adjustment =
+                ColorAdjustment::new(&ppu.borrow().palette(), options.brightness, options.gamma)?;
     
             // Initialize gameboy and load cartridge
-            let mut gameboy_state = GameBoyState::new(canvas_ppu.clone(), event_sender);
+            let mut gameboy_state = GameBoyState::new(ppu.clone(), event_sender);
             gameboy_state
                 .load_cartridge(cartridge)
                 .map_err(|e| e.to_string())?;
+            if let Some(path) = &options.cpu_log {
+                let file = File::create(path).map_err(|e| e.to_string())?;
+                gameboy_state.set_cpu_log(Some(Box::new(BufWriter::new(file))));
+            }
     
+            let audio = if options.sync_to_audio {
+                let desired_spec = AudioSpecDesired {
+                    freq: Some(SAMPLE_RATE as i32),
+                    channels: Some(2),
+                    samples: None,
+                };
+                let queue: AudioQueue<f32> =
+                    sdl_context.audio()?.open_queue(None, &desired_spec)?;
+                queue.resume();
+                Some((queue, AudioSync::new(Duration::from_millis(50))))
+            } else {
+                None
+            };
+
             // Keep track of total cycles and current cycles in current frame
             let mut total_cycles: u128 = 0;
             let mut frame_cycles = 0;
     
             // Start timing frames
             let mut start = Instant::now();
+            let mut frame_histogram = options
+                .frame_histogram
+                .then(|| FrameTimeHistogram::new(Duration::from_millis(4), 8));
+            // Toggled with F1
+            let mut show_debug_overlay = false;
     
             'mainloop: loop {
                 for event in sdl_context.event_pump()?.poll_iter() {
@@ -211,6 +419,16 @@ impl GameboyEmulator {
                             ..
                         }
                         | Event::Quit { .. } => break 'mainloop,
+                        Event::KeyDown {
+                            keycode: Some(Keycode::F1),
+                            repeat: false,
+                            ..
+                        } => show_debug_overlay = !show_debug_overlay,
+                        Event::KeyDown {
+                            keycode: Some(Keycode::F2),
+                            repeat: false,
+                            ..
+                        } => auto_fire_enabled = !auto_fire_enabled,
                         Event::KeyDown {
                             keycode: Some(keycode),
                             ..
@@ -218,6 +436,16 @@ impl GameboyEmulator {
                             let mut send_interrupt = false;
                             for joypad_input in JoypadInput::iter() {
                                 if map_joypad_to_keys(joypad_input).contains(&keycode) {
+                                    // Key repeats mustn't press an auto-fire button while it's
+                                    // meant to be released
+                                    if auto_fire_enabled
+                                        && auto_fire.applies_to(joypad_input)
+                                        && !auto_fire
+                                            .hold(joypad_input, gameboy_state.frame_count())
+                                    {
+                                        continue;
+                                    }
+                                    held_inputs.press(InputSource::Keyboard, joypad_input);
                                     let prev_state =
                                         gameboy_state.joypad.borrow_mut().key_pressed(joypad_input);
                                     // If previous state was not pressed, we send interrupt
@@ -238,44 +466,135 @@ impl GameboyEmulator {
                         } => {
                             for joypad_input in JoypadInput::iter() {
                                 if map_joypad_to_keys(joypad_input).contains(&keycode) {
-                                    gameboy_state.joypad.borrow_mut().key_released(joypad_input);
+                                    auto_fire.release(joypad_input);
+                                    if held_inputs.release(InputSource::Keyboard, joypad_input) {
+                                        gameboy_state
+                                            .joypad
+                                            .borrow_mut()
+                                            .key_released(joypad_input);
+                                    }
+                                }
+                            }
+                        }
+                        Event::ControllerAxisMotion {
+                            axis: axis @ (Axis::LeftX | Axis::LeftY),
+                            value,
+                            ..
+                        } => {
+                            let mut send_interrupt = false;
+                            let changes = stick.move_axis(axis == Axis::LeftX, value);
+                            for (joypad_input, pressed) in changes {
+                                let mut joypad = gameboy_state.joypad.borrow_mut();
+                                if pressed {
+                                    held_inputs.press(InputSource::Stick, joypad_input);
+                                    send_interrupt |= !joypad.key_pressed(joypad_input);
+                                } else if held_inputs.release(InputSource::Stick, joypad_input) {
+                                    joypad.key_released(joypad_input);
                                 }
                             }
+                            if send_interrupt {
+                                gameboy_state
+                                    .memory_bus
+                                    .borrow_mut()
+                                    .interrupt(Interrupt::Joypad)
+                                    .expect("error sending joypad interrupt");
+                            }
                         }
                         _ => {}
                     }
                 }
     
+                for control_event in control_event_receiver.try_iter() {
+                    match control_event {
+                        EmulationControlEvent::Resume => self.resume(),
+                        EmulationControlEvent::Quit => break 'mainloop,
+                    }
+                }
+                // Nothing runs while paused at a breakpoint, so don't spin waiting for events
+                if self.paused_at.is_some() {
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+
                 for _ in 0..1000 {
-                    let elapsed_cycles = emulator.update(&mut gameboy_state, total_cycles);
+                    let elapsed_cycles = self.update(&mut gameboy_state, total_cycles);
                     total_cycles += elapsed_cycles as u128;
                     frame_cycles += elapsed_cycles;
                 }
+
+                if auto_fire_enabled {
+                    let mut send_interrupt = false;
+                    let mut joypad = gameboy_state.joypad.borrow_mut();
+                    for (joypad_input, pressed) in auto_fire.update(gameboy_state.frame_count()) {
+                        if pressed {
+                            send_interrupt |= !joypad.key_pressed(joypad_input);
+                        } else {
+                            joypad.key_released(joypad_input);
+                        }
+                    }
+                    drop(joypad);
+                    if send_interrupt {
+                        gameboy_state
+                            .memory_bus
+                            .borrow_mut()
+                            .interrupt(Interrupt::Joypad)
+                            .expect("error sending joypad interrupt");
+                    }
+                }
     
-                // The clock runs at 4,194,304 Hz, and every 4 clock cycles is 1 machine cycle.
-                // Dividing by 4 and 60 should roughly give the number of machine cycles that
-                // need to run per frame at 60fps.
-                if frame_cycles >= 4_194_304 / 4 / 60 {
+                // Show a frame every time the PPU would have drawn one
+                if frame_cycles >= CYCLES_PER_FRAME {
                     update_frame(
                         &mut canvas.borrow_mut(),
-                        &mut canvas_ppu.borrow_mut(),
+                        &mut ppu.borrow_mut(),
                         &mut texture_book,
+                        &mut lcd_ghosting,
+                        &mut color_adjustment,
+                        options.scale,
+                        gameboy_state.debug_pixels(),
                     )?;
+                    if show_debug_overlay {
+                        draw_debug_overlay(
+                            &mut canvas.borrow_mut(),
+                            &gameboy_state,
+                            &mut texture_book,
+                        )?;
+                    }
     
-                    frame_cycles -= 4_194_304 / 4 / 60;
+                    frame_cycles -= CYCLES_PER_FRAME;
     
                     let duration = start.elapsed();
+                    if let Some(histogram) = frame_histogram.as_mut() {
+                        histogram.record(duration);
+                    }
                     if duration > Duration::from_millis(1000 / 60) {
                         warn!("Time elapsed this frame is: {:?} > 16ms", duration);
                     } else {
                         //std::thread::sleep(Duration::from_millis(1000 / 60) - duration);
                     }
+                    if let Some((queue, audio_sync)) = &audio {
+                        queue.queue_audio(&gameboy_state.take_audio())?;
+                        // Each stereo sample is two f32s
+                        let queued_samples = queue.size() as usize / 8;
+                        thread::sleep(audio_sync.wait_time(queued_samples));
+                    }
+                    // Applied after audio sync, so it can only slow emulation down further
+                    if let Some(frame_limiter) = &frame_limiter {
+                        thread::sleep(frame_limiter.wait_time(start.elapsed()));
+                    }
                     start = Instant::now();
     
                     canvas.borrow_mut().present();
                 }
             }
 
+            if let (Some(path), Some(ram)) = (&options.save_path, gameboy_state.save_ram()) {
+                fs::write(path, ram).map_err(|e| e.to_string())?;
+            }
+            if let Some(histogram) = frame_histogram {
+                print!("{}", histogram);
+            }
+
             Ok(())
         });
             
@@ -283,6 +602,16 @@ impl GameboyEmulator {
     }
 
     fn update(&mut self, gameboy_state: &mut GameBoyState, total_cycles: u128) -> u64 {
+        if self.paused_at.is_some() {
+            return 0;
+        }
+        let pc = gameboy_state.get_pc();
+        if self.breakpoints.should_break(pc) {
+            self.paused_at = Some(pc);
+            gameboy_state.emulation_event(EmulationEvent::BreakpointHit(pc));
+            return 0;
+        }
+
         if self.debug {
             self.update_debug(gameboy_state, total_cycles)
         } else {
@@ -333,8 +662,9 @@ impl GameboyEmulator {
     }
 
     /// Runs the gameboy emulator with a gui.
-    pub fn run(cartridge: Cartridge, debug: bool) -> Result<(), String> {
-        let (join_handle, control_event_sender, event_receiver) = Self::gameboy_thread(cartridge)?;
+    pub fn run(cartridge: Cartridge, debug: bool, options: GuiOptions) -> Result<(), String> {
+        let (join_handle, control_event_sender, event_receiver) =
+            GameboyEmulator::new(false).gameboy_thread(cartridge, options)?;
 
         thread::spawn(move || {
             while let Ok(event) = event_receiver.recv() {
@@ -354,3 +684,48 @@ impl std::fmt::Display for EmulatorDebugInfo {
         write!(f, "{} | cycles: {}", self.gameboy_info, self.total_cycles)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::component::Addressable;
+
+    #[test]
+    fn test_breakpoint_at_jump_target_fires_once() {
+        let (mut gameboy_state, receiver) = GameBoyState::new_headless();
+        // JR +1; NOP; NOP; JR -2, where the jump skips the first NOP
+        gameboy_state
+            .memory_bus
+            .borrow_mut()
+            .write(0xc000, &[0x18, 0x01, 0x00, 0x00, 0x18, 0xfe])
+            .unwrap();
+        gameboy_state.set_pc(0xc000);
+
+        let mut emulator = GameboyEmulator::new(false);
+        emulator.add_breakpoint(0xc002);
+        emulator.add_breakpoint(0xc003);
+        let mut run = |emulator: &mut GameboyEmulator| {
+            for _ in 0..100 {
+                emulator.update(&mut gameboy_state, 0);
+            }
+        };
+        let breakpoint_hits = || {
+            receiver
+                .try_iter()
+                .filter_map(|event| match event {
+                    EmulationEvent::BreakpointHit(pc) => Some(pc),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        run(&mut emulator);
+        assert_eq!(Some(0xc003), emulator.paused_at());
+        assert_eq!(vec![0xc003], breakpoint_hits());
+
+        emulator.resume();
+        run(&mut emulator);
+        assert_eq!(None, emulator.paused_at());
+        assert!(breakpoint_hits().is_empty());
+    }
+}
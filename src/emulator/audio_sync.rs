@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use crate::apu::SAMPLE_RATE;
+
+/// Paces emulation by the audio device, so audio is the master clock. After each frame the
+/// frontend waits until the queued audio has drained back to a target fill, which stops the queue
+/// from ever running dry at the cost of frames being shown unevenly.
+pub struct AudioSync {
+    /// Stereo samples left queued when the next frame starts
+    target_fill: usize,
+}
+
+impl AudioSync {
+    pub fn new(target_fill: Duration) -> AudioSync {
+        AudioSync {
+            target_fill: (target_fill.as_secs_f64() * SAMPLE_RATE as f64) as usize,
+        }
+    }
+
+    /// How long to wait before emulating the next frame, given the stereo samples still queued.
+    /// Zero once the queue is at or below the target fill.
+    pub fn wait_time(&self, queued_samples: usize) -> Duration {
+        let excess = queued_samples.saturating_sub(self.target_fill);
+        Duration::from_secs_f64(excess as f64 / SAMPLE_RATE as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wait_time_drains_queue_to_target() {
+        let audio_sync = AudioSync::new(Duration::from_millis(50));
+        let samples = |millis: u64| (SAMPLE_RATE * millis / 1000) as usize;
+
+        // Emulate straight away while the queue is empty or at the target
+        assert_eq!(Duration::ZERO, audio_sync.wait_time(0));
+        assert_eq!(Duration::ZERO, audio_sync.wait_time(samples(30)));
+        assert_eq!(Duration::ZERO, audio_sync.wait_time(samples(50)));
+
+        // Otherwise wait for the excess to play
+        let wait = audio_sync.wait_time(samples(80));
+        assert!(wait.abs_diff(Duration::from_millis(30)) < Duration::from_millis(1));
+    }
+}
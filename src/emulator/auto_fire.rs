@@ -0,0 +1,115 @@
+use crate::gameboy::FRAMES_PER_SECOND;
+use crate::joypad::JoypadInput;
+
+/// The fastest auto-fire rate in presses per second, a press every other frame.
+const MAX_RATE: f32 = 30.0;
+
+/// Auto-fire for buttons which games expect to be mashed. While an auto-fire button is held, it
+/// alternates between pressed and released at a fixed rate, counted in emulated frames.
+pub struct AutoFire {
+    buttons: Vec<JoypadInput>,
+    /// Frames spent pressed, and then released, in each press/release cycle
+    half_period: u64,
+    /// Auto-fire buttons being held, with the frame they were first held and whether they are
+    /// currently pressed
+    held: Vec<(JoypadInput, u64, bool)>,
+}
+
+impl AutoFire {
+    /// Auto-fires `buttons` at `rate` presses per second.
+    pub fn new(buttons: Vec<JoypadInput>, rate: f32) -> Result<AutoFire, String> {
+        // Presses and releases last whole frames, so rates are rounded to a whole number of frames
+        if !(rate > 0.0 && rate <= MAX_RATE) {
+            return Err(format!(
+                "auto-fire rate must be above 0 and at most {} presses per second, got {}",
+                MAX_RATE, rate
+            ));
+        }
+
+        let frames_per_press = FRAMES_PER_SECOND as f32 / rate;
+        Ok(AutoFire {
+            buttons,
+            half_period: (frames_per_press / 2.0).round().max(1.0) as u64,
+            held: Vec::new(),
+        })
+    }
+
+    /// True iff the input auto-fires while held.
+    pub fn applies_to(&self, input: JoypadInput) -> bool {
+        self.buttons.contains(&input)
+    }
+
+    /// Starts auto-firing a button from `frame`, beginning with a press. Returns true iff it
+    /// wasn't already being held.
+    pub fn hold(&mut self, input: JoypadInput, frame: u64) -> bool {
+        if self.held.iter().any(|(held, _, _)| *held == input) {
+            return false;
+        }
+        self.held.push((input, frame, true));
+        true
+    }
+
+    /// Stops auto-firing a button. Returns true iff it was being held.
+    pub fn release(&mut self, input: JoypadInput) -> bool {
+        let held = self.held.len();
+        self.held.retain(|(other, _, _)| *other != input);
+        self.held.len() != held
+    }
+
+    /// Advances the held buttons to `frame`. Returns each button whose state changed, along with
+    /// whether it is now pressed.
+    pub fn update(&mut self, frame: u64) -> Vec<(JoypadInput, bool)> {
+        let mut changes = Vec::new();
+        for (input, start, pressed) in self.held.iter_mut() {
+            let now_pressed = (frame.saturating_sub(*start) / self.half_period).is_multiple_of(2);
+            if now_pressed != *pressed {
+                *pressed = now_pressed;
+                changes.push((*input, now_pressed));
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_fire_alternates_at_configured_rate() {
+        assert!(AutoFire::new(vec![JoypadInput::A], 0.0).is_err());
+        assert!(AutoFire::new(vec![JoypadInput::A], 30.1).is_err());
+        // The fastest rate is a press every other frame
+        assert_eq!(1, AutoFire::new(vec![], 30.0).unwrap().half_period);
+
+        // 15 presses per second is 2 frames pressed then 2 frames released
+        let mut auto_fire = AutoFire::new(vec![JoypadInput::A], 15.0).unwrap();
+        assert!(auto_fire.applies_to(JoypadInput::A));
+        assert!(!auto_fire.applies_to(JoypadInput::B));
+
+        assert!(auto_fire.hold(JoypadInput::A, 10));
+        assert!(!auto_fire.hold(JoypadInput::A, 11));
+        let changes: Vec<_> = (10..20).map(|frame| auto_fire.update(frame)).collect();
+        let pressed = vec![(JoypadInput::A, true)];
+        let released = vec![(JoypadInput::A, false)];
+        assert_eq!(
+            vec![
+                vec![],
+                vec![],
+                released.clone(),
+                vec![],
+                pressed.clone(),
+                vec![],
+                released,
+                vec![],
+                pressed,
+                vec![],
+            ],
+            changes
+        );
+
+        assert!(auto_fire.release(JoypadInput::A));
+        assert!(!auto_fire.release(JoypadInput::A));
+        assert!(auto_fire.update(20).is_empty());
+    }
+}
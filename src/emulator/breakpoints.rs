@@ -0,0 +1,65 @@
+use std::collections::HashSet;
+
+/// PC addresses at which the run loop pauses before executing the instruction there.
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    addresses: HashSet<u16>,
+    /// The breakpoint just resumed from, which mustn't pause again before its instruction runs
+    resumed_from: Option<u16>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints::default()
+    }
+
+    pub fn add(&mut self, address: u16) {
+        self.addresses.insert(address);
+    }
+
+    pub fn remove(&mut self, address: u16) {
+        self.addresses.remove(&address);
+    }
+
+    pub fn clear(&mut self) {
+        self.addresses.clear();
+    }
+
+    pub fn contains(&self, address: u16) -> bool {
+        self.addresses.contains(&address)
+    }
+
+    /// True iff the instruction at `pc` is about to run and should pause first.
+    pub fn should_break(&mut self, pc: u16) -> bool {
+        if self.resumed_from.take() == Some(pc) {
+            return false;
+        }
+        self.contains(pc)
+    }
+
+    /// Lets the instruction at the breakpoint `pc` run once without pausing again.
+    pub fn resume_from(&mut self, pc: u16) {
+        self.resumed_from = Some(pc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resuming_skips_the_breakpoint_once() {
+        let mut breakpoints = Breakpoints::new();
+        breakpoints.add(0x150);
+        assert!(!breakpoints.should_break(0x100));
+        assert!(breakpoints.should_break(0x150));
+
+        breakpoints.resume_from(0x150);
+        assert!(!breakpoints.should_break(0x150));
+        // A loop back to the breakpoint pauses again
+        assert!(breakpoints.should_break(0x150));
+
+        breakpoints.remove(0x150);
+        assert!(!breakpoints.should_break(0x150));
+    }
+}
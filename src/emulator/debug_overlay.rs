@@ -0,0 +1,98 @@
+/// Glyphs are 3 pixels wide and 5 tall, with a column of spacing between characters.
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+const CHAR_WIDTH: usize = GLYPH_WIDTH + 1;
+/// Lines of text are separated by a row of spacing.
+const LINE_HEIGHT: usize = GLYPH_HEIGHT + 1;
+
+/// Rows of a character's glyph from top to bottom, where bit 2 is the leftmost pixel. Only the
+/// characters needed to show CPU and PPU state are included; anything else is blank.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        _ => [0; GLYPH_HEIGHT],
+    }
+}
+
+/// Renders lines of text with a bitmap font into a `width`x`height` grid of pixels in row-major
+/// order, where true is part of a character. Text past the edges is cut off.
+pub fn render_text(lines: &[String], width: usize, height: usize) -> Vec<bool> {
+    let mut pixels = vec![false; width * height];
+    for (line_number, line) in lines.iter().enumerate() {
+        for (char_number, c) in line.chars().enumerate() {
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for col in 0..GLYPH_WIDTH {
+                    let x = char_number * CHAR_WIDTH + col;
+                    let y = line_number * LINE_HEIGHT + row;
+                    if x < width && y < height && bits & (0b100 >> col) != 0 {
+                        pixels[y * width + x] = true;
+                    }
+                }
+            }
+        }
+    }
+    pixels
+}
+
+/// Renders lines of text as an RGBA32 image: white text over a translucent black background
+/// behind the lines, and transparent everywhere else so the screen shows through.
+pub fn render_overlay(lines: &[String], width: usize, height: usize) -> Vec<u8> {
+    let text = render_text(lines, width, height);
+    let text_height = lines.len() * LINE_HEIGHT;
+    text.iter()
+        .enumerate()
+        .flat_map(|(i, on)| match (on, i / width < text_height) {
+            (true, _) => [255, 255, 255, 255],
+            (false, true) => [0, 0, 0, 160],
+            (false, false) => [0, 0, 0, 0],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_text_draws_glyphs_side_by_side() {
+        let pixels = render_text(&["1L".to_string()], 8, 6);
+        let rows: Vec<String> = pixels
+            .chunks(8)
+            .map(|row| row.iter().map(|on| if *on { '#' } else { '.' }).collect())
+            .collect();
+        assert_eq!(
+            vec![
+                ".#..#...", //
+                "##..#...", //
+                ".#..#...", //
+                ".#..#...", //
+                "###.###.", //
+                "........", //
+            ],
+            rows
+        );
+    }
+}
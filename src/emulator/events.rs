@@ -1,16 +1,39 @@
-use crate::gameboy::GameboyDebugInfo;
+use crate::gameboy::{GameboyDebugInfo, Interrupt};
 
 /// Events created by the emulator and broadcasted across a channel
 #[derive(Debug)]
 pub enum EmulationEvent {
     SerialData(u8),
     Trace(GameboyDebugInfo),
-    MemoryRead { address: usize, value: u8 },
-    MemoryWrite { address: usize, value: u8 },
+    MemoryRead {
+        address: usize,
+        value: u8,
+    },
+    MemoryWrite {
+        address: usize,
+        value: u8,
+    },
+    /// The LCD was switched on (true) or off (false) through LCDC
+    LcdPower(bool),
+    /// The CPU hit an unrecoverable error and stopped, see `ExceptionPolicy::Event`
+    CpuException(String),
+    /// A cartridge was loaded which probably won't run correctly
+    CartridgeWarning(String),
+    /// The CPU jumped to the handler at `vector` for an interrupt, leaving `pc` on the stack. Only
+    /// sent while interrupt logging is enabled, see `GameBoyState::set_interrupt_logging`.
+    InterruptServiced {
+        kind: Interrupt,
+        vector: u16,
+        pc: u16,
+    },
+    /// Emulation paused before running the instruction at a breakpoint, until resumed
+    BreakpointHit(u16),
 }
 
 /// Events sent to the emulator to control its status
 #[derive(Debug)]
 pub enum EmulationControlEvent {
     Quit,
+    /// Continues emulation after a breakpoint was hit
+    Resume,
 }
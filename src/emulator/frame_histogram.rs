@@ -0,0 +1,71 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Counts how long frames take to emulate, in fixed-width buckets of wall-clock time. Frames
+/// slower than the last bucket are counted in it.
+pub struct FrameTimeHistogram {
+    bucket_width: Duration,
+    buckets: Vec<u64>,
+}
+
+impl FrameTimeHistogram {
+    pub fn new(bucket_width: Duration, bucket_count: usize) -> FrameTimeHistogram {
+        assert!(!bucket_width.is_zero(), "buckets must have a nonzero width");
+        assert!(bucket_count > 0, "histogram needs at least one bucket");
+        FrameTimeHistogram {
+            bucket_width,
+            buckets: vec![0; bucket_count],
+        }
+    }
+
+    pub fn record(&mut self, frame_time: Duration) {
+        let index = (frame_time.as_nanos() / self.bucket_width.as_nanos()) as usize;
+        let index = index.min(self.buckets.len() - 1);
+        self.buckets[index] += 1;
+    }
+
+    /// Number of frames recorded in each bucket, fastest first.
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    pub fn total(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+impl fmt::Display for FrameTimeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "frame times ({} frames):", self.total())?;
+        let last = self.buckets.len() - 1;
+        for (i, count) in self.buckets().iter().enumerate() {
+            let start = self.bucket_width * i as u32;
+            if i == last {
+                write!(f, "{:>8?}+         ", start)?;
+            } else {
+                write!(f, "{:>8?} - {:<8?}", start, start + self.bucket_width)?;
+            }
+            writeln!(f, " {}", count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_buckets_by_frame_time() {
+        let mut histogram = FrameTimeHistogram::new(Duration::from_millis(4), 5);
+        histogram.record(Duration::from_millis(0));
+        histogram.record(Duration::from_micros(3999));
+        histogram.record(Duration::from_millis(4));
+        histogram.record(Duration::from_millis(17));
+        // Anything past the last bucket's start is counted in it
+        histogram.record(Duration::from_millis(100));
+
+        assert_eq!(&[2, 1, 0, 0, 2], histogram.buckets());
+        assert_eq!(5, histogram.total());
+    }
+}
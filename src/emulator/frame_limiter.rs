@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+/// Caps how many frames are shown per second, so running flat out doesn't peg a CPU core. After
+/// each frame the frontend waits out whatever is left of the frame's minimum duration.
+pub struct FrameLimiter {
+    /// The shortest time a frame may take
+    min_frame_time: Duration,
+}
+
+impl FrameLimiter {
+    /// Limits frames to at most `max_fps` per second.
+    pub fn new(max_fps: f32) -> Result<FrameLimiter, String> {
+        if max_fps.is_nan() || max_fps <= 0.0 {
+            return Err(format!("max fps must be above 0, got {}", max_fps));
+        }
+
+        // Tiny rates give frame times too long for a Duration
+        let min_frame_time = Duration::try_from_secs_f32(1.0 / max_fps)
+            .map_err(|_| format!("max fps is too small, got {}", max_fps))?;
+
+        Ok(FrameLimiter { min_frame_time })
+    }
+
+    /// How long to wait before starting the next frame, given how long the last one took. Zero
+    /// once the frame has taken at least the minimum frame time.
+    pub fn wait_time(&self, frame_time: Duration) -> Duration {
+        self.min_frame_time.saturating_sub(frame_time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wait_time_limits_frame_rate() {
+        let frame_limiter = FrameLimiter::new(300.0).unwrap();
+
+        // Fast frames are padded out to 1/300th of a second, so at most 300 are shown a second
+        let frame_time = Duration::from_millis(1);
+        let padded = frame_time + frame_limiter.wait_time(frame_time);
+        assert!(padded.abs_diff(Duration::from_secs(1) / 300) < Duration::from_micros(10));
+
+        // Slow frames aren't held up any further
+        assert_eq!(
+            Duration::ZERO,
+            frame_limiter.wait_time(Duration::from_millis(20))
+        );
+
+        assert!(FrameLimiter::new(0.0).is_err());
+        assert!(FrameLimiter::new(1e-30).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use crate::ppu::{BasePpu, Palette, TileColor};
+
+/// Maps screen colors to the bytes of an RGB24 pixel using a palette.
+pub fn tile_color_rgb(color: TileColor, palette: &Palette) -> [u8; 3] {
+    let [r, g, b, _] = palette.rgba(color);
+    [r, g, b]
+}
+
+/// Adjusts one color channel, where gamma above 1 brightens the midtones and brightness scales
+/// the result. A gamma and brightness of 1 leave the channel unchanged.
+pub fn adjust_channel(value: u8, brightness: f32, gamma: f32) -> u8 {
+    let linear = f32::from(value) / 255.0;
+    let adjusted = brightness * linear.powf(1.0 / gamma);
+    (adjusted.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// The screen's colors after a brightness and gamma adjustment, for tuning the display to a
+/// monitor. Only affects presentation.
+pub struct ColorAdjustment {
+    brightness: f32,
+    gamma: f32,
+    /// The palette `colors` were computed from, kept in step with the PPU's by `render_screen`
+    palette: Palette,
+    /// RGB24 bytes for each `TileColor`, in declaration order
+    colors: [[u8; 3]; 5],
+}
+
+impl ColorAdjustment {
+    pub fn new(palette: &Palette, brightness: f32, gamma: f32) -> Result<ColorAdjustment, String> {
+        if !(0.0..).contains(&brightness) {
+            return Err(format!("brightness must be at least 0, got {}", brightness));
+        }
+        if gamma.is_nan() || gamma <= 0.0 {
+            return Err(format!("gamma must be above 0, got {}", gamma));
+        }
+
+        let mut adjustment = ColorAdjustment {
+            brightness,
+            gamma,
+            palette: *palette,
+            colors: [[0; 3]; 5],
+        };
+        adjustment.update_colors();
+        Ok(adjustment)
+    }
+
+    /// Switches to another palette, keeping the brightness and gamma.
+    pub fn set_palette(&mut self, palette: &Palette) {
+        if self.palette != *palette {
+            self.palette = *palette;
+            self.update_colors();
+        }
+    }
+
+    fn update_colors(&mut self) {
+        let (brightness, gamma) = (self.brightness, self.gamma);
+        self.colors = [
+            TileColor::White,
+            TileColor::LightGrey,
+            TileColor::DarkGrey,
+            TileColor::Black,
+            TileColor::Debug,
+        ]
+        .map(|color| {
+            tile_color_rgb(color, &self.palette).map(|c| adjust_channel(c, brightness, gamma))
+        });
+    }
+
+    /// The bytes of an RGB24 pixel for a screen color.
+    pub fn rgb(&self, color: TileColor) -> [u8; 3] {
+        self.colors[color as usize]
+    }
+}
+
+impl Default for ColorAdjustment {
+    fn default() -> Self {
+        ColorAdjustment::new(&Palette::default(), 1.0, 1.0)
+            .expect("the identity adjustment is valid")
+    }
+}
+
+/// The width of the Game Boy's screen in pixels.
+const SCREEN_WIDTH: usize = 160;
+
+/// Converts the screen into RGB24 bytes with adjusted colors, scaling it up by an integer `factor`
+/// with nearest-neighbor so each pixel becomes a `factor`x`factor` block.
+pub fn scale_framebuffer(src: &[TileColor], factor: u32, adjustment: &ColorAdjustment) -> Vec<u8> {
+    scale_image(src, SCREEN_WIDTH, factor, adjustment)
+}
+
+/// Converts the PPU's screen into RGB24 bytes scaled by `factor`, in the PPU's current palette.
+/// Debug pixels are shown as the colors underneath unless `debug_pixels` is set.
+pub fn render_screen(
+    ppu: &BasePpu,
+    adjustment: &mut ColorAdjustment,
+    factor: u32,
+    debug_pixels: bool,
+) -> Vec<u8> {
+    adjustment.set_palette(&ppu.palette());
+    let screen = ppu.engine().screen();
+    if debug_pixels {
+        scale_framebuffer(screen, factor, adjustment)
+    } else {
+        let screen: Vec<TileColor> = screen.iter().map(|c| c.hide_debug()).collect();
+        scale_framebuffer(&screen, factor, adjustment)
+    }
+}
+
+/// Like `scale_framebuffer`, but for a row-major image of any `width`.
+pub fn scale_image(
+    src: &[TileColor],
+    width: usize,
+    factor: u32,
+    adjustment: &ColorAdjustment,
+) -> Vec<u8> {
+    let factor = factor as usize;
+    let mut scaled = Vec::with_capacity(src.len() * factor * factor * 3);
+    for row in src.chunks(width) {
+        let scaled_row: Vec<u8> = row
+            .iter()
+            .flat_map(|color| adjustment.rgb(*color).repeat(factor))
+            .collect();
+        for _ in 0..factor {
+            scaled.extend_from_slice(&scaled_row);
+        }
+    }
+    scaled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::NoGuiEngine;
+
+    #[test]
+    fn test_scale_framebuffer_expands_pixels_into_blocks() {
+        let src = [
+            TileColor::White,
+            TileColor::Black,
+            TileColor::DarkGrey,
+            TileColor::LightGrey,
+        ];
+        let identity = ColorAdjustment::default();
+        let scaled = scale_image(&src, 2, 2, &identity);
+
+        let w = [255, 255, 255];
+        let b = [0, 0, 0];
+        let d = [100, 100, 100];
+        let l = [200, 200, 200];
+        let expected = [w, w, b, b, w, w, b, b, d, d, l, l, d, d, l, l].concat();
+        assert_eq!(expected, scaled);
+
+        assert_eq!(scale_image(&src, 2, 1, &identity), [w, b, d, l].concat());
+
+        let screen = [TileColor::White; 160 * 144];
+        let scaled_screen = scale_framebuffer(&screen, 2, &identity);
+        assert_eq!(320 * 288 * 3, scaled_screen.len());
+    }
+
+    #[test]
+    fn test_gamma_brightens_midtones() {
+        for value in [0, 100, 200, 255] {
+            assert_eq!(value, adjust_channel(value, 1.0, 1.0));
+        }
+        assert!(adjust_channel(100, 1.0, 2.2) > 100);
+        assert!(adjust_channel(100, 1.0, 0.5) < 100);
+        // Black and white stay put
+        assert_eq!(0, adjust_channel(0, 1.0, 2.2));
+        assert_eq!(255, adjust_channel(255, 1.0, 2.2));
+
+        // Brightness scales everything, clipping at white
+        assert_eq!(50, adjust_channel(100, 0.5, 1.0));
+        assert_eq!(255, adjust_channel(200, 1.5, 1.0));
+
+        let adjustment = ColorAdjustment::new(&Palette::GREY, 1.0, 2.2).unwrap();
+        assert!(adjustment.rgb(TileColor::DarkGrey)[0] > 100);
+        assert!(ColorAdjustment::new(&Palette::GREY, 1.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn test_palette_recolors_framebuffer() {
+        let green = ColorAdjustment::new(&Palette::DMG, 1.0, 1.0).unwrap();
+        let scaled = scale_image(&[TileColor::White, TileColor::Black], 2, 1, &green);
+        assert_eq!([155, 188, 15, 15, 56, 15], scaled.as_slice());
+
+        assert_eq!(Palette::DMG, "dmg".parse().unwrap());
+        assert!("sepia".parse::<Palette>().is_err());
+    }
+
+    #[test]
+    fn test_render_screen_follows_the_ppu_palette() {
+        let mut ppu = BasePpu::new(Box::new(NoGuiEngine::new()));
+        let mut adjustment = ColorAdjustment::new(&ppu.palette(), 1.0, 1.0).unwrap();
+        let pixels = render_screen(&ppu, &mut adjustment, 1, false);
+        assert_eq!([255, 255, 255], pixels[..3]);
+
+        ppu.set_palette(Palette::DMG);
+        let pixels = render_screen(&ppu, &mut adjustment, 1, false);
+        assert_eq!([155, 188, 15], pixels[..3]);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::joypad::JoypadInput;
+
+/// Maps an analog stick onto the joypad's digital directions.
+pub struct AnalogStick {
+    /// How far from the center (0 to 1) the stick must be pushed before it counts as a direction
+    deadzone: f32,
+    x: f32,
+    y: f32,
+}
+
+impl AnalogStick {
+    pub fn new(deadzone: f32) -> Result<AnalogStick, String> {
+        if !(0.0..=1.0).contains(&deadzone) {
+            return Err(format!(
+                "stick deadzone must be between 0 and 1, got {}",
+                deadzone
+            ));
+        }
+
+        Ok(AnalogStick {
+            deadzone,
+            x: 0.0,
+            y: 0.0,
+        })
+    }
+
+    /// Moves one axis of the stick, given as a raw SDL axis value. Returns each direction whose
+    /// state changed, along with whether it is now pressed.
+    pub fn move_axis(&mut self, horizontal: bool, value: i16) -> Vec<(JoypadInput, bool)> {
+        let before = stick_directions(self.x, self.y, self.deadzone);
+
+        let value = f32::from(value) / f32::from(i16::MAX);
+        if horizontal {
+            self.x = value;
+        } else {
+            self.y = value;
+        }
+        let after = stick_directions(self.x, self.y, self.deadzone);
+
+        let released = before
+            .iter()
+            .filter(|input| !after.contains(input))
+            .map(|input| (*input, false));
+        let pressed = after
+            .iter()
+            .filter(|input| !before.contains(input))
+            .map(|input| (*input, true));
+        released.chain(pressed).collect()
+    }
+}
+
+/// The directions an analog stick is pointing in, where each axis ranges from -1 to 1 and down is
+/// positive y. Each axis is checked separately, so diagonals press two directions.
+pub fn stick_directions(x: f32, y: f32, deadzone: f32) -> Vec<JoypadInput> {
+    let mut directions = Vec::new();
+    if x < -deadzone {
+        directions.push(JoypadInput::Left);
+    } else if x > deadzone {
+        directions.push(JoypadInput::Right);
+    }
+    if y < -deadzone {
+        directions.push(JoypadInput::Up);
+    } else if y > deadzone {
+        directions.push(JoypadInput::Down);
+    }
+    directions
+}
+
+/// Where a joypad input came from. The keyboard and the stick can hold the same direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputSource {
+    Keyboard,
+    Stick,
+}
+
+/// Tracks which inputs each source holds, so that an input is only released once no source
+/// holds it any more.
+#[derive(Debug, Default)]
+pub struct HeldInputs {
+    keyboard: Vec<JoypadInput>,
+    stick: Vec<JoypadInput>,
+}
+
+impl HeldInputs {
+    fn source_mut(&mut self, source: InputSource) -> &mut Vec<JoypadInput> {
+        match source {
+            InputSource::Keyboard => &mut self.keyboard,
+            InputSource::Stick => &mut self.stick,
+        }
+    }
+
+    /// Records `source` pressing `input`.
+    pub fn press(&mut self, source: InputSource, input: JoypadInput) {
+        let held = self.source_mut(source);
+        if !held.contains(&input) {
+            held.push(input);
+        }
+    }
+
+    /// Records `source` letting go of `input`. Returns true iff no source holds it any more, so
+    /// it should be released on the joypad.
+    pub fn release(&mut self, source: InputSource, input: JoypadInput) -> bool {
+        self.source_mut(source).retain(|held| *held != input);
+        !self.keyboard.contains(&input) && !self.stick.contains(&input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stick_directions_respect_deadzone() {
+        assert_eq!(Vec::<JoypadInput>::new(), stick_directions(0.2, -0.2, 0.25));
+        assert_eq!(vec![JoypadInput::Right], stick_directions(0.3, 0.0, 0.25));
+        assert_eq!(vec![JoypadInput::Up], stick_directions(0.1, -0.9, 0.25));
+        assert_eq!(
+            vec![JoypadInput::Left, JoypadInput::Down],
+            stick_directions(-0.7, 0.7, 0.25)
+        );
+    }
+
+    #[test]
+    fn test_move_axis_reports_changed_directions() {
+        let mut stick = AnalogStick::new(0.25).unwrap();
+        assert!(stick.move_axis(true, 1000).is_empty());
+        assert_eq!(
+            vec![(JoypadInput::Right, true)],
+            stick.move_axis(true, i16::MAX)
+        );
+        assert_eq!(
+            vec![(JoypadInput::Down, true)],
+            stick.move_axis(false, i16::MAX)
+        );
+        assert_eq!(
+            vec![(JoypadInput::Right, false), (JoypadInput::Left, true)],
+            stick.move_axis(true, i16::MIN)
+        );
+    }
+
+    #[test]
+    fn test_input_held_by_keyboard_survives_stick_release() {
+        let mut held = HeldInputs::default();
+        held.press(InputSource::Keyboard, JoypadInput::Left);
+        held.press(InputSource::Stick, JoypadInput::Left);
+
+        assert!(!held.release(InputSource::Stick, JoypadInput::Left));
+        assert!(held.release(InputSource::Keyboard, JoypadInput::Left));
+        // Releasing something that was never held also leaves it released
+        assert!(held.release(InputSource::Stick, JoypadInput::Up));
+    }
+}
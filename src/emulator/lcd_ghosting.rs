@@ -0,0 +1,72 @@
+/// Blends each frame with the one shown before it, imitating the slow response of the DMG's LCD.
+/// Games which flicker sprites on alternate frames relied on this to look solid.
+pub struct LcdGhosting {
+    /// How much of the previous frame persists, from 0 (no ghosting) to 1 (frozen image)
+    factor: f32,
+    /// The last frame that was output, as color bytes
+    previous_frame: Option<Vec<u8>>,
+}
+
+impl LcdGhosting {
+    pub fn new(factor: f32) -> Result<LcdGhosting, String> {
+        if !(0.0..=1.0).contains(&factor) {
+            return Err(format!(
+                "lcd ghosting factor must be between 0 and 1, got {}",
+                factor
+            ));
+        }
+
+        Ok(LcdGhosting {
+            factor,
+            previous_frame: None,
+        })
+    }
+
+    /// Blends the frame in place with the previously output frame, then remembers the result.
+    pub fn apply(&mut self, frame: &mut [u8]) {
+        if let Some(previous_frame) = &self.previous_frame {
+            for (new, old) in frame.iter_mut().zip(previous_frame) {
+                *new = blend(*old, *new, self.factor);
+            }
+        }
+
+        match &mut self.previous_frame {
+            Some(previous_frame) => previous_frame.copy_from_slice(frame),
+            None => self.previous_frame = Some(frame.to_vec()),
+        }
+    }
+}
+
+/// Weighted average of an old and new color channel, where `factor` is the weight of the old one.
+pub fn blend(old: u8, new: u8, factor: f32) -> u8 {
+    (f32::from(old) * factor + f32::from(new) * (1.0 - factor)).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blend_is_weighted_average_of_old_and_new() {
+        assert_eq!(150, blend(0, 200, 0.25));
+        assert_eq!(100, blend(200, 0, 0.5));
+        assert_eq!(200, blend(100, 200, 0.0));
+        assert_eq!(100, blend(100, 200, 1.0));
+
+        // The first frame has nothing to blend with, later frames fade from it
+        let mut ghosting = LcdGhosting::new(0.5).unwrap();
+        let mut frame = vec![255, 0];
+        ghosting.apply(&mut frame);
+        assert_eq!(vec![255, 0], frame);
+
+        let mut frame = vec![255, 255];
+        ghosting.apply(&mut frame);
+        assert_eq!(vec![255, 128], frame);
+    }
+
+    #[test]
+    fn test_factor_must_be_between_0_and_1() {
+        assert!(LcdGhosting::new(-0.1).is_err());
+        assert!(LcdGhosting::new(1.1).is_err());
+    }
+}
@@ -9,10 +9,12 @@ pub struct TextureBook {
     pub background_map: Texture,
     pub lcd_display: Texture,
     pub sprite_map: Texture,
+    pub debug_overlay: Texture,
 }
 
 impl TextureBook {
-    pub fn new(canvas: &Canvas<Window>) -> Result<TextureBook, String> {
+    /// The LCD display is `scale` times larger than the gameboy's screen, and holds RGB24 pixels.
+    pub fn new(canvas: &Canvas<Window>, scale: u32) -> Result<TextureBook, String> {
         let creator = canvas.texture_creator();
         let background_map = creator
             .create_texture_target(PixelFormatEnum::RGBA8888, 8 * 32, 8 * 32)
@@ -21,15 +23,20 @@ impl TextureBook {
             .create_texture_target(PixelFormatEnum::RGBA8888, 160, 144)
             .map_err(|e| e.to_string())?;
         let lcd_display = creator
-            .create_texture_target(PixelFormatEnum::RGBA8888, 160, 144)
+            .create_texture_streaming(PixelFormatEnum::RGB24, 160 * scale, 144 * scale)
             .map_err(|e| e.to_string())?;
         sprite_map.set_blend_mode(BlendMode::Blend);
+        let mut debug_overlay = creator
+            .create_texture_streaming(PixelFormatEnum::RGBA32, 160, 144)
+            .map_err(|e| e.to_string())?;
+        debug_overlay.set_blend_mode(BlendMode::Blend);
 
         Ok(TextureBook {
             texture_creator: creator,
             background_map,
             lcd_display,
             sprite_map,
+            debug_overlay,
         })
     }
 }
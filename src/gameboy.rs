@@ -1,18 +1,21 @@
+use crate::apu::{Apu, CLOCK_RATE};
 use crate::cartridge::{self, Cartridge};
 use crate::component::{Addressable, Steppable};
 use crate::cpu::CPU;
 use crate::emulator::events::EmulationEvent;
-use crate::error::Result;
-use crate::joypad::Joypad;
-use crate::memory::MemoryBus;
-use crate::ppu::Ppu;
+use crate::error::{Error, Result};
+use crate::joypad::{Joypad, JoypadInput};
+use crate::memory::{MemoryBus, MemoryBusState};
+use crate::ppu::{self, BasePpu, Layer, NoGuiEngine, OamData, Ppu, TileColor};
 use crate::timer::Timer;
 use core::fmt;
-use log::trace;
-use std::cell::RefCell;
+use log::{error, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::cell::{Ref, RefCell};
 use std::fs;
+use std::io::Write;
 use std::rc::Rc;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{self, Receiver, Sender};
 
 pub type Observer = Box<dyn FnMut(u8)>;
 
@@ -28,23 +31,104 @@ pub struct GameboyDebugInfo {
     mem_TIMA_ff05: u8,
 }
 
+/// The CPU's registers at one moment, e.g. for a debugger to display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuSnapshot {
+    pub pc: u16,
+    pub sp: u16,
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    /// The interrupt master enable flag
+    pub ime: bool,
+    pub halted: bool,
+}
+
+/// Increased whenever the layout of a save state changes, so old states are refused rather than
+/// misread.
+const SAVE_STATE_VERSION: u32 = 3;
+
+/// Everything needed to resume emulation, see `GameBoyState::save_state`. The version comes
+/// first so it can be checked before decoding the rest.
+#[derive(Serialize, Deserialize)]
+struct SaveState {
+    version: u32,
+    cpu: CPU,
+    timer: Timer,
+    joypad: Joypad,
+    apu: Apu,
+    /// See `Ppu::save_state`
+    ppu: Vec<u8>,
+    memory: MemoryBusState,
+    held_inputs: Vec<(JoypadInput, u64)>,
+    clock_overshoot: u64,
+    peripheral_cycle_remainder: f32,
+    cpu_locked: bool,
+}
+
 pub struct GameBoyState {
-    cpu: Rc<RefCell<CPU>>,
+    pub(crate) cpu: Rc<RefCell<CPU>>,
     pub ppu: Rc<RefCell<dyn Ppu>>,
     pub joypad: Rc<RefCell<Joypad>>,
     pub timer: Rc<RefCell<Timer>>,
+    pub apu: Rc<RefCell<Apu>>,
     pub memory_bus: Rc<RefCell<MemoryBus>>,
-    emulation_event_sender: Sender<EmulationEvent>
+    emulation_event_sender: Sender<EmulationEvent>,
+    /// Number of CPU cycles that run for every PPU/timer cycle.
+    clock_multiplier: f32,
+    /// Fractional PPU/timer cycles carried over between ticks.
+    peripheral_cycle_remainder: f32,
+    /// Receives a line of CPU state before every instruction, see `set_cpu_log`.
+    cpu_log: Option<Box<dyn Write>>,
+    /// T-cycles run past the end of the last `clock` call, which has to finish its last
+    /// instruction.
+    clock_overshoot: u64,
+    exception_policy: ExceptionPolicy,
+    /// True once the CPU has stopped after an exception. Other components keep running.
+    cpu_locked: bool,
+    /// Inputs pressed by `hold_input`, with the frame count at which each is released.
+    held_inputs: Vec<(JoypadInput, u64)>,
+    /// If true, cartridges which can't run on the emulated model are refused rather than
+    /// loaded with a warning.
+    strict_cartridge_checks: bool,
+    /// If false, `TileColor::Debug` pixels are shown as White
+    debug_pixels: bool,
+    /// If true, an idle CPU skips ahead to the next peripheral event, see `set_scheduler_enabled`
+    scheduler_enabled: bool,
+    /// If true, every interrupt the CPU dispatches is sent as an
+    /// `EmulationEvent::InterruptServiced`
+    interrupt_logging: bool,
+    /// If true, loops polling LY are skipped up to the next line, see `set_ly_wait_skipping`
+    ly_wait_skipping: bool,
+}
+
+/// What happens when the CPU hits something it can't recover from, such as an illegal opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExceptionPolicy {
+    /// Panic, stopping emulation
+    #[default]
+    Panic,
+    /// Stop the CPU, like the hardware locking up
+    Halt,
+    /// Stop the CPU and send an `EmulationEvent::CpuException`
+    Event,
 }
 
 impl GameBoyState {
     pub fn new(ppu: Rc<RefCell<dyn Ppu>>, emulation_event_sender: Sender<EmulationEvent>) -> Self {
         let joypad = Rc::new(RefCell::new(Joypad::new()));
         let timer = Rc::new(RefCell::new(Timer::new()));
+        let apu = Rc::new(RefCell::new(Apu::new()));
         let memory_bus = Rc::new(RefCell::new(MemoryBus::new(
             ppu.clone(),
             joypad.clone(),
             timer.clone(),
+            apu.clone(),
             emulation_event_sender.clone(),
         )));
         Self {
@@ -52,11 +136,169 @@ impl GameBoyState {
             ppu: ppu.clone(),
             joypad,
             timer,
+            apu,
             memory_bus: memory_bus.clone(),
             emulation_event_sender,
+            clock_multiplier: 1.0,
+            peripheral_cycle_remainder: 0.0,
+            cpu_log: None,
+            clock_overshoot: 0,
+            exception_policy: ExceptionPolicy::default(),
+            cpu_locked: false,
+            held_inputs: Vec::new(),
+            strict_cartridge_checks: false,
+            debug_pixels: false,
+            scheduler_enabled: false,
+            interrupt_logging: false,
+            ly_wait_skipping: false,
         }
     }
 
+    /// Creates a headless gameboy without a cartridge, with `code` copied into memory at
+    /// `load_addr` and the program counter set to `start_pc`. Useful for testing small
+    /// hand-assembled programs.
+    ///
+    /// Since there is no cartridge, `code` should be placed in RAM (e.g. work RAM at 0xc000).
+    /// Emulation events are discarded.
+    pub fn new_raw(code: &[u8], load_addr: u16, start_pc: u16) -> Result<Self> {
+        let (gameboy, _) = Self::new_headless();
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write(load_addr.into(), code)?;
+        gameboy.set_pc(start_pc);
+        Ok(gameboy)
+    }
+
+    /// Creates a headless gameboy without a cartridge, along with the receiver for its emulation
+    /// events.
+    pub fn new_headless() -> (Self, Receiver<EmulationEvent>) {
+        let (sender, receiver) = mpsc::channel();
+        let ppu = Rc::new(RefCell::new(BasePpu::new(Box::new(NoGuiEngine::new()))));
+        (Self::new(ppu, sender), receiver)
+    }
+
+    pub fn set_pc(&self, pc: u16) {
+        self.cpu.borrow_mut().pc = pc;
+    }
+
+    pub fn set_sp(&self, sp: u16) {
+        self.cpu.borrow_mut().sp = sp;
+    }
+
+    /// Logs the CPU state before every instruction to `writer`, one `cpu_log_line` per line, or
+    /// stops logging if `writer` is None.
+    pub fn set_cpu_log(&mut self, writer: Option<Box<dyn Write>>) {
+        self.cpu_log = writer;
+    }
+
+    /// Describes the registers and the 4 bytes at PC in the format used by Gameboy Doctor, so
+    /// traces can be diffed against logs from other emulators.
+    pub fn cpu_log_line(&self) -> String {
+        let cpu = self.cpu.borrow();
+        let mut memory_bus = self.memory_bus.borrow_mut();
+        let pcmem: Vec<String> = (0..4)
+            .map(|offset| {
+                let address = cpu.pc.wrapping_add(offset);
+                let value = memory_bus.peek(address.into()).unwrap_or(0xff);
+                format!("{:02X}", value)
+            })
+            .collect();
+
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+            cpu.registers.a,
+            u8::from(cpu.registers.f),
+            cpu.registers.b,
+            cpu.registers.c,
+            cpu.registers.d,
+            cpu.registers.e,
+            cpu.registers.h,
+            cpu.registers.l,
+            cpu.sp,
+            cpu.pc,
+            pcmem.join(","),
+        )
+    }
+
+    /// Dumps the CPU registers, the main IO registers and the PPU's progress as JSON, for
+    /// debugging and diffing against other emulators. Memory isn't included.
+    pub fn export_json(&self) -> String {
+        let cpu = self.cpu.borrow();
+        let cpu_fields = [
+            ("a", cpu.registers.a.to_string()),
+            ("f", u8::from(cpu.registers.f).to_string()),
+            ("b", cpu.registers.b.to_string()),
+            ("c", cpu.registers.c.to_string()),
+            ("d", cpu.registers.d.to_string()),
+            ("e", cpu.registers.e.to_string()),
+            ("h", cpu.registers.h.to_string()),
+            ("l", cpu.registers.l.to_string()),
+            ("sp", cpu.sp.to_string()),
+            ("pc", cpu.pc.to_string()),
+            ("ime", cpu.interrupt_enabled.to_string()),
+            ("halted", cpu.halted.to_string()),
+            ("stopped", cpu.stopped.to_string()),
+        ];
+
+        let mut memory_bus = self.memory_bus.borrow_mut();
+        let io_fields = JSON_IO_REGISTERS.map(|(name, address)| {
+            let value = memory_bus.peek(address).unwrap_or(0xff);
+            (name, value.to_string())
+        });
+
+        let ppu = self.ppu.borrow();
+        let ppu_fields = [
+            ("lcd_enabled", ppu.lcd_enabled().to_string()),
+            ("mode", ppu.mode().to_string()),
+            ("ly", ppu.ly().to_string()),
+            ("frame_count", ppu.frame_count().to_string()),
+        ];
+
+        let object = |fields: &[(&str, String)]| {
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|(name, value)| format!("    \"{}\": {}", name, value))
+                .collect();
+            format!("{{\n{}\n  }}", fields.join(",\n"))
+        };
+        format!(
+            "{{\n  \"cpu\": {},\n  \"io\": {},\n  \"ppu\": {}\n}}",
+            object(&cpu_fields),
+            object(&io_fields),
+            object(&ppu_fields),
+        )
+    }
+
+    /// Scales the CPU clock relative to the PPU and timer, so a multiplier of 2.0 runs twice as
+    /// many CPU cycles per frame while video timing stays fixed.
+    ///
+    /// This is an experimental enhancement, not an accuracy feature: games that rely on the CPU
+    /// and the rest of the hardware running in lockstep (timing-sensitive effects, audio) may
+    /// break when the multiplier is anything other than 1.0.
+    pub fn set_clock_multiplier(&mut self, multiplier: f32) {
+        assert!(multiplier > 0.0, "clock multiplier must be positive");
+        self.clock_multiplier = multiplier;
+        self.peripheral_cycle_remainder = 0.0;
+    }
+
+    pub fn clock_multiplier(&self) -> f32 {
+        self.clock_multiplier
+    }
+
+    /// Converts elapsed CPU M-cycles into the number of M-cycles the PPU and timer should run
+    /// for, carrying any fractional part over to the next call.
+    fn peripheral_cycles(&mut self, cpu_cycles: u64) -> u64 {
+        if self.clock_multiplier == 1.0 {
+            return cpu_cycles;
+        }
+
+        let cycles = cpu_cycles as f32 / self.clock_multiplier + self.peripheral_cycle_remainder;
+        let whole_cycles = cycles.floor();
+        self.peripheral_cycle_remainder = cycles - whole_cycles;
+        whole_cycles as u64
+    }
+
     pub fn get_pc(&self) -> u16 {
         self.cpu.borrow().pc
     }
@@ -67,33 +309,221 @@ impl GameBoyState {
         self.load_cartridge(cartridge)
     }
 
+    /// Resets the gameboy and inserts a cartridge, so loading a new game into an existing
+    /// gameboy behaves like starting a new one.
+    ///
+    /// A CGB-only game on a DMG is loaded anyway with an `EmulationEvent::CartridgeWarning`, or
+    /// refused if strict cartridge checks are on, in which case the gameboy is left untouched.
     pub fn load_cartridge(&mut self, cartridge: Cartridge) -> Result<()> {
         println!("Loaded cartridge: {:?}", cartridge);
+        if cartridge.features().cgb_only && !self.memory_bus.borrow().cgb_mode() {
+            let message = "cartridge requires a Game Boy Color, but a DMG is being emulated";
+            if self.strict_cartridge_checks {
+                return Err(Error::new(message));
+            }
+            warn!("{}", message);
+            self.emulation_event(EmulationEvent::CartridgeWarning(message.to_string()));
+        }
+        self.reset();
         let mut memory_bus = self.memory_bus.borrow_mut();
         memory_bus.insert_cartridge(cartridge);
         trace!("{:#x}", memory_bus.read_u8(0x100)?);
         Ok(())
     }
 
+    /// Returns every component to its power-on state, like switching the gameboy off and on
+    /// again. The cartridge stays inserted, and the clock multiplier and CPU log are settings
+    /// rather than state, so they're kept.
+    pub fn reset(&mut self) {
+        *self.cpu.borrow_mut() = CPU::new();
+        self.ppu.borrow_mut().reset();
+        *self.joypad.borrow_mut() = Joypad::new();
+        *self.timer.borrow_mut() = Timer::new();
+        self.apu.borrow_mut().reset();
+        self.memory_bus.borrow_mut().reset();
+        self.peripheral_cycle_remainder = 0.0;
+        self.clock_overshoot = 0;
+        self.cpu_locked = false;
+        self.held_inputs.clear();
+    }
+
+    /// Makes `load_cartridge` refuse cartridges which can't run on the emulated model, instead
+    /// of warning about them.
+    pub fn set_strict_cartridge_checks(&mut self, strict: bool) {
+        self.strict_cartridge_checks = strict;
+    }
+
+    /// Switches between DMG and CGB behaviour. CGB support is incomplete: only work RAM and VRAM
+    /// banking, VRAM DMA, palette RAM, background attributes and the object priority mode are
+    /// emulated so far.
+    pub fn set_cgb_mode(&self, enabled: bool) {
+        self.ppu.borrow_mut().set_cgb_mode(enabled);
+        self.memory_bus.borrow_mut().set_cgb_mode(enabled);
+    }
+
+    /// Sends an `EmulationEvent::InterruptServiced` whenever the CPU jumps to an interrupt
+    /// handler. Off by default, since busy games service hundreds of interrupts a second.
+    pub fn set_interrupt_logging(&mut self, enabled: bool) {
+        self.interrupt_logging = enabled;
+    }
+
+    /// Chooses what happens when the CPU hits an unrecoverable error. Panics by default.
+    pub fn set_exception_policy(&mut self, policy: ExceptionPolicy) {
+        self.exception_policy = policy;
+    }
+
+    /// True iff the CPU is in STOP standby, where the LCD is blank and the PPU and timer are
+    /// frozen until a button is pressed.
+    pub fn in_standby(&self) -> bool {
+        self.cpu.borrow().stopped
+    }
+
+    /// True iff the CPU has stopped after an exception, under the `Halt` or `Event` policy.
+    pub fn cpu_locked(&self) -> bool {
+        self.cpu_locked
+    }
+
+    /// Handles an error from the CPU according to the exception policy, returning the M-cycles
+    /// that pass.
+    fn cpu_exception(&mut self, err: Error) -> u64 {
+        match self.exception_policy {
+            ExceptionPolicy::Panic => panic!("error while stepping cpu: {}", err),
+            ExceptionPolicy::Halt => error!("cpu stopped: {}", err),
+            ExceptionPolicy::Event => self.emulation_event(EmulationEvent::CpuException(err.msg)),
+        }
+        self.cpu_locked = true;
+        1
+    }
+
+    /// Stops the CPU after it has locked up like the hardware does, see `CPU::locked_up`. This
+    /// isn't an emulator error, so it never panics, but the `Event` policy still reports it.
+    fn cpu_lockup(&mut self) {
+        let msg = "HALT with interrupts enabled but none selected in IE";
+        warn!("cpu locked up: {}", msg);
+        if self.exception_policy == ExceptionPolicy::Event {
+            self.emulation_event(EmulationEvent::CpuException(msg.to_string()));
+        }
+        self.cpu_locked = true;
+    }
+
+    /// Runs the CPU for one instruction, or one interrupt dispatch, and the rest of the gameboy
+    /// alongside it, skipping ahead where the scheduler or LY wait skipping allow. Returns the
+    /// T-cycles that passed.
     pub fn tick(&mut self) -> u64 {
+        self.step(true)
+    }
+
+    /// Like `tick`, but never skips ahead with the scheduler or LY wait skipping, so a debugger
+    /// sees every instruction. While halted each step is one M-cycle. Fails if the CPU is locked
+    /// by an exception, either already or during this instruction.
+    pub fn step_instruction(&mut self) -> Result<u8> {
+        if self.cpu_locked {
+            return Err(Error::new("CPU is locked after an exception"));
+        }
+        let t_cycles = self.step(false);
+        if self.cpu_locked {
+            return Err(Error::new("CPU exception during instruction"));
+        }
+        u8::try_from(t_cycles).map_err(|_| Error::new("instruction took too many cycles"))
+    }
+
+    /// The CPU's registers, see `CpuSnapshot`.
+    pub fn cpu_snapshot(&self) -> CpuSnapshot {
+        let cpu = self.cpu.borrow();
+        CpuSnapshot {
+            pc: cpu.pc,
+            sp: cpu.sp,
+            a: cpu.registers.a,
+            f: u8::from(cpu.registers.f),
+            b: cpu.registers.b,
+            c: cpu.registers.c,
+            d: cpu.registers.d,
+            e: cpu.registers.e,
+            h: cpu.registers.h,
+            l: cpu.registers.l,
+            ime: cpu.interrupt_enabled,
+            halted: cpu.halted,
+        }
+    }
+
+    /// Steps the CPU and runs the peripherals for as long as it took. If `skip_ahead` is true, an
+    /// idle or polling CPU may be skipped ahead, see `set_scheduler_enabled`.
+    fn step(&mut self, skip_ahead: bool) -> u64 {
         self.emulation_event(EmulationEvent::Trace(self.debug_info()));
+        if self.cpu_log.is_some() {
+            let line = self.cpu_log_line();
+            if let Some(cpu_log) = self.cpu_log.as_mut() {
+                writeln!(cpu_log, "{}", line).expect("error writing cpu log");
+            }
+        }
 
-        let elapsed_cycles = self
-            .cpu
-            .borrow_mut()
-            .step(&self)
-            .expect("error while stepping cpu");
-        {
+        let elapsed_cycles = if self.cpu_locked {
+            1
+        } else if let Some(skipped_cycles) = skip_ahead.then(|| self.ly_wait_cycles()).flatten() {
+            skipped_cycles
+        } else {
+            let result = self.cpu.borrow_mut().step(&self);
+            let elapsed_cycles = result.unwrap_or_else(|err| self.cpu_exception(err));
+            if self.cpu.borrow().locked_up {
+                self.cpu_lockup();
+            }
+            elapsed_cycles
+        };
+        if self.interrupt_logging {
+            let serviced_interrupt = self.cpu.borrow_mut().serviced_interrupt.take();
+            if let Some((kind, pc)) = serviced_interrupt {
+                let vector = kind.vector();
+                self.emulation_event(EmulationEvent::InterruptServiced { kind, vector, pc });
+            }
+        }
+        // STOP standby freezes the PPU and timer until a button wakes the CPU, though time
+        // still passes for frame pacing and the cartridge's real-time clock, which has its own
+        // crystal
+        if self.in_standby() {
+            self.memory_bus
+                .borrow_mut()
+                .step_cartridge(4 * elapsed_cycles);
+            return 4 * elapsed_cycles;
+        }
+
+        let mut elapsed_cycles = self.peripheral_cycles(elapsed_cycles);
+        if skip_ahead && self.scheduler_enabled {
+            elapsed_cycles = elapsed_cycles.max(self.idle_cycles());
+        }
+        for _ in 0..elapsed_cycles {
+            // OAM DMA copies a byte every M-cycle, interleaved with the PPU's OAM search
+            self.memory_bus
+                .borrow_mut()
+                .step_dma(1)
+                .expect("error while stepping oam dma");
+            // The APU reads DIV, so it steps before the timer is borrowed
+            self.apu
+                .borrow_mut()
+                .step(self)
+                .expect("error while stepping apu");
             let mut ppu = self.ppu.borrow_mut();
             let mut timer = self.timer.borrow_mut();
-            for _ in 0..elapsed_cycles {
-                ppu.step(&self).expect("error while stepping ppu");
-                // Timer steps each T-cycle
-                for _ in 0..4 {
-                    timer.step(&self).expect("error while stepping timer");
-                }
+            // The PPU draws one dot and the timer steps once every T-cycle
+            for _ in 0..4 {
+                ppu.step(self).expect("error while stepping ppu");
+                timer.step(self).expect("error while stepping timer");
+            }
+            if ppu.take_hblank_started() {
+                drop(ppu);
+                self.memory_bus
+                    .borrow_mut()
+                    .hblank_started()
+                    .expect("error during hblank dma");
             }
-            trace!("stepped ppu and timer for {} M-cycles", elapsed_cycles);
+        }
+        trace!("stepped ppu, apu and timer for {} M-cycles", elapsed_cycles);
+        let mut memory_bus = self.memory_bus.borrow_mut();
+        memory_bus.instruction_finished();
+        memory_bus.step_cartridge(4 * elapsed_cycles);
+        drop(memory_bus);
+
+        if !self.held_inputs.is_empty() {
+            self.release_held_inputs();
         }
 
         // If data exists on the serial port, output it as an emulation event
@@ -102,10 +532,345 @@ impl GameBoyState {
             self.emulation_event(EmulationEvent::SerialData(byte));
         }
 
-        // Return T-cycles
+        // Return T-cycles, measured by the PPU and timer so frame pacing is unaffected by the
+        // clock multiplier
         4 * elapsed_cycles
     }
 
+    /// Makes `tick` run the peripherals straight up to their next interrupt while the CPU is
+    /// halted or locked, instead of stepping the CPU once per M-cycle. The emulation is the same,
+    /// but the skipped M-cycles don't produce `Trace` events or CPU log lines, and `tick` can
+    /// return up to a frame's worth of cycles at once.
+    pub fn set_scheduler_enabled(&mut self, enabled: bool) {
+        self.scheduler_enabled = enabled;
+    }
+
+    /// Makes `tick` skip iterations of a loop which polls LY until it reaches some value, running
+    /// just the peripherals up to the end of the current line. The emulation is the same, but the
+    /// skipped iterations don't produce `Trace` events or CPU log lines. Only applies while the
+    /// clock multiplier is 1.
+    pub fn set_ly_wait_skipping(&mut self, enabled: bool) {
+        self.ly_wait_skipping = enabled;
+    }
+
+    /// True iff the CPU is at the start of a loop polling LY, see `LyWaitLoop`.
+    pub fn waiting_for_ly(&self) -> bool {
+        self.ly_wait_loop().is_some()
+    }
+
+    fn ly_wait_loop(&self) -> Option<LyWaitLoop> {
+        let pc = self.cpu.borrow().pc;
+        let mut memory_bus = self.memory_bus.borrow_mut();
+        let code: Vec<u8> = (0..7)
+            .map(|offset| memory_bus.peek(pc.wrapping_add(offset).into()).unwrap_or(0))
+            .collect();
+        LyWaitLoop::decode(&code)
+    }
+
+    /// M-cycles of a LY polling loop that can be skipped with LY staying the same and no
+    /// interrupt being serviced, if LY wait skipping is on and the CPU is in such a loop. The
+    /// CPU is left at the start of the loop, ready to poll again.
+    fn ly_wait_cycles(&self) -> Option<u64> {
+        if !self.ly_wait_skipping || self.clock_multiplier != 1.0 {
+            return None;
+        }
+        let ly_wait_loop = self.ly_wait_loop()?;
+        let interrupt_enabled = {
+            let cpu = self.cpu.borrow();
+            if cpu.halted || cpu.stopped || cpu.halt_bug_opcode.is_some() {
+                return None;
+            }
+            cpu.interrupt_enabled
+        };
+        let interrupt_enable = self.memory_bus.borrow_mut().peek(0xffff).unwrap_or(0);
+        // The CPU has to take a pending interrupt before polling again
+        if self.memory_bus.borrow().dma_active()
+            || (interrupt_enabled && interrupt_enable & self.interrupt_flag() != 0)
+        {
+            return None;
+        }
+        let ppu = self.ppu.borrow();
+        if !ppu.lcd_enabled() || ly_wait_loop.exits_on(ppu.ly()) {
+            return None;
+        }
+
+        // LY stays the same until the end of the line
+        let mut cycles = 456 - u64::from(ppu.scanline_dot());
+        let mut margin = 0;
+        if interrupt_enabled && interrupt_enable != 0 {
+            let next_interrupt = [
+                ppu.cycles_until_next_interrupt(),
+                self.timer.borrow().cycles_until_overflow(),
+            ];
+            if let Some(next_interrupt) = next_interrupt.into_iter().flatten().min() {
+                cycles = cycles.min(next_interrupt);
+                // Leave an iteration for the CPU to notice the interrupt in
+                margin = 1;
+            }
+        }
+        let iterations = (cycles / (4 * ly_wait_loop.m_cycles)).checked_sub(margin)?;
+        (iterations > 0).then_some(iterations * ly_wait_loop.m_cycles)
+    }
+
+    /// M-cycles the peripherals can run for before the CPU has anything to do: until the PPU or
+    /// timer next requests an interrupt while the CPU is halted or locked, otherwise 0. Capped at
+    /// a frame in case nothing is scheduled.
+    fn idle_cycles(&self) -> u64 {
+        if !self.cpu_locked && !self.cpu.borrow().halted {
+            return 0;
+        }
+        let next_event = [
+            self.ppu.borrow().cycles_until_next_interrupt(),
+            self.timer.borrow().cycles_until_overflow(),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(CYCLES_PER_FRAME, u64::min);
+        // The interrupt is requested during the M-cycle containing its T-cycle
+        next_event.div_ceil(4)
+    }
+
+    /// Advances every subsystem by `t_cycles` T-cycles, returning the events which occurred along
+    /// the way. Instructions can't be split, so the last one may run past the end; the extra
+    /// cycles are taken off the next call so that time stays exact over many calls.
+    pub fn clock(&mut self, t_cycles: u64) -> Vec<ClockEvent> {
+        let mut events = Vec::new();
+        let mut elapsed = self.clock_overshoot;
+        while elapsed < t_cycles {
+            let interrupt_flag = self.interrupt_flag();
+            let frame_count = self.frame_count();
+
+            elapsed += self.tick();
+
+            let requested = self.interrupt_flag() & !interrupt_flag;
+            for (bit, interrupt) in INTERRUPT_BITS {
+                if requested & (1 << bit) != 0 {
+                    events.push(ClockEvent::InterruptRequested(interrupt));
+                }
+            }
+            // Frames counted while the LCD is off have no VBlank
+            if self.frame_count() != frame_count && self.lcd_enabled() {
+                events.push(ClockEvent::VBlank);
+            }
+        }
+        self.clock_overshoot = elapsed - t_cycles;
+
+        events
+    }
+
+    fn interrupt_flag(&self) -> u8 {
+        self.memory_bus.borrow().interrupt_flag()
+    }
+
+    /// True iff the LCD is switched on, bit 7 of LCDC. Changes are also sent as
+    /// `EmulationEvent::LcdPower`.
+    pub fn lcd_enabled(&self) -> bool {
+        self.ppu.borrow().lcd_enabled()
+    }
+
+    /// The dot (0-455) the PPU has reached on the current line, LY. Together they give the exact
+    /// raster position.
+    pub fn scanline_dot(&self) -> u16 {
+        self.ppu.borrow().scanline_dot()
+    }
+
+    /// Number of frames completed since the gameboy was created or reset, counted as each
+    /// VBlank starts. While the LCD is off, a frame is still counted every `CYCLES_PER_FRAME`
+    /// T-cycles so that frame based timing like `hold_input` and `run_to_frame` carries on.
+    pub fn frame_count(&self) -> u64 {
+        self.ppu.borrow().frame_count()
+    }
+
+    /// Starts counting frames from 0 again. Held inputs keep the same number of frames left.
+    pub fn reset_frame_count(&mut self) {
+        let frame_count = self.frame_count();
+        for (_, release_frame) in self.held_inputs.iter_mut() {
+            *release_frame = release_frame.saturating_sub(frame_count);
+        }
+        self.ppu.borrow_mut().reset_frame_count();
+    }
+
+    /// Presses an input now and releases it once `frames` more frames have completed, for
+    /// scripting menu navigation without tracking releases by hand. Holding an input which is
+    /// already held restarts its count.
+    pub fn hold_input(&mut self, input: JoypadInput, frames: u64) -> Result<()> {
+        let release_frame = self.frame_count() + frames;
+        self.held_inputs.retain(|(held, _)| *held != input);
+        self.held_inputs.push((input, release_frame));
+        if !self.joypad.borrow_mut().key_pressed(input) {
+            self.memory_bus.borrow_mut().interrupt(Interrupt::Joypad)?;
+        }
+        Ok(())
+    }
+
+    /// Releases held inputs whose frames have run out.
+    fn release_held_inputs(&mut self) {
+        let frame_count = self.frame_count();
+        let mut joypad = self.joypad.borrow_mut();
+        self.held_inputs.retain(|(input, release_frame)| {
+            if frame_count < *release_frame {
+                return true;
+            }
+            joypad.key_released(*input);
+            false
+        });
+    }
+
+    /// Runs until the next VBlank starts, completing the current frame.
+    pub fn tick_until_vblank(&mut self) {
+        self.run_to_frame(self.frame_count() + 1);
+    }
+
+    /// Runs until `frame` frames have been completed, stopping as soon as the last one finishes.
+    /// Does nothing if that frame has already been reached. Also stops if the CPU enters STOP
+    /// standby, since no more frames are drawn until a button is pressed.
+    pub fn run_to_frame(&mut self, frame: u64) {
+        while self.frame_count() < frame && !self.in_standby() {
+            self.tick();
+        }
+    }
+
+    /// The CGB background palettes decoded to RGB, e.g. for a palette viewer.
+    pub fn cgb_bg_palettes(&self) -> ppu::CgbPalettes {
+        ppu::decode_cgb_palettes(self.ppu.borrow().bg_palette_ram())
+    }
+
+    /// The CGB object palettes decoded to RGB.
+    pub fn cgb_obj_palettes(&self) -> ppu::CgbPalettes {
+        ppu::decode_cgb_palettes(self.ppu.borrow().obj_palette_ram())
+    }
+
+    /// A copy of the pixels currently displayed on the LCD, 160x144 in row-major order.
+    pub fn screen(&self) -> Vec<TileColor> {
+        let ppu = self.ppu.borrow();
+        if self.in_standby() {
+            vec![TileColor::White; ppu.screen().len()]
+        } else if self.debug_pixels {
+            ppu.screen().to_vec()
+        } else {
+            ppu.screen()
+                .iter()
+                .map(|color| color.hide_debug())
+                .collect()
+        }
+    }
+
+    /// Draws a complete frame from the current PPU state without waiting for VBlank or advancing
+    /// emulation, e.g. for a debug snapshot taken mid-frame. This also replaces the screen.
+    pub fn force_render(&mut self) -> Vec<TileColor> {
+        let dma_active = self.memory_bus.borrow().dma_active();
+        let screen = self.ppu.borrow_mut().force_render(dma_active);
+        if self.debug_pixels {
+            screen
+        } else {
+            screen.iter().map(|color| color.hide_debug()).collect()
+        }
+    }
+
+    /// Shows or hides `TileColor::Debug` pixels, which mark pixels the graphics engine couldn't
+    /// work out. They're hidden (drawn as White) by default, so they never reach players.
+    pub fn set_debug_pixels(&mut self, enabled: bool) {
+        self.debug_pixels = enabled;
+    }
+
+    pub fn debug_pixels(&self) -> bool {
+        self.debug_pixels
+    }
+
+    /// Hides or shows a layer on the display, for debugging which layer produces a pixel. This
+    /// overrides LCDC's enable bits for display only.
+    pub fn set_layer_enabled(&mut self, layer: Layer, enabled: bool) {
+        self.ppu.borrow_mut().set_layer_enabled(layer, enabled);
+    }
+
+    /// Returns and clears the audio produced since the last call, as interleaved left and right
+    /// samples at `apu::SAMPLE_RATE` samples per second.
+    pub fn take_audio(&mut self) -> Vec<f32> {
+        self.apu.borrow_mut().take_samples()
+    }
+
+    /// VRAM (0x8000-0x9fff) as raw bytes, e.g. for a memory editor. Always 0x4000 bytes: bank 0
+    /// followed by the CGB's bank 1, which stays zeroed outside CGB mode. The PPU stays borrowed
+    /// while the result is held. Writes should go through the memory bus so the graphics
+    /// engine's caches stay in sync.
+    pub fn vram(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.ppu.borrow(), |ppu| ppu.vram())
+    }
+
+    /// OAM (0xfe00-0xfe9f) as raw bytes. The PPU stays borrowed while the result is held.
+    pub fn oam(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.ppu.borrow(), |ppu| ppu.oam())
+    }
+
+    /// The sprites selected for the line being drawn, see `Ppu::scanline_sprites`.
+    pub fn current_scanline_sprites(&self) -> Vec<OamData> {
+        self.ppu.borrow().scanline_sprites()
+    }
+
+    /// Renders all 384 tiles in VRAM as a 128x192 image, 16 tiles wide, without needing a gui.
+    pub fn render_tile_atlas(&self) -> Vec<TileColor> {
+        ppu::render_tile_atlas(self.ppu.borrow().tile_data())
+    }
+
+    /// The inserted cartridge's battery-backed RAM, see `Cartridge::save_ram`.
+    pub fn save_ram(&self) -> Option<Vec<u8>> {
+        self.memory_bus.borrow().save_ram()
+    }
+
+    /// Encodes the whole machine, so emulation can later continue from this exact point with
+    /// `load_state`. Settings such as the exception policy and debugging options aren't saved.
+    pub fn save_state(&self) -> Vec<u8> {
+        let state = SaveState {
+            version: SAVE_STATE_VERSION,
+            cpu: self.cpu.borrow().clone(),
+            timer: self.timer.borrow().clone(),
+            joypad: self.joypad.borrow().clone(),
+            apu: self.apu.borrow().clone(),
+            ppu: self.ppu.borrow().save_state(),
+            memory: self.memory_bus.borrow().state(),
+            held_inputs: self.held_inputs.clone(),
+            clock_overshoot: self.clock_overshoot,
+            peripheral_cycle_remainder: self.peripheral_cycle_remainder,
+            cpu_locked: self.cpu_locked,
+        };
+        bincode::serialize(&state).expect("error encoding save state")
+    }
+
+    /// Restores a state from `save_state`. The same cartridge must be loaded as when the state
+    /// was saved. Nothing is changed if the state can't be loaded.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<()> {
+        let version: u32 =
+            bincode::deserialize(bytes).map_err(|_| Error::new("Invalid save state"))?;
+        if version != SAVE_STATE_VERSION {
+            return Err(Error::new("Save state is from an incompatible version"));
+        }
+        let state: SaveState =
+            bincode::deserialize(bytes).map_err(|_| Error::new("Invalid save state"))?;
+
+        // Everything which can fail goes first, so a bad state leaves the machine untouched
+        let old_memory = self.memory_bus.borrow().state();
+        self.memory_bus.borrow_mut().restore_state(state.memory)?;
+        if let Err(error) = self.ppu.borrow_mut().load_state(&state.ppu) {
+            self.memory_bus.borrow_mut().restore_state(old_memory)?;
+            return Err(error);
+        }
+
+        *self.cpu.borrow_mut() = state.cpu;
+        *self.timer.borrow_mut() = state.timer;
+        *self.joypad.borrow_mut() = state.joypad;
+        self.apu.borrow_mut().restore_state(state.apu);
+        self.held_inputs = state.held_inputs;
+        self.clock_overshoot = state.clock_overshoot;
+        self.peripheral_cycle_remainder = state.peripheral_cycle_remainder;
+        self.cpu_locked = state.cpu_locked;
+        Ok(())
+    }
+
+    /// Tiles changed since the last call, see `Ppu::take_dirty_tiles`.
+    pub fn take_dirty_tiles(&self) -> Vec<usize> {
+        self.ppu.borrow_mut().take_dirty_tiles()
+    }
+
     pub fn emulation_event(&self, event: EmulationEvent) {
         self.emulation_event_sender.send(event);
     }
@@ -128,7 +893,7 @@ impl GameBoyState {
             register_bc: u16::from_le_bytes([cpu.registers.b, cpu.registers.c]),
             register_de: u16::from_le_bytes([cpu.registers.d, cpu.registers.e]),
             register_hl: u16::from_le_bytes([cpu.registers.h, cpu.registers.l]),
-            mem_TIMA_ff05: self.memory_bus.borrow_mut().read_u8(0xff05).unwrap(),
+            mem_TIMA_ff05: self.memory_bus.borrow_mut().peek(0xff05).unwrap(),
         }
     }
 }
@@ -152,9 +917,1220 @@ impl std::fmt::Display for GameboyDebugInfo {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Interrupt {
     VBlank,
     Stat,
     Timer,
+    Serial,
     Joypad,
 }
+
+impl Interrupt {
+    /// The interrupt with the given bit in IE and IF.
+    pub fn from_bit(bit: u8) -> Option<Interrupt> {
+        INTERRUPT_BITS
+            .iter()
+            .find(|(interrupt_bit, _)| *interrupt_bit == bit)
+            .map(|(_, interrupt)| *interrupt)
+    }
+
+    /// The address of the interrupt's handler.
+    pub fn vector(self) -> u16 {
+        let (bit, _) = INTERRUPT_BITS
+            .iter()
+            .find(|(_, interrupt)| *interrupt == self)
+            .expect("every interrupt has a bit");
+        0x40 + u16::from(*bit) * 8
+    }
+}
+
+/// IO registers included in `export_json`, by their names in Pan Docs.
+const JSON_IO_REGISTERS: [(&str, usize); 18] = [
+    ("p1", 0xff00),
+    ("div", 0xff04),
+    ("tima", 0xff05),
+    ("tma", 0xff06),
+    ("tac", 0xff07),
+    ("if", 0xff0f),
+    ("lcdc", 0xff40),
+    ("stat", 0xff41),
+    ("scy", 0xff42),
+    ("scx", 0xff43),
+    ("ly", 0xff44),
+    ("lyc", 0xff45),
+    ("bgp", 0xff47),
+    ("obp0", 0xff48),
+    ("obp1", 0xff49),
+    ("wy", 0xff4a),
+    ("wx", 0xff4b),
+    ("ie", 0xffff),
+];
+
+/// A tight loop which reads LY into A, compares it with a constant, and jumps back while the
+/// comparison fails, e.g. `LDH A,(0x44); CP 0x90; JR NZ,-6` to wait for VBlank.
+struct LyWaitLoop {
+    /// M-cycles taken by each iteration which jumps back
+    m_cycles: u64,
+    /// The value LY is compared with
+    compare: u8,
+    /// The JR condition's opcode, which decides when the loop exits
+    condition: u8,
+}
+
+impl LyWaitLoop {
+    /// Recognizes a LY polling loop at the start of `code`, read with either LDH or LD A,(a16).
+    fn decode(code: &[u8]) -> Option<LyWaitLoop> {
+        let (read_length, read_cycles) = match code {
+            [0xf0, 0x44, ..] => (2, 3),
+            [0xfa, 0x44, 0xff, ..] => (3, 4),
+            _ => return None,
+        };
+        match code[read_length..] {
+            [0xfe, compare, condition @ (0x20 | 0x28 | 0x30 | 0x38), offset, ..]
+                if offset as i8 == -(read_length as i8 + 4) =>
+            {
+                Some(LyWaitLoop {
+                    // CP d8 takes 2 M-cycles and a taken JR takes 3
+                    m_cycles: read_cycles + 5,
+                    compare,
+                    condition,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// True iff the loop stops once it reads `ly`.
+    fn exits_on(&self, ly: u8) -> bool {
+        match self.condition {
+            // JR NZ and JR Z loop while LY is different from, or equal to, the constant
+            0x20 => ly == self.compare,
+            0x28 => ly != self.compare,
+            // JR NC and JR C loop while LY is at least, or below, the constant
+            0x30 => ly < self.compare,
+            _ => ly >= self.compare,
+        }
+    }
+}
+
+/// T-cycles taken to draw one frame, including VBlank.
+pub const CYCLES_PER_FRAME: u64 = 70224;
+
+/// Frames drawn per second of emulated time, a little under 60.
+pub const FRAMES_PER_SECOND: f64 = CLOCK_RATE as f64 / CYCLES_PER_FRAME as f64;
+
+/// Each interrupt along with its bit in IF
+const INTERRUPT_BITS: [(u8, Interrupt); 5] = [
+    (0, Interrupt::VBlank),
+    (1, Interrupt::Stat),
+    (2, Interrupt::Timer),
+    (3, Interrupt::Serial),
+    (4, Interrupt::Joypad),
+];
+
+/// Something which happened while the gameboy was clocked, see `GameBoyState::clock`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockEvent {
+    /// The PPU finished drawing a frame and entered VBlank. Not sent while the LCD is off,
+    /// even though `GameBoyState::frame_count` keeps counting.
+    VBlank,
+    /// An interrupt was requested in IF, whether or not the CPU services it
+    InterruptRequested(Interrupt),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::{GraphicsEngine, PpuData};
+
+    /// Builds a headless gameboy running a ROM whose entry point is an infinite `JR -2` loop.
+    fn looping_gameboy() -> GameBoyState {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x18;
+        rom[0x101] = 0xfe;
+        let cartridge = Cartridge::cartridge_from_data(&rom).unwrap();
+
+        let (mut gameboy, _) = GameBoyState::new_headless();
+        gameboy.load_cartridge(cartridge).unwrap();
+        gameboy
+    }
+
+    /// Counts the instructions executed while the PPU runs for the given number of frames.
+    fn instructions_in_frames(gameboy: &mut GameBoyState, frames: u64) -> u64 {
+        let mut elapsed = 0;
+        let mut instructions = 0;
+        while elapsed < frames * 70224 {
+            elapsed += gameboy.tick();
+            instructions += 1;
+        }
+        instructions
+    }
+
+    #[test]
+    fn test_new_raw_steps_through_code_in_work_ram() {
+        // LD A,0x42; INC A; LD B,A
+        let code = [0x3e, 0x42, 0x3c, 0x47];
+        let mut gameboy = GameBoyState::new_raw(&code, 0xc000, 0xc000).unwrap();
+        gameboy.set_sp(0xdfff);
+
+        gameboy.tick();
+        assert_eq!(0xc002, gameboy.get_pc());
+        gameboy.tick();
+        assert_eq!(0xc003, gameboy.get_pc());
+        gameboy.tick();
+        assert_eq!(0xc004, gameboy.get_pc());
+
+        let cpu = gameboy.cpu.borrow();
+        assert_eq!(0x43, cpu.registers.a);
+        assert_eq!(0x43, cpu.registers.b);
+        assert_eq!(0xdfff, cpu.sp);
+    }
+
+    #[test]
+    fn test_dirty_tiles_lists_only_written_tiles() {
+        let gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        assert_eq!(384, gameboy.take_dirty_tiles().len());
+
+        // Two writes to tile 17's data
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write(0x8000 + 17 * 16 + 4, &[0xff, 0xff])
+            .unwrap();
+        assert_eq!(vec![17], gameboy.take_dirty_tiles());
+        assert_eq!(Vec::<usize>::new(), gameboy.take_dirty_tiles());
+    }
+
+    #[test]
+    fn test_render_tile_atlas_places_tiles_16_wide() {
+        let gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        // Set pixel (5, 2) of tile 17 to color 3
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write(0x8000 + 17 * 16 + 2 * 2, &[0b100, 0b100])
+            .unwrap();
+
+        let atlas = gameboy.render_tile_atlas();
+        assert_eq!(128 * 192, atlas.len());
+
+        let black_pixels: Vec<usize> = atlas
+            .iter()
+            .enumerate()
+            .filter(|(_, color)| **color == TileColor::Black)
+            .map(|(i, _)| i)
+            .collect();
+        // Tile 17 is in the second row and second column of the atlas
+        assert_eq!(vec![(8 + 2) * 128 + 8 + 5], black_pixels);
+    }
+
+    #[test]
+    fn test_clock_multiplier_scales_instructions_per_frame() {
+        let mut normal = looping_gameboy();
+        let normal_instructions = instructions_in_frames(&mut normal, 2);
+
+        let mut overclocked = looping_gameboy();
+        overclocked.set_clock_multiplier(2.0);
+        let overclocked_instructions = instructions_in_frames(&mut overclocked, 2);
+
+        let ratio = overclocked_instructions as f64 / normal_instructions as f64;
+        assert!((1.95..=2.05).contains(&ratio), "ratio was {}", ratio);
+    }
+
+    #[test]
+    fn test_ppu_draws_one_dot_per_t_cycle() {
+        let mut gameboy = looping_gameboy();
+        let ly = |gameboy: &GameBoyState| gameboy.memory_bus.borrow_mut().read_u8(0xff44).unwrap();
+        let mut line_starts = Vec::new();
+        let mut elapsed = 0;
+        while line_starts.len() < 4 {
+            let line = ly(&gameboy);
+            elapsed += gameboy.tick();
+            if ly(&gameboy) != line {
+                line_starts.push(elapsed);
+            }
+        }
+        // A line is 456 dots, give or take the 12 T-cycles of a JR
+        for line in line_starts.windows(2) {
+            let line_length = line[1] - line[0];
+            assert!(line_length.abs_diff(456) <= 12, "line took {}", line_length);
+        }
+    }
+
+    #[test]
+    fn test_frames_are_154_lines_and_div_ticks_every_256_t_cycles() {
+        let mut gameboy = looping_gameboy();
+        let mut frame_starts = Vec::new();
+        let mut elapsed = 0;
+        while frame_starts.len() < 3 {
+            let frame_count = gameboy.frame_count();
+            elapsed += gameboy.tick();
+            if gameboy.frame_count() != frame_count {
+                frame_starts.push(elapsed);
+            }
+        }
+        // A frame is 154 lines of 456 dots, give or take the 12 T-cycles of a JR
+        for frame in frame_starts.windows(2) {
+            let frame_length = frame[1] - frame[0];
+            assert!(
+                frame_length.abs_diff(70224) <= 12,
+                "frame took {}",
+                frame_length
+            );
+        }
+
+        // DIV counts up every 256 T-cycles
+        let div = gameboy.memory_bus.borrow_mut().peek(0xff04).unwrap();
+        gameboy.clock(256 * 16);
+        let ticks = gameboy
+            .memory_bus
+            .borrow_mut()
+            .peek(0xff04)
+            .unwrap()
+            .wrapping_sub(div);
+        assert!((15..=17).contains(&ticks), "DIV ticked {} times", ticks);
+    }
+
+    #[test]
+    fn test_clock_reports_one_vblank_per_frame() {
+        let mut gameboy = looping_gameboy();
+        // A frame is 154 lines of 456 dots
+        let events = gameboy.clock(70224);
+
+        let vblanks = events.iter().filter(|event| **event == ClockEvent::VBlank);
+        assert_eq!(1, vblanks.count());
+        assert!(events.contains(&ClockEvent::InterruptRequested(Interrupt::VBlank)));
+    }
+
+    #[test]
+    fn test_clock_reports_no_vblank_while_the_lcd_is_off() {
+        let mut gameboy = looping_gameboy();
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write_u8(0xff40, 0x11)
+            .unwrap();
+        let events = gameboy.clock(2 * CYCLES_PER_FRAME);
+
+        assert!(events.is_empty(), "{:?}", events);
+        assert_eq!(2, gameboy.frame_count());
+    }
+
+    #[test]
+    fn test_lcd_power_changes_are_reported() {
+        let (gameboy, receiver) = GameBoyState::new_headless();
+        let lcd_power_events = || {
+            receiver
+                .try_iter()
+                .filter_map(|event| match event {
+                    EmulationEvent::LcdPower(enabled) => Some(enabled),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The LCD starts on, as the boot ROM leaves it
+        assert!(gameboy.lcd_enabled());
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write_u8(0xff40, 0x11)
+            .unwrap();
+        assert_eq!(vec![false], lcd_power_events());
+
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write_u8(0xff40, 0x91)
+            .unwrap();
+        assert!(gameboy.lcd_enabled());
+        assert_eq!(vec![true], lcd_power_events());
+
+        // Writes which leave bit 7 alone aren't transitions
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write_u8(0xff40, 0x93)
+            .unwrap();
+        assert_eq!(Vec::<bool>::new(), lcd_power_events());
+
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write_u8(0xff40, 0x13)
+            .unwrap();
+        assert!(!gameboy.lcd_enabled());
+        assert_eq!(vec![false], lcd_power_events());
+    }
+
+    #[test]
+    fn test_cgb_only_cartridge_on_dmg_is_reported() {
+        let (mut gameboy, receiver) = GameBoyState::new_headless();
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0xc0;
+
+        gameboy
+            .load_cartridge(Cartridge::cartridge_from_data(&rom).unwrap())
+            .unwrap();
+        let warnings = receiver
+            .try_iter()
+            .filter(|event| matches!(event, EmulationEvent::CartridgeWarning(_)))
+            .count();
+        assert_eq!(1, warnings);
+
+        gameboy.set_strict_cartridge_checks(true);
+        assert!(gameboy
+            .load_cartridge(Cartridge::cartridge_from_data(&rom).unwrap())
+            .is_err());
+
+        // Games which also run on a DMG are fine
+        rom[0x143] = 0x80;
+        gameboy
+            .load_cartridge(Cartridge::cartridge_from_data(&rom).unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_load_cartridge_leaves_no_trace_of_previous_cartridge() {
+        let mut rom_a = vec![0; 0x8000];
+        rom_a[0x134..0x138].copy_from_slice(b"ROMA");
+        let code = [
+            0x3e, 0xff, 0xea, 0x00, 0x80, // LD A,0xff; LD (0x8000),A -- tile 0's first row
+            0x3e, 0xe4, 0xe0, 0x47, // LD A,0xe4; LDH (BGP),A
+            0x3e, 0x91, 0xe0, 0x40, // LD A,0x91; LDH (LCDC),A
+            0x3e, 0xc1, 0xe0, 0x51, 0xe0, 0x53, // LD A,0xc1; LDH (HDMA1),A; LDH (HDMA3),A
+            0xe0, 0x46, // LDH (DMA),A -- copy 0xc100-0xc19f to OAM
+            0x18, 0xfe, // JR -2
+        ];
+        rom_a[0x100..0x100 + code.len()].copy_from_slice(&code);
+
+        let mut rom_b = vec![0; 0x8000];
+        rom_b[0x134..0x138].copy_from_slice(b"ROMB");
+        rom_b[0x100] = 0x18;
+        rom_b[0x101] = 0xfe;
+
+        let mut reused = looping_gameboy();
+        reused
+            .load_cartridge(Cartridge::cartridge_from_data(&rom_a).unwrap())
+            .unwrap();
+        reused.run_to_frame(2);
+        assert!(reused.screen().contains(&TileColor::LightGrey));
+
+        reused
+            .load_cartridge(Cartridge::cartridge_from_data(&rom_b).unwrap())
+            .unwrap();
+        reused.run_to_frame(2);
+
+        let mut fresh = looping_gameboy();
+        fresh
+            .load_cartridge(Cartridge::cartridge_from_data(&rom_b).unwrap())
+            .unwrap();
+        fresh.run_to_frame(2);
+
+        let mut title = [0; 4];
+        reused
+            .memory_bus
+            .borrow_mut()
+            .read(0x134, &mut title)
+            .unwrap();
+        assert_eq!(b"ROMB", &title);
+        assert!(fresh.screen() == reused.screen());
+        assert_eq!(fresh.cpu_log_line(), reused.cpu_log_line());
+        // Including the DMA source and destination registers, which can't be read back
+        assert!(fresh.save_state() == reused.save_state());
+    }
+
+    #[test]
+    fn test_save_state_resumes_at_the_same_point() {
+        let mut rom = vec![0; 0x8000];
+        let code = [
+            0x3e, 0xf0, 0xea, 0x00, 0x80, // LD A,0xf0; LD (0x8000),A -- tile 0's first row
+            0x3e, 0x91, 0xe0, 0x40, // LD A,0x91; LDH (LCDC),A
+            0x04, 0x78, 0xe0, 0x43, // loop: INC B; LD A,B; LDH (SCX),A
+            0x18, 0xfb, // JR loop
+        ];
+        rom[0x100..0x100 + code.len()].copy_from_slice(&code);
+        let mut gameboy = looping_gameboy();
+        gameboy
+            .load_cartridge(Cartridge::cartridge_from_data(&rom).unwrap())
+            .unwrap();
+        gameboy.run_to_frame(3);
+        // Save mid-frame, so the PPU's timing has to be restored as well
+        for _ in 0..1000 {
+            gameboy.tick();
+        }
+        let state = gameboy.save_state();
+
+        gameboy.run_to_frame(6);
+        let screen = gameboy.screen();
+        let snapshot = gameboy.cpu_snapshot();
+
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(3, gameboy.frame_count());
+        gameboy.run_to_frame(6);
+        assert!(screen == gameboy.screen());
+        assert_eq!(snapshot, gameboy.cpu_snapshot());
+
+        let mut other_version = state.clone();
+        other_version[0] ^= 0xff;
+        assert!(gameboy.load_state(&other_version).is_err());
+        assert!(gameboy.load_state(&state[..100]).is_err());
+        assert_eq!(snapshot, gameboy.cpu_snapshot());
+    }
+
+    #[test]
+    fn test_halt_policy_stops_cpu_on_illegal_opcode() {
+        // NOP; illegal opcode 0xd3
+        let mut gameboy = GameBoyState::new_raw(&[0x00, 0xd3, 0x00], 0xc000, 0xc000).unwrap();
+        gameboy.set_exception_policy(ExceptionPolicy::Halt);
+
+        gameboy.tick();
+        assert!(!gameboy.cpu_locked());
+        gameboy.tick();
+        assert!(gameboy.cpu_locked());
+
+        // Time still passes, but no more instructions run
+        let pc = gameboy.get_pc();
+        assert_eq!(4, gameboy.tick());
+        assert_eq!(pc, gameboy.get_pc());
+    }
+
+    #[test]
+    fn test_halt_without_enabled_interrupts_is_a_lockup() {
+        let (mut gameboy, receiver) = GameBoyState::new_headless();
+        gameboy.set_exception_policy(ExceptionPolicy::Event);
+        // EI; HALT, with IE left at 0
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write(0xc000, &[0xfb, 0x76])
+            .unwrap();
+        gameboy.memory_bus.borrow_mut().write_u8(0xffff, 0).unwrap();
+        gameboy.set_pc(0xc000);
+
+        for _ in 0..4 {
+            gameboy.tick();
+        }
+        assert!(gameboy.cpu_locked());
+        let exceptions = receiver
+            .try_iter()
+            .filter(|event| matches!(event, EmulationEvent::CpuException(_)))
+            .count();
+        assert_eq!(1, exceptions);
+    }
+
+    #[test]
+    fn test_halt_lockup_does_not_panic_under_default_policy() {
+        // EI; HALT, with IE left at 0
+        let mut gameboy = GameBoyState::new_raw(&[0xfb, 0x76], 0xc000, 0xc000).unwrap();
+        gameboy.memory_bus.borrow_mut().write_u8(0xffff, 0).unwrap();
+
+        gameboy.run_to_frame(2);
+        assert!(gameboy.cpu_locked());
+        assert_eq!(0xc002, gameboy.get_pc());
+    }
+
+    #[test]
+    fn test_interrupt_logging_reports_serviced_vblank() {
+        let (mut gameboy, receiver) = GameBoyState::new_headless();
+        gameboy.set_interrupt_logging(true);
+        // NOP, with a VBlank interrupt enabled and requested
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write(0xc000, &[0x00])
+            .unwrap();
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write_u8(0xffff, 0x01)
+            .unwrap();
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write_u8(0xff0f, 0x01)
+            .unwrap();
+        gameboy.cpu.borrow_mut().interrupt_enabled = true;
+        gameboy.set_pc(0xc000);
+
+        gameboy.tick();
+        let serviced: Vec<_> = receiver
+            .try_iter()
+            .filter_map(|event| match event {
+                EmulationEvent::InterruptServiced { kind, vector, pc } => Some((kind, vector, pc)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![(Interrupt::VBlank, 0x0040, 0xc001)], serviced);
+    }
+
+    #[test]
+    fn test_take_audio_returns_samples_of_playing_tone() {
+        let code = [
+            0x3e, 0x80, 0xe0, 0x26, // LD A,0x80; LDH (NR52),A -- sound on
+            0x3e, 0x77, 0xe0, 0x24, // LD A,0x77; LDH (NR50),A -- full volume
+            0x3e, 0xff, 0xe0, 0x25, // LD A,0xff; LDH (NR51),A -- all channels to both sides
+            0x3e, 0x80, 0xe0, 0x11, // LD A,0x80; LDH (NR11),A -- 50% duty
+            0x3e, 0xf0, 0xe0, 0x12, // LD A,0xf0; LDH (NR12),A -- max volume
+            0x3e, 0x00, 0xe0, 0x13, // LD A,0x00; LDH (NR13),A
+            0x3e, 0x87, 0xe0, 0x14, // LD A,0x87; LDH (NR14),A -- trigger at about 1kHz
+            0x18, 0xfe, // JR -2
+        ];
+        let mut gameboy = GameBoyState::new_raw(&code, 0xc000, 0xc000).unwrap();
+
+        let mut elapsed = 0;
+        while elapsed < 70224 {
+            elapsed += gameboy.tick();
+        }
+
+        let samples = gameboy.take_audio();
+        let expected_frames = elapsed * crate::apu::SAMPLE_RATE / 4_194_304;
+        assert_eq!(2 * expected_frames as usize, samples.len());
+        assert!(samples.iter().any(|sample| *sample > 0.1));
+        assert!(samples.iter().any(|sample| *sample < -0.1));
+
+        // Samples are only returned once
+        assert!(gameboy.take_audio().is_empty());
+    }
+
+    #[test]
+    fn test_run_to_frame_stops_when_frame_completes() {
+        let mut gameboy = looping_gameboy();
+        gameboy.run_to_frame(50);
+        assert_eq!(50, gameboy.frame_count());
+
+        // Running to an earlier frame has no effect
+        let pc = gameboy.get_pc();
+        gameboy.run_to_frame(10);
+        assert_eq!(50, gameboy.frame_count());
+        assert_eq!(pc, gameboy.get_pc());
+    }
+
+    #[test]
+    fn test_disabled_sprite_layer_shows_background() {
+        // JR -2
+        let mut gameboy = GameBoyState::new_raw(&[0x18, 0xfe], 0xc000, 0xc000).unwrap();
+        {
+            let mut memory_bus = gameboy.memory_bus.borrow_mut();
+            // Tile 1 is entirely color 3, drawn by a sprite in the top left corner
+            for row in 0..8 {
+                memory_bus.write(0x8010 + row * 2, &[0xff, 0xff]).unwrap();
+            }
+            memory_bus.write(0xfe00, &[16, 8, 1, 0]).unwrap();
+            memory_bus.write_u8(0xff47, 0xe4).unwrap();
+            memory_bus.write_u8(0xff48, 0xe4).unwrap();
+            // LCD on, 0x8000 tile data, sprites and background enabled
+            memory_bus.write_u8(0xff40, 0x93).unwrap();
+        }
+
+        gameboy.run_to_frame(1);
+        assert_eq!(TileColor::Black, gameboy.screen()[0]);
+
+        gameboy.set_layer_enabled(Layer::Sprites, false);
+        gameboy.run_to_frame(2);
+        assert_eq!(TileColor::White, gameboy.screen()[0]);
+    }
+
+    #[test]
+    fn test_frame_count_follows_vblanks_and_resets() {
+        let mut gameboy = looping_gameboy();
+        for frame in 1..=3 {
+            gameboy.tick_until_vblank();
+            assert_eq!(frame, gameboy.frame_count());
+            assert_eq!(1, gameboy.interrupt_flag() & 1);
+            gameboy.memory_bus.borrow_mut().write_u8(0xff0f, 0).unwrap();
+        }
+
+        gameboy.reset_frame_count();
+        assert_eq!(0, gameboy.frame_count());
+        gameboy.tick_until_vblank();
+        assert_eq!(1, gameboy.frame_count());
+
+        gameboy.reset();
+        assert_eq!(0, gameboy.frame_count());
+    }
+
+    #[test]
+    fn test_cgb_palettes_written_through_registers_are_decoded() {
+        let gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+        // Only a CGB has palette RAM
+        memory_bus.write(0xff6a, &[0x80, 0xff]).unwrap();
+        assert_eq!(0xff, memory_bus.read_u8(0xff6b).unwrap());
+        drop(memory_bus);
+        assert_eq!((0, 0, 0), gameboy.cgb_obj_palettes()[0][0]);
+
+        gameboy.set_cgb_mode(true);
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+        // Background palette 1, color 3 is red 31, green 16, blue 1, written with auto-increment
+        memory_bus.write_u8(0xff68, 0x80 | 14).unwrap();
+        let color: u16 = 31 | 16 << 5 | 1 << 10;
+        for byte in color.to_le_bytes() {
+            memory_bus.write_u8(0xff69, byte).unwrap();
+        }
+        assert_eq!(0xc0 | 16, memory_bus.read_u8(0xff68).unwrap());
+        drop(memory_bus);
+
+        // Each 5 bit component c becomes c << 3 | c >> 2
+        assert_eq!((0xff, 0x84, 0x08), gameboy.cgb_bg_palettes()[1][3]);
+        assert_eq!((0, 0, 0), gameboy.cgb_bg_palettes()[1][2]);
+    }
+
+    /// An engine which can't draw anything, so the whole screen is `TileColor::Debug`.
+    struct DebugEngine(Vec<TileColor>);
+
+    impl GraphicsEngine for DebugEngine {
+        fn place_pixel(&mut self, _data: &PpuData, _x: u8, _y: u8) {}
+
+        fn reset(&mut self) {}
+
+        fn screen(&self) -> &[TileColor] {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn test_vram_and_oam_reflect_bus_writes() {
+        let gameboy = GameBoyState::new_raw(&[0x18, 0xfe], 0xc000, 0xc000).unwrap();
+        assert_eq!(0x4000, gameboy.vram().len());
+        assert_eq!(0xa0, gameboy.oam().len());
+
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+        memory_bus.write_u8(0x8001, 0x12).unwrap();
+        memory_bus.write_u8(0x9c00, 0x34).unwrap();
+        memory_bus.write_u8(0xfe9f, 0x56).unwrap();
+        drop(memory_bus);
+
+        let vram = gameboy.vram();
+        assert_eq!(0x12, vram[0x0001]);
+        assert_eq!(0x34, vram[0x1c00]);
+        assert_eq!(0x56, gameboy.oam()[0x9f]);
+    }
+
+    #[test]
+    fn test_vram_includes_bank1_in_cgb_mode() {
+        let gameboy = GameBoyState::new_raw(&[0x18, 0xfe], 0xc000, 0xc000).unwrap();
+        gameboy.set_cgb_mode(true);
+        assert_eq!(0x4000, gameboy.vram().len());
+
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+        memory_bus.write_u8(0x8001, 0x12).unwrap();
+        memory_bus.write_u8(0xff4f, 0x01).unwrap();
+        memory_bus.write_u8(0x8001, 0x34).unwrap();
+        drop(memory_bus);
+
+        let vram = gameboy.vram();
+        assert_eq!(0x12, vram[0x0001]);
+        assert_eq!(0x34, vram[0x2001]);
+    }
+
+    #[test]
+    fn test_current_scanline_sprites_are_in_priority_order() {
+        let mut gameboy = GameBoyState::new_raw(&[0x18, 0xfe], 0xc000, 0xc000).unwrap();
+        // Three sprites on lines 16-23 and one below them, with X out of order
+        let oam = [
+            [32, 40, 1, 0],
+            [32, 16, 2, 0],
+            [80, 8, 3, 0],
+            [32, 28, 4, 0],
+        ];
+        gameboy
+            .ppu
+            .borrow_mut()
+            .write(0xfe00, &oam.concat())
+            .unwrap();
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write_u8(0xff40, 0x82)
+            .unwrap();
+
+        while gameboy.ppu.borrow().ly() != 20 || gameboy.ppu.borrow().mode() != 3 {
+            gameboy.tick();
+        }
+        let tiles: Vec<u8> = gameboy
+            .current_scanline_sprites()
+            .iter()
+            .map(|sprite| sprite.tile_index())
+            .collect();
+        assert_eq!(vec![2, 4, 1], tiles);
+    }
+
+    #[test]
+    fn test_force_render_draws_full_frame_mid_frame() {
+        let mut gameboy = GameBoyState::new_raw(&[0x18, 0xfe], 0xc000, 0xc000).unwrap();
+        {
+            let mut memory_bus = gameboy.memory_bus.borrow_mut();
+            // Tile 0 is entirely color 3, and fills the background
+            memory_bus.write(0x8000, &[0xff; 16]).unwrap();
+            memory_bus.write_u8(0xff47, 0xe4).unwrap();
+            memory_bus.write_u8(0xff40, 0x91).unwrap();
+        }
+        while gameboy.ppu.borrow().ly() < 50 {
+            gameboy.tick();
+        }
+        let state = |gameboy: &GameBoyState| {
+            let ppu = gameboy.ppu.borrow();
+            (ppu.ly(), ppu.mode(), gameboy.get_pc())
+        };
+        let before = state(&gameboy);
+        // Lines below the one being drawn are still blank
+        assert_eq!(TileColor::White, gameboy.screen()[100 * 160]);
+
+        let screen = gameboy.force_render();
+        assert_eq!(160 * 144, screen.len());
+        assert!(screen.iter().all(|color| *color == TileColor::Black));
+        assert_eq!(screen, gameboy.screen());
+        assert_eq!(before, state(&gameboy));
+        assert_eq!(0, gameboy.frame_count());
+    }
+
+    #[test]
+    fn test_force_render_is_blank_with_the_lcd_off_and_hides_sprites_during_dma() {
+        let mut gameboy = GameBoyState::new_raw(&[0x18, 0xfe], 0xc000, 0xc000).unwrap();
+        {
+            let mut memory_bus = gameboy.memory_bus.borrow_mut();
+            // Tile 1 is entirely color 3, drawn by a sprite in the top left corner
+            memory_bus.write(0x8010, &[0xff; 16]).unwrap();
+            memory_bus.write(0xfe00, &[16, 8, 1, 0]).unwrap();
+            memory_bus.write_u8(0xff47, 0xe4).unwrap();
+            memory_bus.write_u8(0xff48, 0xe4).unwrap();
+            // LCD off, with 0x8000 tile data, sprites and background enabled
+            memory_bus.write_u8(0xff40, 0x13).unwrap();
+        }
+        let screen = gameboy.force_render();
+        assert!(screen.iter().all(|color| *color == TileColor::White));
+
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write_u8(0xff40, 0x93)
+            .unwrap();
+        assert_eq!(TileColor::Black, gameboy.force_render()[0]);
+
+        {
+            let mut memory_bus = gameboy.memory_bus.borrow_mut();
+            memory_bus.write_u8(0xff46, 0xc1).unwrap();
+            memory_bus.instruction_finished();
+            memory_bus.step_dma(1).unwrap();
+        }
+        assert_eq!(TileColor::White, gameboy.force_render()[0]);
+    }
+
+    #[test]
+    fn test_export_json_reports_registers() {
+        let code = [
+            0x3e, 0x42, // LD A,0x42
+            0xe0, 0x06, // LDH (TMA),A
+            0x06, 0x13, // LD B,0x13
+            0x18, 0xfe, // JR -2
+        ];
+        let mut gameboy = GameBoyState::new_raw(&code, 0xc000, 0xc000).unwrap();
+        for _ in 0..3 {
+            gameboy.tick();
+        }
+
+        let json = gameboy.export_json();
+        assert!(
+            json.starts_with("{\n  \"cpu\": {\n    \"a\": 66,\n"),
+            "{}",
+            json
+        );
+        assert!(json.contains("\"b\": 19,"), "{}", json);
+        assert!(json.contains("\"pc\": 49158,"), "{}", json);
+        assert!(json.contains("\"tma\": 66,"), "{}", json);
+        assert!(json.ends_with("\"frame_count\": 0\n  }\n}"), "{}", json);
+    }
+
+    #[test]
+    fn test_debug_pixels_are_hidden_by_default() {
+        let (sender, _) = mpsc::channel();
+        let engine = DebugEngine(vec![TileColor::Debug; 160 * 144]);
+        let ppu = Rc::new(RefCell::new(BasePpu::new(Box::new(engine))));
+        let mut gameboy = GameBoyState::new(ppu, sender);
+
+        assert!(gameboy
+            .screen()
+            .iter()
+            .all(|color| *color == TileColor::White));
+
+        gameboy.set_debug_pixels(true);
+        assert!(gameboy
+            .screen()
+            .iter()
+            .all(|color| *color == TileColor::Debug));
+    }
+
+    #[test]
+    fn test_hold_input_releases_after_frames() {
+        let mut gameboy = looping_gameboy();
+        // Select the direction buttons
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write_u8(0xff00, 0x20)
+            .unwrap();
+        let down_pressed = |gameboy: &GameBoyState| {
+            gameboy.memory_bus.borrow_mut().read_u8(0xff00).unwrap() & 0b1000 == 0
+        };
+
+        gameboy.hold_input(JoypadInput::Down, 5).unwrap();
+        assert!(down_pressed(&gameboy));
+        for frame in 1..5 {
+            gameboy.run_to_frame(frame);
+            assert!(down_pressed(&gameboy), "released early at frame {}", frame);
+        }
+        gameboy.run_to_frame(5);
+        assert!(!down_pressed(&gameboy));
+    }
+
+    #[test]
+    fn test_stop_standby_freezes_ppu_until_button_press() {
+        let code = [
+            0x3e, 0x91, 0xe0, 0x40, // LD A,0x91; LDH (LCDC),A
+            0x3e, 0x10, 0xe0, 0x00, // LD A,0x10; LDH (P1),A -- select the action buttons
+            0x10, 0x00, // STOP
+            0x18, 0xfe, // JR -2
+        ];
+        let mut gameboy = GameBoyState::new_raw(&code, 0xc000, 0xc000).unwrap();
+        while !gameboy.in_standby() {
+            gameboy.tick();
+        }
+        let ly = gameboy.ppu.borrow().ly();
+        for _ in 0..1000 {
+            gameboy.tick();
+        }
+        assert!(gameboy.in_standby());
+        // STOP resets DIV, which then stays frozen
+        assert_eq!(0, gameboy.timer.borrow().div());
+        assert_eq!(ly, gameboy.ppu.borrow().ly());
+        assert!(gameboy
+            .screen()
+            .iter()
+            .all(|color| *color == TileColor::White));
+
+        gameboy.joypad.borrow_mut().key_pressed(JoypadInput::A);
+        for _ in 0..1000 {
+            gameboy.tick();
+        }
+        assert!(!gameboy.in_standby());
+        assert_eq!(0xc00a, gameboy.get_pc());
+        assert_ne!(ly, gameboy.ppu.borrow().ly());
+    }
+
+    #[test]
+    fn test_rtc_keeps_counting_in_stop_standby() {
+        // MBC3+TIMER+RAM+BATTERY
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x10;
+        rom[0x149] = 0x03;
+        let code = [
+            0x3e, 0x10, 0xe0, 0x00, // LD A,0x10; LDH (P1),A -- select the action buttons
+            0x10, 0x00, // STOP
+            0x18, 0xfe, // JR -2
+        ];
+        rom[0x100..0x100 + code.len()].copy_from_slice(&code);
+        let (mut gameboy, _) = GameBoyState::new_headless();
+        gameboy
+            .load_cartridge(Cartridge::cartridge_from_data(&rom).unwrap())
+            .unwrap();
+        while !gameboy.in_standby() {
+            gameboy.tick();
+        }
+
+        let mut elapsed = 0;
+        while elapsed < 4_194_304 {
+            elapsed += gameboy.tick();
+        }
+        assert!(gameboy.in_standby());
+
+        // Latch the clock and read its seconds register
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+        memory_bus.write_u8(0x0000, 0x0a).unwrap();
+        memory_bus.write_u8(0x4000, 0x08).unwrap();
+        memory_bus.write_u8(0x6000, 0x00).unwrap();
+        memory_bus.write_u8(0x6000, 0x01).unwrap();
+        assert_eq!(1, memory_bus.read_u8(0xa000).unwrap());
+    }
+
+    #[test]
+    fn test_step_instruction_runs_one_instruction_at_a_time() {
+        // NOP; LD A,0x42; LD HL,0x1234; SWAP A; JR -2
+        let code = [0x00, 0x3e, 0x42, 0x21, 0x34, 0x12, 0xcb, 0x37, 0x18, 0xfe];
+        let mut gameboy = GameBoyState::new_raw(&code, 0xc000, 0xc000).unwrap();
+
+        let steps: Vec<(u16, u8)> = (0..5)
+            .map(|_| {
+                let t_cycles = gameboy.step_instruction().unwrap();
+                (gameboy.get_pc(), t_cycles)
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (0xc001, 4),
+                (0xc003, 8),
+                (0xc006, 12),
+                (0xc008, 8),
+                (0xc008, 12)
+            ],
+            steps
+        );
+
+        let snapshot = gameboy.cpu_snapshot();
+        assert_eq!((0x24, 0x12, 0x34), (snapshot.a, snapshot.h, snapshot.l));
+        assert!(!snapshot.ime);
+    }
+
+    #[test]
+    fn test_scanline_dot_counts_dots_within_line() {
+        let gameboy = GameBoyState::new_raw(&[0x18, 0xfe], 0xc000, 0xc000).unwrap();
+        let step_ppu = |dots| {
+            for _ in 0..dots {
+                gameboy.ppu.borrow_mut().step(&gameboy).unwrap();
+            }
+        };
+        gameboy.ppu.borrow_mut().write_u8(0xff40, 0x91).unwrap();
+
+        step_ppu(100);
+        assert_eq!(100, gameboy.scanline_dot());
+        // The count starts again on the next line
+        step_ppu(456 + 200);
+        assert_eq!(1, gameboy.ppu.borrow().ly());
+        assert_eq!(300, gameboy.scanline_dot());
+    }
+
+    #[test]
+    fn test_ly_wait_skipping_keeps_timing_of_vblank_wait() {
+        // LDH A,(LY); CP 0x90; JR NZ,-6; JR -2
+        let code = [0xf0, 0x44, 0xfe, 0x90, 0x20, 0xfa, 0x18, 0xfe];
+        let run = |skipping: bool| {
+            let mut gameboy = GameBoyState::new_raw(&code, 0xc000, 0xc000).unwrap();
+            gameboy.set_ly_wait_skipping(skipping);
+            assert!(gameboy.waiting_for_ly());
+            let (mut ticks, mut cycles) = (0, 0);
+            while gameboy.get_pc() != 0xc006 {
+                cycles += gameboy.tick();
+                ticks += 1;
+            }
+            assert!(!gameboy.waiting_for_ly());
+            let position = (gameboy.ppu.borrow().ly(), gameboy.scanline_dot());
+            (ticks, cycles, position, gameboy.cpu_log_line())
+        };
+
+        let (ticks, cycles, position, log_line) = run(false);
+        let (skipped_ticks, skipped_cycles, skipped_position, skipped_log_line) = run(true);
+        assert_eq!(0x90, position.0);
+        assert_eq!((cycles, position), (skipped_cycles, skipped_position));
+        assert_eq!(log_line, skipped_log_line);
+        assert!(skipped_ticks * 10 < ticks);
+    }
+
+    #[test]
+    fn test_idle_cycles_after_switching_to_a_faster_timer_rate() {
+        // HALT; JR -3
+        let mut gameboy = GameBoyState::new_raw(&[0x76, 0x18, 0xfd], 0xc000, 0xc000).unwrap();
+        gameboy.set_scheduler_enabled(true);
+        {
+            // Count most of the way to an increment at the slowest rate, then switch to the
+            // fastest
+            let mut timer = gameboy.timer.borrow_mut();
+            timer.write_u8(0xff07, 0b100).unwrap();
+            for _ in 0..1000 {
+                timer.step(&gameboy).unwrap();
+            }
+            timer.write_u8(0xff07, 0b101).unwrap();
+        }
+        gameboy.cpu.borrow_mut().halted = true;
+
+        // TIMA overflows within 256 increments at the new rate of one per 16 T-cycles
+        let idle = gameboy.idle_cycles();
+        assert!((1..=256 * 16 / 4).contains(&idle), "idle for {}", idle);
+        gameboy.tick();
+    }
+
+    #[test]
+    fn test_hblank_dma_copies_one_block_per_hblank() {
+        let mut gameboy = GameBoyState::new_raw(&[0x18, 0xfe], 0xc000, 0xc000).unwrap();
+        {
+            let mut memory_bus = gameboy.memory_bus.borrow_mut();
+            memory_bus.set_cgb_mode(true);
+            let source: Vec<u8> = (1..=0x80).collect();
+            memory_bus.write(0xc100, &source).unwrap();
+            // Copy 4 blocks from 0xc100 to 0x8000, one per HBlank, with the LCD on
+            memory_bus.write(0xff51, &[0xc1, 0x00, 0x00, 0x00]).unwrap();
+            memory_bus.write_u8(0xff40, 0x80).unwrap();
+            memory_bus.write_u8(0xff55, 0x83).unwrap();
+        }
+        let copied_bytes = |gameboy: &GameBoyState| {
+            let mut vram = vec![0; 0x80];
+            gameboy.ppu.borrow_mut().read(0x8000, &mut vram).unwrap();
+            vram.iter().filter(|byte| **byte != 0).count()
+        };
+        let run_until = |gameboy: &mut GameBoyState, ly: u8, mode: u8| {
+            while gameboy.ppu.borrow().ly() != ly || gameboy.ppu.borrow().mode() != mode {
+                gameboy.tick();
+            }
+        };
+
+        for line in 0..4 {
+            run_until(&mut gameboy, line, 3);
+            assert_eq!(16 * usize::from(line), copied_bytes(&gameboy));
+            run_until(&mut gameboy, line, 0);
+            assert_eq!(16 * usize::from(line + 1), copied_bytes(&gameboy));
+        }
+        assert_eq!(
+            0xff,
+            gameboy.memory_bus.borrow_mut().read_u8(0xff55).unwrap()
+        );
+
+        // Nothing more is copied once the 4 blocks are done
+        run_until(&mut gameboy, 6, 0);
+        assert_eq!(64, copied_bytes(&gameboy));
+    }
+
+    #[test]
+    fn test_oam_dma_started_by_cpu_copies_160_bytes_over_160_m_cycles() {
+        // LD A,0xc1; LDH (0x46),A; JR -2
+        let code = [0x3e, 0xc1, 0xe0, 0x46, 0x18, 0xfe];
+        let mut gameboy = GameBoyState::new_raw(&code, 0xc000, 0xc000).unwrap();
+        let source: Vec<u8> = (0..160).collect();
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write(0xc100, &source)
+            .unwrap();
+
+        gameboy.tick();
+        gameboy.tick();
+        // LDH writes in its last M-cycle, and the copy starts an M-cycle later
+        assert!(!gameboy.memory_bus.borrow().dma_active());
+        let mut m_cycles = gameboy.tick() / 4;
+        assert!(gameboy.memory_bus.borrow().dma_active());
+        while gameboy.memory_bus.borrow().dma_active() {
+            m_cycles += gameboy.tick() / 4;
+        }
+        // The copy then ends during one of the JRs
+        assert!((161..164).contains(&m_cycles));
+        assert_eq!(source[..], *gameboy.oam());
+    }
+
+    #[test]
+    fn test_dma_access_block_leaves_interrupt_registers_readable() {
+        // Running from HRAM: EI; LD A,0xc0; LDH (DMA),A; then JR -2
+        let code = [0xfb, 0x3e, 0xc0, 0xe0, 0x46, 0x18, 0xfe];
+        let mut gameboy = GameBoyState::new_raw(&code, 0xff80, 0xff80).unwrap();
+        gameboy.set_sp(0xfffe);
+        gameboy.memory_bus.borrow_mut().set_dma_access_block(true);
+        // No interrupts are enabled, so none may be serviced
+        gameboy.memory_bus.borrow_mut().write_u8(0xffff, 0).unwrap();
+
+        for _ in 0..200 {
+            gameboy.tick();
+        }
+        assert_eq!(0xff85, gameboy.get_pc());
+        assert_eq!(0xfffe, gameboy.cpu_snapshot().sp);
+    }
+
+    #[test]
+    fn test_oam_dma_hides_sprites_from_oam_search() {
+        let gameboy = GameBoyState::new_raw(&[0x18, 0xfe], 0xc000, 0xc000).unwrap();
+        // Steps OAM DMA and the PPU through one M-cycle, the way `tick` does
+        let step_m_cycle = || {
+            gameboy.memory_bus.borrow_mut().step_dma(1).unwrap();
+            for _ in 0..4 {
+                gameboy.ppu.borrow_mut().step(&gameboy).unwrap();
+            }
+        };
+
+        let oam: Vec<u8> = (0..0xa0)
+            .map(|i| if i < 4 { [26, 8, 1, 0][i] } else { 0 })
+            .collect();
+        {
+            let mut memory_bus = gameboy.memory_bus.borrow_mut();
+            // Tile 1 is entirely color 3
+            for row in 0..8 {
+                memory_bus.write(0x8010 + row * 2, &[0xff, 0xff]).unwrap();
+            }
+            // A sprite covering lines 10-17 in the top left corner, waiting to be copied to OAM
+            memory_bus.write(0xc100, &oam).unwrap();
+            memory_bus.write_u8(0xff47, 0xe4).unwrap();
+            memory_bus.write_u8(0xff48, 0xe4).unwrap();
+            // LCD on, 0x8000 tile data, sprites and background enabled
+            memory_bus.write_u8(0xff40, 0x93).unwrap();
+        }
+
+        // Start OAM DMA as line 10's OAM search begins
+        while gameboy.ppu.borrow().ly() != 10 {
+            step_m_cycle();
+        }
+        {
+            let mut memory_bus = gameboy.memory_bus.borrow_mut();
+            memory_bus.write_u8(0xff46, 0xc1).unwrap();
+            memory_bus.instruction_finished();
+        }
+        step_m_cycle();
+        assert_eq!(
+            0xff,
+            gameboy.memory_bus.borrow_mut().read_u8(0xfe00).unwrap()
+        );
+
+        while gameboy.ppu.borrow().frame_count() == 0 {
+            step_m_cycle();
+        }
+
+        // The 160 M-cycle transfer covers the OAM searches of lines 10 and 11, but not line 12
+        let screen = gameboy.screen();
+        assert_eq!(TileColor::White, screen[10 * 160]);
+        assert_eq!(TileColor::White, screen[11 * 160]);
+        assert_eq!(TileColor::Black, screen[12 * 160]);
+
+        let mut copied = vec![0; 0xa0];
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .read(0xfe00, &mut copied)
+            .unwrap();
+        assert_eq!(oam, copied);
+    }
+
+    /// A writer whose output can still be inspected after it is handed to the gameboy.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_cpu_log_uses_gameboy_doctor_format() {
+        // LD A,0x42; LD B,A; NOP
+        let code = [0x3e, 0x42, 0x47, 0x00, 0x00];
+        let mut gameboy = GameBoyState::new_raw(&code, 0xc000, 0xc000).unwrap();
+        gameboy.set_sp(0xdfff);
+
+        let buffer = SharedBuffer::default();
+        gameboy.set_cpu_log(Some(Box::new(buffer.clone())));
+        for _ in 0..3 {
+            gameboy.tick();
+        }
+
+        // Registers start with the values left by the boot ROM
+        let log = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(
+            vec![
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:DFFF PC:C000 PCMEM:3E,42,47,00",
+                "A:42 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:DFFF PC:C002 PCMEM:47,00,00,00",
+                "A:42 F:B0 B:42 C:13 D:00 E:D8 H:01 L:4D SP:DFFF PC:C003 PCMEM:00,00,00,00",
+            ],
+            lines
+        );
+    }
+}
@@ -1,8 +1,10 @@
 use crate::component::{Address, Addressable};
 use crate::error::{Error, Result};
-use strum_macros::EnumIter;
+use serde::{Deserialize, Serialize};
+use strum_macros::{EnumIter, EnumString};
 
-#[derive(Debug, Clone, Copy, EnumIter)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter, EnumString, Serialize, Deserialize)]
+#[strum(ascii_case_insensitive)]
 pub enum JoypadInput {
     A,
     B,
@@ -14,7 +16,7 @@ pub enum JoypadInput {
     Right,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Joypad {
     /// Only bits 5 and 6 are used
     state_byte: u8,
@@ -85,15 +87,16 @@ impl Joypad {
         }
     }
 
-    /// Use keyboard input to get the byte at 0xff00.
-    fn get_state(&mut self) -> u8 {
-        let mut input_nibble = 0u8;
+    /// Use keyboard input to get the byte at 0xff00. Pressed buttons read as 0, so selecting both
+    /// groups ANDs them together and selecting neither reads as nothing pressed.
+    pub fn read_register(&self) -> u8 {
+        let mut input_nibble = 0xf;
 
         if self.select_action() {
-            input_nibble |= self.action_nibble;
+            input_nibble &= self.action_nibble;
         }
         if self.select_direction() {
-            input_nibble |= self.direction_nibble;
+            input_nibble &= self.direction_nibble;
         }
 
         // Mask out everything but the select bits and add the inputs
@@ -114,7 +117,7 @@ impl Addressable for Joypad {
         if data.len() != 1 || address != 0xff00 {
             return Err(Error::new("invalid address"));
         }
-        data[0] = self.get_state();
+        data[0] = self.read_register();
         Ok(())
     }
 
@@ -126,3 +129,24 @@ impl Addressable for Joypad {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_register_with_both_or_neither_group_selected() {
+        let mut joypad = Joypad::new();
+        joypad.key_pressed(JoypadInput::A);
+        joypad.key_pressed(JoypadInput::Start);
+        joypad.key_pressed(JoypadInput::Left);
+
+        // Both groups selected: a press in either group reads as pressed
+        joypad.write_u8(0xff00, 0x00).unwrap();
+        assert_eq!(0b0100, joypad.read_register() & 0xf);
+
+        // Neither group selected
+        joypad.write_u8(0xff00, 0x30).unwrap();
+        assert_eq!(0xf, joypad.read_register() & 0xf);
+    }
+}
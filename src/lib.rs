@@ -1,6 +1,7 @@
 mod component;
 mod error;
 
+mod apu;
 mod bit_field;
 pub mod cartridge;
 pub mod cpu;
@@ -13,8 +14,18 @@ mod register;
 mod timer;
 mod utils;
 
+pub use apu::Apu;
+pub use component::{Address, Addressable};
 pub use error::{Error, Result};
 pub use joypad::Joypad;
+pub use joypad::JoypadInput;
 pub use memory::MemoryBus;
-pub use ppu::CanvasPpu;
+pub use ppu::BasePpu;
+pub use ppu::CanvasEngine;
+pub use ppu::GraphicsEngine;
+pub use ppu::Layer;
+pub use ppu::NoGuiEngine;
+pub use ppu::OamData;
+pub use ppu::Palette;
 pub use ppu::Ppu;
+pub use ppu::TileColor;
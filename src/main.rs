@@ -1,6 +1,8 @@
-use gameboy_emulator::cartridge::Cartridge;
-use gameboy_emulator::emulator::GameboyEmulator;
+use gameboy_emulator::cartridge::{self, Cartridge};
+use gameboy_emulator::emulator::{GameboyEmulator, GuiOptions};
+use gameboy_emulator::{JoypadInput, Palette};
 use std::fs;
+use std::path::PathBuf;
 
 use clap::Parser;
 
@@ -12,9 +14,62 @@ struct Args {
     #[arg(short = 'r', long = "rom", required = true)]
     rom_path: String,
 
+    /// Print the ROM's header information and exit without running it
+    #[arg(long = "info", default_value_t = false)]
+    info: bool,
+
     /// Debug mode
     #[arg(short, long, default_value_t = false)]
     debug: bool,
+
+    /// Blend each frame with the previous one to imitate the DMG's slow LCD, from 0 (off) to 1
+    #[arg(long = "lcd-ghosting", default_value_t = 0.0)]
+    lcd_ghosting: f32,
+
+    /// How far a controller's analog stick must be pushed before it presses a direction, from 0 to 1
+    #[arg(long = "stick-deadzone", default_value_t = 0.25)]
+    stick_deadzone: f32,
+
+    /// Log the CPU state before every instruction to this file, in Gameboy Doctor's format
+    #[arg(long = "cpu-log")]
+    cpu_log: Option<PathBuf>,
+
+    /// Print a histogram of how long each frame took on exit
+    #[arg(long = "frame-histogram", default_value_t = false)]
+    frame_histogram: bool,
+
+    /// Integer factor to scale the window up by
+    #[arg(long = "scale", default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    scale: u32,
+
+    /// Buttons which auto-fire while held, e.g. "a,b". F2 switches auto-fire on and off
+    #[arg(long = "auto-fire", value_delimiter = ',')]
+    auto_fire: Vec<JoypadInput>,
+
+    /// Presses per second made by auto-fire buttons
+    #[arg(long = "auto-fire-rate", default_value_t = 15.0)]
+    auto_fire_rate: f32,
+
+    /// Multiplies the brightness of the screen's colors
+    #[arg(long = "brightness", default_value_t = 1.0)]
+    brightness: f32,
+
+    /// Gamma applied to the screen's colors, where values above 1 brighten the midtones
+    #[arg(long = "gamma", default_value_t = 1.0)]
+    gamma: f32,
+
+    /// Play audio and use it to pace emulation, which avoids audio dropouts but shows frames less
+    /// evenly
+    #[arg(long = "sync-to-audio", default_value_t = false)]
+    sync_to_audio: bool,
+
+    /// The most frames shown per second, to stop emulation running flat out, e.g. 300
+    #[arg(long = "max-fps")]
+    max_fps: Option<f32>,
+
+    /// Colors to show the screen in: "grey", "dmg" or "high-contrast"
+    #[arg(long = "palette", default_value = "grey")]
+    palette: Palette,
 }
 
 fn main() -> Result<(), ()> {
@@ -22,10 +77,45 @@ fn main() -> Result<(), ()> {
 
     let args = Args::parse();
 
-    let bytes = fs::read(args.rom_path).expect("could not read file");
-    let cartridge = Cartridge::cartridge_from_data(&bytes).expect("failed to build cartridge");
+    let bytes = fs::read(&args.rom_path).expect("could not read file");
+    if args.info {
+        return match cartridge::describe_header(&bytes) {
+            Ok(info) => {
+                println!("{}", info);
+                Ok(())
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                Err(())
+            }
+        };
+    }
+    let mut cartridge = Cartridge::cartridge_from_data(&bytes).expect("failed to build cartridge");
+
+    // Battery-backed RAM and clocks are kept in a .sav file next to the ROM
+    let save_path = cartridge
+        .has_battery_save()
+        .then(|| PathBuf::from(&args.rom_path).with_extension("sav"));
+    if let Some(save) = save_path.as_ref().and_then(|path| fs::read(path).ok()) {
+        cartridge.load_ram(&save);
+    }
 
-    GameboyEmulator::run(cartridge, args.debug).expect("error during running");
+    let options = GuiOptions {
+        lcd_ghosting: args.lcd_ghosting,
+        stick_deadzone: args.stick_deadzone,
+        cpu_log: args.cpu_log,
+        frame_histogram: args.frame_histogram,
+        scale: args.scale,
+        auto_fire: args.auto_fire,
+        auto_fire_rate: args.auto_fire_rate,
+        brightness: args.brightness,
+        gamma: args.gamma,
+        sync_to_audio: args.sync_to_audio,
+        max_fps: args.max_fps,
+        save_path,
+        palette: args.palette,
+    };
+    GameboyEmulator::run(cartridge, args.debug, options).expect("error during running");
 
     Ok(())
 }
@@ -3,18 +3,65 @@
  * This structure makes it easy to delegate reads/writes to the corresponding memory-mapped component.
  */
 use std::cell::RefCell;
+use std::ops::RangeInclusive;
 use std::rc::Rc;
 use std::sync::mpsc::Sender;
 
+use crate::apu::Apu;
 use crate::cartridge::Cartridge;
 use crate::component::{Address, Addressable};
 use crate::emulator::events::EmulationEvent;
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::gameboy::Interrupt;
 use crate::joypad::Joypad;
 use crate::ppu::Ppu;
 use crate::timer::Timer;
-use log::debug;
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+
+/// Bits of each IO register (0xff00-0xff7f) which always read as 1, either because they are
+/// unused or because they are write-only.
+#[rustfmt::skip]
+pub(crate) const IO_READ_MASKS: [u8; 0x80] = [
+    // P1    SB    SC    --    DIV   TIMA  TMA   TAC
+    0xc0, 0x00, 0x7e, 0xff, 0x00, 0x00, 0x00, 0xf8,
+    // --    --    --    --    --    --    --    IF
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xe0,
+    // NR10  NR11  NR12  NR13  NR14  --    NR21  NR22
+    0x80, 0x3f, 0x00, 0xff, 0xbf, 0xff, 0x3f, 0x00,
+    // NR23  NR24  NR30  NR31  NR32  NR33  NR34  --
+    0xff, 0xbf, 0x7f, 0xff, 0x9f, 0xff, 0xbf, 0xff,
+    // NR41  NR42  NR43  NR44  NR50  NR51  NR52  --
+    0xff, 0x00, 0x00, 0xbf, 0x00, 0x00, 0x70, 0xff,
+    // --    --    --    --    --    --    --    --
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    // Wave RAM
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // LCDC  STAT  SCY   SCX   LY    LYC   DMA   BGP
+    0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // OBP0  OBP1  WY    WX    --    --    --    VBK
+    0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xfe,
+    // The remaining registers are only used by the CGB, except for the boot ROM disable
+    // BANK  HDMA1 HDMA2 HDMA3 HDMA4 HDMA5 --    --
+    0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    // BCPS  BCPD  OCPS  OCPD  OPRI  --    --    --
+    0x40, 0x00, 0x40, 0x00, 0xfe, 0xff, 0xff, 0xff,
+    // SVBK  --    --    --    --    --    --    --
+    0xf8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+
+/// True iff nothing is mapped at an IO address on either model, so it reads as 0xff and ignores
+/// writes. Unemulated CGB registers aren't included.
+fn unmapped_io(address: Address) -> bool {
+    matches!(
+        address,
+        0xff03 | 0xff08..=0xff0e | 0xff4c | 0xff4e | 0xff57..=0xff67 | 0xff6d..=0xff6f | 0xff71..=0xff7f
+    )
+}
 
 /// Mock memory bus
 pub struct MemoryBus {
@@ -22,9 +69,76 @@ pub struct MemoryBus {
     ppu: Rc<RefCell<dyn Ppu>>,
     joypad: Rc<RefCell<Joypad>>,
     timer: Rc<RefCell<Timer>>,
+    apu: Rc<RefCell<Apu>>,
     pub data: [u8; 0x10000],
     pub serial_port_data: Vec<u8>,
-    emulation_event_sender: Sender<EmulationEvent>
+    emulation_event_sender: Sender<EmulationEvent>,
+    /// Number of VRAM/OAM writes attempted while the PPU blocks access to them. Only counted
+    /// when blocked access logging is enabled.
+    blocked_access_count: Option<u64>,
+    /// M-cycles left in the current OAM DMA transfer
+    dma_cycles_remaining: u8,
+    /// Start of the 160 bytes being copied by the current OAM DMA transfer
+    dma_source: Address,
+    /// Source of an OAM DMA transfer requested during the current instruction
+    dma_requested: Option<Address>,
+    /// Source of an OAM DMA transfer which starts after the next M-cycle
+    dma_starting: Option<Address>,
+    /// Reads and writes made so far during the current instruction, each taking an M-cycle
+    instruction_accesses: u8,
+    /// If true, reads outside of HRAM return 0xff while an OAM DMA is in progress
+    dma_access_block: bool,
+    /// Boot ROM mapped over the start of the cartridge, if one was loaded
+    boot_rom: Option<Vec<u8>>,
+    /// Set by the first nonzero write to 0xff50, which unmaps the boot ROM until reset
+    boot_rom_disabled: bool,
+    /// Addresses whose writes are recorded in `watchpoint_hits`
+    write_watchpoints: Vec<Address>,
+    watchpoint_hits: Vec<WatchpointHit>,
+    /// True when running as a CGB, which banks work RAM
+    cgb_mode: bool,
+    /// SVBK: Selects which work RAM bank (1-7) is mapped at 0xd000-0xdfff in CGB mode
+    svbk: u8,
+    /// Work RAM banks 1-7 for CGB mode. A DMG only has bank 1, which is kept in `data`.
+    banked_wram: Vec<u8>,
+    /// HDMA1-HDMA4: Where the next block of a CGB VRAM DMA is copied from and to
+    hdma_source: Address,
+    hdma_destination: Address,
+    /// HDMA5: 16 byte blocks left to copy, minus 1. Only the low 7 bits are used.
+    hdma_length: u8,
+    /// True while an HBlank DMA is copying a block every HBlank
+    hblank_dma_active: bool,
+    /// Custom devices mapped over the usual components by `map_peripheral`
+    peripherals: Vec<(RangeInclusive<Address>, Box<dyn Addressable>)>,
+}
+
+/// A write to a watched address, see `MemoryBus::add_write_watchpoint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchpointHit {
+    pub address: Address,
+    pub old_value: u8,
+    pub new_value: u8,
+}
+
+/// The parts of the memory bus kept in a save state. Connected components are saved on their
+/// own, and debugging settings aren't saved at all.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct MemoryBusState {
+    data: Vec<u8>,
+    dma_cycles_remaining: u8,
+    dma_source: Address,
+    dma_requested: Option<Address>,
+    dma_starting: Option<Address>,
+    boot_rom_disabled: bool,
+    cgb_mode: bool,
+    svbk: u8,
+    banked_wram: Vec<u8>,
+    hdma_source: Address,
+    hdma_destination: Address,
+    hdma_length: u8,
+    hblank_dma_active: bool,
+    /// See `Cartridge::save_state`
+    cartridge: Option<Vec<u8>>,
 }
 
 impl MemoryBus {
@@ -32,6 +146,7 @@ impl MemoryBus {
         ppu: Rc<RefCell<dyn Ppu>>,
         joypad: Rc<RefCell<Joypad>>,
         timer: Rc<RefCell<Timer>>,
+        apu: Rc<RefCell<Apu>>,
         emulation_event_sender: Sender<EmulationEvent>
     ) -> Self {
         let memory_bus = Self {
@@ -39,9 +154,29 @@ impl MemoryBus {
             ppu,
             joypad,
             timer,
+            apu,
             data: [0; 0x10000],
             serial_port_data: Vec::new(),
             emulation_event_sender,
+            blocked_access_count: None,
+            dma_cycles_remaining: 0,
+            dma_source: 0,
+            dma_requested: None,
+            dma_starting: None,
+            instruction_accesses: 0,
+            dma_access_block: false,
+            boot_rom: None,
+            boot_rom_disabled: false,
+            write_watchpoints: Vec::new(),
+            watchpoint_hits: Vec::new(),
+            cgb_mode: false,
+            svbk: 0,
+            banked_wram: vec![0; 7 * 0x1000],
+            hdma_source: 0,
+            hdma_destination: 0,
+            hdma_length: 0x7f,
+            hblank_dma_active: false,
+            peripherals: Vec::new(),
         };
 
         memory_bus
@@ -52,31 +187,86 @@ impl MemoryBus {
             println!("Reading correct tile");
         }
 
-        match address {
+        // During OAM DMA the CPU can't reach the external and internal buses the transfer uses,
+        // leaving the IO registers, HRAM and IE
+        let dma_blocking = self.dma_blocking();
+        self.instruction_accesses = self.instruction_accesses.saturating_add(1);
+        if self.dma_access_block && dma_blocking && address < 0xff00 {
+            return Ok(0xff);
+        }
+        // OAM is busy with the transfer itself
+        if dma_blocking && (0xfe00..=0xfe9f).contains(&address) {
+            return Ok(0xff);
+        }
+
+        self.bus_read(address)
+    }
+
+    /// Reads a byte the way a debugger would see it, ignoring any OAM DMA which blocks the CPU.
+    pub fn peek(&mut self, address: Address) -> Result<u8> {
+        self.bus_read(address)
+    }
+
+    /// Reads from whichever component is mapped at the address, ignoring OAM DMA.
+    fn bus_read(&mut self, address: Address) -> Result<u8> {
+        if let Some(device) = self.peripheral(address) {
+            return device.read_u8(address);
+        }
+
+        let value = match address {
+            0..=0xff if self.boot_rom_mapped() => {
+                let boot_rom = self.boot_rom.as_ref().expect("No boot ROM loaded");
+                Ok(boot_rom[address])
+            }
             0..=0x7fff => {
                 let cartridge = self.cartridge.as_ref().expect("No cartridge inserted");
                 let value = cartridge.read(address).expect("Error reading cartridge");
                 Ok(value)
             }
+            // External RAM, which is plain memory when running code without a cartridge
+            0xa000..=0xbfff if self.cartridge.is_some() => {
+                let cartridge = self.cartridge.as_ref().expect("No cartridge inserted");
+                Ok(cartridge.read(address).expect("Error reading cartridge"))
+            }
             0x8000..=0x97ff => self.ppu.borrow_mut().read_u8(address),
-            0x9800..=0x9bff => self.ppu.borrow_mut().read_u8(address),
+            0x9800..=0x9fff => self.ppu.borrow_mut().read_u8(address),
             // OAM
             0xfe00..=0xfe9f => self.ppu.borrow_mut().read_u8(address),
             // Joypad
             0xff00 => self.joypad.borrow_mut().read_u8(address),
             // Timer
             0xff04..=0xff07 => self.timer.borrow_mut().read_u8(address),
-            // IF register always has top 3 bits high
-            0xff0f => Ok(self.data[address] | 0xe0),
-            // LCD Control register (LCDC)
-            0xff40 => self.ppu.borrow_mut().read_u8(address),
-            0xff44 => self.ppu.borrow_mut().read_u8(address),
-            0xff4d => Ok(0xff),
+            // Sound registers and wave RAM
+            0xff10..=0xff3f => self.apu.borrow_mut().read_u8(address),
+            // LCD Control and STAT registers
+            0xff40..=0xff41 => self.ppu.borrow_mut().read_u8(address),
+            // Scroll, LY, LYC, palette and window registers
+            0xff42..=0xff45 | 0xff47..=0xff4b => self.ppu.borrow_mut().read_u8(address),
+            // VRAM bank select (VBK)
+            0xff4f => self.ppu.borrow_mut().read_u8(address),
+            // CGB palettes and object priority mode (OPRI)
+            0xff68..=0xff6c => self.ppu.borrow_mut().read_u8(address),
+            0xd000..=0xdfff if self.cgb_mode => Ok(self.banked_wram[self.wram_offset(address)]),
+            0xff55 if self.cgb_mode => Ok(self.read_hdma5()),
+            0xff55 => Ok(0xff),
+            address if unmapped_io(address) => {
+                trace!("Read from unmapped IO register {:#06x}", address);
+                Ok(0xff)
+            }
+            0xff70 if self.cgb_mode => Ok(self.svbk),
+            0xff70 => Ok(0xff),
             _ => Ok(self.data[address]),
+        }?;
+
+        match address {
+            0xff00..=0xff7f => Ok(value | IO_READ_MASKS[address - 0xff00]),
+            _ => Ok(value),
         }
     }
 
     fn _write(&mut self, address: Address, value: u8) -> Result<()> {
+        let dma_blocking = self.dma_blocking();
+        self.instruction_accesses = self.instruction_accesses.saturating_add(1);
         if address == 0xFF02 && value == 0x81 {
             self.serial_port_data.push(self.data[0xFF01]);
         }
@@ -85,6 +275,31 @@ impl MemoryBus {
             self.emulation_event(EmulationEvent::MemoryWrite { address: address, value: value });
         }
 
+        if self.write_watchpoints.contains(&address) {
+            let old_value = self.bus_read(address)?;
+            self.watchpoint_hits.push(WatchpointHit {
+                address,
+                old_value,
+                new_value: value,
+            });
+        }
+
+        if let Some(count) = self.blocked_access_count.as_mut() {
+            let blocked = match address {
+                0x8000..=0x9fff => self.ppu.borrow().vram_blocked(),
+                0xfe00..=0xfe9f => self.ppu.borrow().oam_blocked(),
+                _ => false,
+            };
+            if blocked {
+                debug!("Write to {:#06x} while the PPU blocks access", address);
+                *count += 1;
+            }
+        }
+
+        if let Some(device) = self.peripheral(address) {
+            return device.write_u8(address, value);
+        }
+
         match address {
             0..=0x7fff => {
                 let cartridge = self.cartridge.as_mut().expect("No cartridge inserted");
@@ -92,17 +307,70 @@ impl MemoryBus {
                     .write(address, value)
                     .expect("Error reading cartridge");
             }
+            0xa000..=0xbfff if self.cartridge.is_some() => {
+                let cartridge = self.cartridge.as_mut().expect("No cartridge inserted");
+                cartridge
+                    .write(address, value)
+                    .expect("Error writing cartridge");
+            }
             0x8000..=0x97ff => self.ppu.borrow_mut().write_u8(address, value)?,
-            0x9800..=0x9bff => self.ppu.borrow_mut().write_u8(address, value)?,
-            // OAM
+            0x9800..=0x9fff => self.ppu.borrow_mut().write_u8(address, value)?,
+            // OAM, which ignores the CPU during OAM DMA
+            0xfe00..=0xfe9f if dma_blocking => {}
             0xfe00..=0xfe9f => self.ppu.borrow_mut().write_u8(address, value)?,
             // Joypad
             0xff00 => self.joypad.borrow_mut().write_u8(address, value)?,
             // Timer
             0xff04..=0xff07 => self.timer.borrow_mut().write_u8(address, value)?,
-            // LCD Control register (LCDC)
-            0xff40 => self.ppu.borrow_mut().write_u8(address, value)?,
-            0xff46 => self.oam_transfer(value)?,
+            // Sound registers and wave RAM
+            0xff10..=0xff3f => self.apu.borrow_mut().write_u8(address, value)?,
+            // LCD Control
+            0xff40 => {
+                let was_enabled = self.ppu.borrow().lcd_enabled();
+                self.ppu.borrow_mut().write_u8(address, value)?;
+                let enabled = self.ppu.borrow().lcd_enabled();
+                if enabled != was_enabled {
+                    self.emulation_event(EmulationEvent::LcdPower(enabled));
+                }
+            }
+            // STAT
+            0xff41 => self.ppu.borrow_mut().write_u8(address, value)?,
+            // Scroll, LYC, palette and window registers
+            0xff42..=0xff43 | 0xff45 | 0xff47..=0xff4b => {
+                self.ppu.borrow_mut().write_u8(address, value)?
+            }
+            // VRAM bank select (VBK)
+            0xff4f => self.ppu.borrow_mut().write_u8(address, value)?,
+            // CGB palettes and object priority mode (OPRI)
+            0xff68..=0xff6c => self.ppu.borrow_mut().write_u8(address, value)?,
+            0xd000..=0xdfff if self.cgb_mode => {
+                let offset = self.wram_offset(address);
+                self.banked_wram[offset] = value;
+            }
+            address if unmapped_io(address) => {
+                trace!("Ignored write to unmapped IO register {:#06x}", address);
+            }
+            0xff70 => self.svbk = value & 0b111,
+            0xff51 => self.hdma_source = (self.hdma_source & 0x00ff) | usize::from(value) << 8,
+            0xff52 => self.hdma_source = (self.hdma_source & 0xff00) | usize::from(value & 0xf0),
+            0xff53 => {
+                self.hdma_destination =
+                    (self.hdma_destination & 0x00ff) | usize::from(value & 0x1f) << 8
+            }
+            0xff54 => {
+                self.hdma_destination = (self.hdma_destination & 0xff00) | usize::from(value & 0xf0)
+            }
+            0xff55 if self.cgb_mode => self.write_hdma5(value)?,
+            // OAM DMA, which reads back the last page written
+            0xff46 => {
+                self.oam_transfer(value)?;
+                self.data[address] = value;
+            }
+            // Boot ROM disable, which can't be undone
+            0xff50 => {
+                self.boot_rom_disabled |= value != 0;
+                self.data[address] = value;
+            }
             // Write to VRAM tile data
             _ => self.data[address] = value,
         }
@@ -114,25 +382,332 @@ impl MemoryBus {
         self.emulation_event_sender.send(event);
     }
 
-    // Initiate an OAM transfer
+    /// Enables or disables counting VRAM/OAM writes made while the PPU's current mode blocks
+    /// the CPU from accessing them. Such writes usually point to timing bugs in a ROM or in the
+    /// emulator. Enabling resets the count.
+    pub fn set_blocked_access_logging(&mut self, enabled: bool) {
+        self.blocked_access_count = if enabled { Some(0) } else { None };
+    }
+
+    /// Number of VRAM/OAM writes attempted during blocked PPU modes since blocked access logging
+    /// was enabled.
+    pub fn blocked_access_count(&self) -> u64 {
+        self.blocked_access_count.unwrap_or(0)
+    }
+
+    /// Requests copying 160 bytes from `value * 0x100` into OAM. The transfer starts one M-cycle
+    /// after `start_requested_dma` is called, then copies a byte every M-cycle as `step_dma` is
+    /// called. Starting a transfer during another restarts it.
     fn oam_transfer(&mut self, value: u8) -> Result<()> {
-        let mut data = vec![0; 0xa0];
-        self.read(usize::from(value) * 0x100, &mut data)?;
-        self.write(0xfe00, &data)?;
+        // Sources are read through the bus, so banked memory is respected. There's nothing to
+        // copy from at 0xe000 and above, so the DMG reads the work RAM 0x2000 below instead.
+        let page = if value >= 0xe0 { value - 0x20 } else { value };
+        self.dma_requested = Some(usize::from(page) * 0x100);
         Ok(())
     }
 
+    /// Called once the M-cycles of an instruction have been stepped. The CPU writes 0xff46 in
+    /// the last M-cycle of an instruction, so a transfer requested by it begins its start-up
+    /// delay now.
+    pub fn instruction_finished(&mut self) {
+        self.instruction_accesses = 0;
+        if let Some(source) = self.dma_requested.take() {
+            self.dma_starting = Some(source);
+        }
+    }
+
+    /// True iff an OAM DMA transfer is in progress. A restarted transfer keeps OAM busy while
+    /// the new one starts up.
+    pub fn dma_active(&self) -> bool {
+        self.dma_cycles_remaining > 0
+    }
+
+    /// True iff OAM DMA blocks the CPU's next access. An instruction's accesses are made before
+    /// its M-cycles are stepped, so the transfer is looked ahead by one M-cycle per access
+    /// already made.
+    fn dma_blocking(&self) -> bool {
+        let elapsed_cycles = self.instruction_accesses;
+        (self.dma_starting.is_some() && elapsed_cycles > 0)
+            || self.dma_cycles_remaining > elapsed_cycles
+    }
+
+    /// Advances an in-progress OAM DMA transfer by the given number of M-cycles, copying a byte
+    /// each cycle.
+    pub fn step_dma(&mut self, cycles: u64) -> Result<()> {
+        for _ in 0..cycles {
+            if self.dma_active() {
+                let offset = 160 - usize::from(self.dma_cycles_remaining);
+                let value = self.bus_read(self.dma_source + offset)?;
+                self.ppu.borrow_mut().write_u8(0xfe00 + offset, value)?;
+                self.dma_cycles_remaining -= 1;
+            }
+            if let Some(source) = self.dma_starting.take() {
+                self.dma_source = source;
+                self.dma_cycles_remaining = 160;
+            }
+        }
+        Ok(())
+    }
+
+    /// HDMA5 reads as the blocks left minus 1, with bit 7 set once no HBlank DMA is running.
+    /// A finished transfer reads as 0xff.
+    fn read_hdma5(&self) -> u8 {
+        if self.hblank_dma_active {
+            self.hdma_length
+        } else {
+            0x80 | self.hdma_length
+        }
+    }
+
+    /// Starts a VRAM DMA. With bit 7 clear the whole transfer happens at once, otherwise one
+    /// 16 byte block is copied each HBlank. Clearing bit 7 during an HBlank DMA stops it instead.
+    fn write_hdma5(&mut self, value: u8) -> Result<()> {
+        if self.hblank_dma_active && value & 0x80 == 0 {
+            self.hblank_dma_active = false;
+            return Ok(());
+        }
+
+        self.hdma_length = value & 0x7f;
+        if value & 0x80 != 0 {
+            self.hblank_dma_active = true;
+        } else {
+            for _ in 0..=self.hdma_length {
+                self.copy_hdma_block()?;
+            }
+            self.hdma_length = 0x7f;
+        }
+        Ok(())
+    }
+
+    /// Copies the next 16 bytes of a VRAM DMA.
+    fn copy_hdma_block(&mut self) -> Result<()> {
+        for _ in 0..16 {
+            let value = self.bus_read(self.hdma_source)?;
+            self.ppu
+                .borrow_mut()
+                .write_u8(0x8000 | self.hdma_destination, value)?;
+            self.hdma_source = (self.hdma_source + 1) & 0xffff;
+            self.hdma_destination = (self.hdma_destination + 1) & 0x1fff;
+        }
+        Ok(())
+    }
+
+    /// Called as the PPU enters HBlank, to copy the next block of an HBlank DMA.
+    pub fn hblank_started(&mut self) -> Result<()> {
+        if !self.hblank_dma_active {
+            return Ok(());
+        }
+        self.copy_hdma_block()?;
+        if self.hdma_length == 0 {
+            self.hblank_dma_active = false;
+            self.hdma_length = 0x7f;
+        } else {
+            self.hdma_length -= 1;
+        }
+        Ok(())
+    }
+
+    /// Enables or disables blocking CPU reads outside of HRAM during OAM DMA, which then return
+    /// 0xff as they do on hardware. Programs are expected to wait in HRAM until the transfer ends.
+    pub fn set_dma_access_block(&mut self, enabled: bool) {
+        self.dma_access_block = enabled;
+    }
+
     pub fn interrupt(&mut self, interrupt: Interrupt) -> Result<()> {
         debug!("Interrupting");
         let bit = match interrupt {
             Interrupt::VBlank => 0,
             Interrupt::Stat => 1,
             Interrupt::Timer => 2,
+            Interrupt::Serial => 3,
             Interrupt::Joypad => 4,
         };
-        let mut interrupt_flag = self.read_u8(0xFF0F)?;
-        interrupt_flag |= 1 << bit;
-        self.write_u8(0xFF0F, interrupt_flag)?;
+        // IF is accessed directly, since interrupts can be requested while the CPU's reads are
+        // blocked
+        self.data[0xFF0F] |= 1 << bit;
+        Ok(())
+    }
+
+    /// The interrupt flag register IF, read directly like `interrupt` writes it.
+    pub fn interrupt_flag(&self) -> u8 {
+        self.data[0xff0f]
+    }
+
+    /// Runs the inserted cartridge's hardware for some T-cycles, see `Cartridge::step`.
+    pub fn step_cartridge(&mut self, t_cycles: u64) {
+        if let Some(cartridge) = self.cartridge.as_mut() {
+            cartridge.step(t_cycles);
+        }
+    }
+
+    /// The inserted cartridge's battery-backed RAM, if it has any.
+    pub fn save_ram(&self) -> Option<Vec<u8>> {
+        self.cartridge
+            .as_ref()
+            .and_then(|cartridge| cartridge.save_ram())
+    }
+
+    /// True iff the inserted cartridge's rumble motor is switched on.
+    pub fn rumble_active(&self) -> bool {
+        self.cartridge
+            .as_ref()
+            .is_some_and(|cartridge| cartridge.rumble_active())
+    }
+
+    /// Clears memory and any transfer in progress, keeping the connected components and the
+    /// debugging settings.
+    pub fn reset(&mut self) {
+        self.data.fill(0);
+        self.serial_port_data.clear();
+        self.dma_cycles_remaining = 0;
+        self.dma_source = 0;
+        self.dma_requested = None;
+        self.dma_starting = None;
+        self.instruction_accesses = 0;
+        self.boot_rom_disabled = false;
+        self.svbk = 0;
+        self.banked_wram.fill(0);
+        self.hdma_source = 0;
+        self.hdma_destination = 0;
+        self.hdma_length = 0x7f;
+        self.hblank_dma_active = false;
+        self.power_on_lcd();
+    }
+
+    /// The PPU powers on with the LCD as the boot ROM leaves it. If the boot ROM is going to run,
+    /// it starts from LCDC=0x00 instead, with the LCD off.
+    fn power_on_lcd(&mut self) {
+        if self.boot_rom_mapped() {
+            self.ppu
+                .borrow_mut()
+                .write_u8(0xff40, 0x00)
+                .expect("error switching off the LCD");
+        }
+    }
+
+    /// Switches between DMG and CGB behaviour. Only the CGB banks work RAM.
+    pub fn set_cgb_mode(&mut self, enabled: bool) {
+        self.cgb_mode = enabled;
+    }
+
+    /// True iff running as a CGB rather than a DMG.
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    /// Index into `banked_wram` of an address in 0xd000-0xdfff, using the bank selected by SVBK.
+    fn wram_offset(&self, address: Address) -> usize {
+        // Bank 0 is always at 0xc000-0xcfff, so selecting it selects bank 1 instead
+        let bank = usize::from(self.svbk.max(1));
+        (bank - 1) * 0x1000 + (address - 0xd000)
+    }
+
+    /// Routes reads and writes in `range` to a custom device instead of the usual component,
+    /// e.g. a debug register for a test ROM. The device sees the full bus address. Devices stay
+    /// mapped through resets, and their ranges can't overlap.
+    pub fn map_peripheral(
+        &mut self,
+        range: RangeInclusive<u16>,
+        device: Box<dyn Addressable>,
+    ) -> Result<()> {
+        let range = Address::from(*range.start())..=Address::from(*range.end());
+        if range.is_empty() {
+            return Err(Error::new("peripheral range is empty"));
+        }
+        if self
+            .peripherals
+            .iter()
+            .any(|(mapped, _)| range.start() <= mapped.end() && mapped.start() <= range.end())
+        {
+            return Err(Error::new("peripheral range overlaps a mapped peripheral"));
+        }
+        self.peripherals.push((range, device));
+        Ok(())
+    }
+
+    /// The custom device mapped at an address, if there is one.
+    fn peripheral(&mut self, address: Address) -> Option<&mut Box<dyn Addressable>> {
+        self.peripherals
+            .iter_mut()
+            .find(|(range, _)| range.contains(&address))
+            .map(|(_, device)| device)
+    }
+
+    /// Records every write to `address`, along with the value it replaced. Read-modify-write
+    /// instructions read and write in separate bus accesses, so they're seen as a normal write.
+    pub fn add_write_watchpoint(&mut self, address: Address) {
+        self.write_watchpoints.push(address);
+    }
+
+    pub fn remove_write_watchpoint(&mut self, address: Address) {
+        self.write_watchpoints.retain(|watched| *watched != address);
+    }
+
+    /// Returns and clears the writes to watched addresses, oldest first.
+    pub fn take_watchpoint_hits(&mut self) -> Vec<WatchpointHit> {
+        std::mem::take(&mut self.watchpoint_hits)
+    }
+
+    /// Maps a 256 byte boot ROM over 0x0000-0x00ff, until it unmaps itself by writing to 0xff50.
+    /// The LCD is switched off, as it is at power on, for the boot ROM to start it.
+    pub fn load_boot_rom(&mut self, boot_rom: Vec<u8>) -> Result<()> {
+        if boot_rom.len() != 0x100 {
+            return Err(Error::new("boot ROM must be 256 bytes"));
+        }
+        self.boot_rom = Some(boot_rom);
+        self.power_on_lcd();
+        Ok(())
+    }
+
+    /// True iff reads from 0x0000-0x00ff go to the boot ROM instead of the cartridge.
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some() && !self.boot_rom_disabled
+    }
+
+    /// Captures the bus for a save state.
+    pub(crate) fn state(&self) -> MemoryBusState {
+        MemoryBusState {
+            data: self.data.to_vec(),
+            dma_cycles_remaining: self.dma_cycles_remaining,
+            dma_source: self.dma_source,
+            dma_requested: self.dma_requested,
+            dma_starting: self.dma_starting,
+            boot_rom_disabled: self.boot_rom_disabled,
+            cgb_mode: self.cgb_mode,
+            svbk: self.svbk,
+            banked_wram: self.banked_wram.clone(),
+            hdma_source: self.hdma_source,
+            hdma_destination: self.hdma_destination,
+            hdma_length: self.hdma_length,
+            hblank_dma_active: self.hblank_dma_active,
+            cartridge: self.cartridge.as_ref().map(Cartridge::save_state),
+        }
+    }
+
+    /// Restores the bus from `state`. The same cartridge must be inserted as when it was saved.
+    pub(crate) fn restore_state(&mut self, state: MemoryBusState) -> Result<()> {
+        if state.data.len() != self.data.len() || state.banked_wram.len() != self.banked_wram.len()
+        {
+            return Err(Error::new("Invalid memory state"));
+        }
+        match (self.cartridge.as_mut(), &state.cartridge) {
+            (Some(cartridge), Some(cartridge_state)) => cartridge.load_state(cartridge_state)?,
+            (None, None) => {}
+            _ => return Err(Error::new("Save state is for a different cartridge")),
+        }
+        self.data.copy_from_slice(&state.data);
+        self.dma_cycles_remaining = state.dma_cycles_remaining;
+        self.dma_source = state.dma_source;
+        self.dma_requested = state.dma_requested;
+        self.dma_starting = state.dma_starting;
+        self.boot_rom_disabled = state.boot_rom_disabled;
+        self.cgb_mode = state.cgb_mode;
+        self.svbk = state.svbk;
+        self.banked_wram = state.banked_wram;
+        self.hdma_source = state.hdma_source;
+        self.hdma_destination = state.hdma_destination;
+        self.hdma_length = state.hdma_length;
+        self.hblank_dma_active = state.hblank_dma_active;
         Ok(())
     }
 
@@ -162,3 +737,265 @@ impl Addressable for MemoryBus {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::GameBoyState;
+
+    #[test]
+    fn test_write_watchpoint_sees_read_modify_write_old_and_new_values() {
+        let code = [
+            0x21, 0x00, 0xc1, // LD HL,0xc100
+            0x36, 0x41, // LD (HL),0x41
+            0x34, // INC (HL)
+            0xcb, 0xfe, // SET 7,(HL)
+            0x35, // DEC (HL)
+            0xcb, 0x86, // RES 0,(HL)
+            0xcb, 0x36, // SWAP (HL)
+        ];
+        let mut gameboy = GameBoyState::new_raw(&code, 0xc000, 0xc000).unwrap();
+        gameboy.memory_bus.borrow_mut().add_write_watchpoint(0xc100);
+        for _ in 0..7 {
+            gameboy.tick();
+        }
+
+        let hit = |old_value, new_value| WatchpointHit {
+            address: 0xc100,
+            old_value,
+            new_value,
+        };
+        assert_eq!(
+            vec![
+                hit(0x00, 0x41),
+                hit(0x41, 0x42),
+                hit(0x42, 0xc2),
+                hit(0xc2, 0xc1),
+                hit(0xc1, 0xc0),
+                hit(0xc0, 0x0c),
+            ],
+            gameboy.memory_bus.borrow_mut().take_watchpoint_hits()
+        );
+    }
+
+    #[test]
+    fn test_unmapped_io_reads_ff_and_ignores_writes() {
+        let gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+        for address in [0xff03, 0xff08, 0xff0e, 0xff7f] {
+            memory_bus.write_u8(address, 0x00).unwrap();
+            assert_eq!(
+                0xff,
+                memory_bus.read_u8(address).unwrap(),
+                "{:#06x}",
+                address
+            );
+        }
+    }
+
+    /// A peripheral which stores one byte, but reads back its complement.
+    struct InvertingRegister(u8);
+
+    impl Addressable for InvertingRegister {
+        fn read(&mut self, _address: Address, data: &mut [u8]) -> Result<()> {
+            data.fill(!self.0);
+            Ok(())
+        }
+
+        fn write(&mut self, _address: Address, data: &[u8]) -> Result<()> {
+            self.0 = data[data.len() - 1];
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_mapped_peripheral_handles_its_range() {
+        let gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+        memory_bus
+            .map_peripheral(0xff03..=0xff03, Box::new(InvertingRegister(0)))
+            .unwrap();
+        assert!(memory_bus
+            .map_peripheral(0xff00..=0xff03, Box::new(InvertingRegister(0)))
+            .is_err());
+
+        memory_bus.write_u8(0xff03, 0x5a).unwrap();
+        assert_eq!(0xa5, memory_bus.read_u8(0xff03).unwrap());
+        // Neighbouring addresses are unaffected
+        assert_eq!(0xff, memory_bus.read_u8(0xff08).unwrap());
+    }
+
+    #[test]
+    fn test_oam_dma_reads_banked_rom_and_mirrors_high_sources() {
+        // MBC1 with 4 banks, where every byte of bank 2 is 0x22
+        let mut rom = vec![0; 0x10000];
+        rom[0x147] = 0x01;
+        rom[0x148] = 0x01;
+        rom[0x8000..0xc000].fill(0x22);
+        let mut gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        gameboy
+            .load_cartridge(Cartridge::cartridge_from_data(&rom).unwrap())
+            .unwrap();
+
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+        memory_bus.write_u8(0x2000, 2).unwrap();
+        memory_bus.write_u8(0xff46, 0x40).unwrap();
+        memory_bus.instruction_finished();
+        memory_bus.step_dma(161).unwrap();
+        drop(memory_bus);
+        assert!(gameboy.oam().iter().all(|byte| *byte == 0x22));
+
+        // A source page of 0xe1 reads work RAM at 0xc100
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+        memory_bus.write(0xc100, &[0x33; 160]).unwrap();
+        memory_bus.write_u8(0xff46, 0xe1).unwrap();
+        memory_bus.instruction_finished();
+        memory_bus.step_dma(161).unwrap();
+        drop(memory_bus);
+        assert!(gameboy.oam().iter().all(|byte| *byte == 0x33));
+    }
+
+    #[test]
+    fn test_cgb_work_ram_banks_are_isolated() {
+        let gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+        memory_bus.set_cgb_mode(true);
+
+        memory_bus.write_u8(0xff70, 3).unwrap();
+        memory_bus.write_u8(0xd123, 0x33).unwrap();
+        assert_eq!(0xfb, memory_bus.read_u8(0xff70).unwrap());
+
+        memory_bus.write_u8(0xff70, 4).unwrap();
+        assert_eq!(0x00, memory_bus.read_u8(0xd123).unwrap());
+        memory_bus.write_u8(0xd123, 0x44).unwrap();
+
+        memory_bus.write_u8(0xff70, 3).unwrap();
+        assert_eq!(0x33, memory_bus.read_u8(0xd123).unwrap());
+
+        // Bank 0 selects bank 1
+        memory_bus.write_u8(0xff70, 1).unwrap();
+        memory_bus.write_u8(0xd123, 0x11).unwrap();
+        memory_bus.write_u8(0xff70, 0).unwrap();
+        assert_eq!(0x11, memory_bus.read_u8(0xd123).unwrap());
+    }
+
+    #[test]
+    fn test_boot_rom_cannot_be_remapped() {
+        let gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+        let mut rom = vec![0; 0x8000];
+        rom[0x00] = 0xaa;
+        memory_bus.insert_cartridge(Cartridge::cartridge_from_data(&rom).unwrap());
+        memory_bus.load_boot_rom(vec![0x31; 0x100]).unwrap();
+        assert_eq!(0x31, memory_bus.read_u8(0x00).unwrap());
+
+        memory_bus.write_u8(0xff50, 0x01).unwrap();
+        assert_eq!(0xaa, memory_bus.read_u8(0x00).unwrap());
+
+        memory_bus.write_u8(0xff50, 0x00).unwrap();
+        assert!(!memory_bus.boot_rom_mapped());
+        assert_eq!(0xaa, memory_bus.read_u8(0x00).unwrap());
+    }
+
+    #[test]
+    fn test_boot_rom_starts_with_lcd_off() {
+        let mut gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        let lcdc = |gameboy: &GameBoyState| gameboy.ppu.borrow_mut().read_u8(0xff40).unwrap();
+        // Without a boot ROM, LCDC is as the boot ROM leaves it
+        assert_eq!(0x91, lcdc(&gameboy));
+
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+        memory_bus.load_boot_rom(vec![0x00; 0x100]).unwrap();
+        assert_eq!(0x00, memory_bus.read_u8(0xff40).unwrap());
+        drop(memory_bus);
+        assert!(!gameboy.lcd_enabled());
+
+        // The boot ROM runs again from power on
+        gameboy.ppu.borrow_mut().write_u8(0xff40, 0x91).unwrap();
+        gameboy.reset();
+        assert_eq!(0x00, lcdc(&gameboy));
+        assert!(!gameboy.lcd_enabled());
+    }
+
+    #[test]
+    fn test_blocked_access_count_counts_vram_writes_during_pixel_transfer() {
+        // A program of NOPs
+        let mut gameboy = GameBoyState::new_raw(&[0; 0x100], 0xc000, 0xc000).unwrap();
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .set_blocked_access_logging(true);
+        // Enable the LCD
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write_u8(0xff40, 0x80)
+            .unwrap();
+
+        // Writes during OAM search don't touch VRAM
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write_u8(0x8000, 0xff)
+            .unwrap();
+        assert_eq!(0, gameboy.memory_bus.borrow().blocked_access_count());
+
+        while !gameboy.ppu.borrow().vram_blocked() {
+            gameboy.tick();
+        }
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write_u8(0x8000, 0xff)
+            .unwrap();
+        assert_eq!(1, gameboy.memory_bus.borrow().blocked_access_count());
+    }
+
+    #[test]
+    fn test_write_only_io_registers_read_back_as_1s() {
+        let gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+
+        // Sound on, then write a frequency to NR13
+        memory_bus.write_u8(0xff26, 0x80).unwrap();
+        memory_bus.write_u8(0xff13, 0x12).unwrap();
+        assert_eq!(0xff, memory_bus.read_u8(0xff13).unwrap());
+
+        // Only the length enable bit of NR14 can be read
+        memory_bus.write_u8(0xff14, 0x47).unwrap();
+        assert_eq!(0xff, memory_bus.read_u8(0xff14).unwrap());
+        memory_bus.write_u8(0xff14, 0x07).unwrap();
+        assert_eq!(0xbf, memory_bus.read_u8(0xff14).unwrap());
+
+        // Unused registers read as 0xff
+        assert_eq!(0xff, memory_bus.read_u8(0xff03).unwrap());
+        assert_eq!(0xff, memory_bus.read_u8(0xff4d).unwrap());
+    }
+
+    #[test]
+    fn test_dma_access_block_allows_hram_and_io_reads() {
+        let gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        let mut memory_bus = gameboy.memory_bus.borrow_mut();
+        memory_bus.set_dma_access_block(true);
+        memory_bus.write_u8(0xc100, 0x42).unwrap();
+        memory_bus.write_u8(0xff90, 0x24).unwrap();
+
+        // Copy 0xc000-0xc09f into OAM
+        memory_bus.write_u8(0xff46, 0xc0).unwrap();
+        memory_bus.instruction_finished();
+        // The transfer starts an M-cycle after the instruction writing 0xff46
+        assert_eq!(0x42, memory_bus.read_u8(0xc100).unwrap());
+        memory_bus.step_dma(1).unwrap();
+        assert_eq!(0xc0, memory_bus.read_u8(0xff46).unwrap());
+        assert_eq!(0xff, memory_bus.read_u8(0xc100).unwrap());
+        assert_eq!(0x24, memory_bus.read_u8(0xff90).unwrap());
+        assert_eq!(0, memory_bus.read_u8(0xffff).unwrap());
+        assert_eq!(0x42, memory_bus.peek(0xc100).unwrap());
+
+        memory_bus.step_dma(159).unwrap();
+        assert!(memory_bus.dma_active());
+        memory_bus.step_dma(1).unwrap();
+        assert!(!memory_bus.dma_active());
+        assert_eq!(0x42, memory_bus.read_u8(0xc100).unwrap());
+    }
+}
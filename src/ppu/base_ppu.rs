@@ -0,0 +1,824 @@
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+use crate::component::{Address, Addressable, ElapsedTime, Steppable};
+use crate::error::{Error, Result};
+use crate::gameboy::GameBoyState;
+
+use super::{
+    lcd, GraphicsEngine, Layer, OamData, Palette, Ppu, TileColor, TileDataAddressingMethod,
+};
+
+/// The video memory and LCD registers, shared with the graphics engine when rendering.
+#[derive(Serialize, Deserialize)]
+pub struct PpuData {
+    /// Both 8 KiB banks of VRAM (0x8000-0x9fff), bank 0 followed by bank 1. Only a CGB can
+    /// switch to bank 1, so on a DMG it stays zeroed. See `tile_data`, `background_map` and
+    /// `vram_bank1` for the parts of it.
+    vram: Vec<u8>,
+
+    /// A table containing data for 40 sprites
+    pub sprite_tiles_table: Vec<u8>,
+
+    pub lcd: lcd::Lcd,
+
+    /// Debugging overrides which hide a layer from the display, indexed by `Layer`
+    #[serde(skip)]
+    layers_enabled: [bool; 3],
+
+    /// True when running as a CGB, which enables the CGB-only registers
+    pub cgb_mode: bool,
+    /// VBK: Selects which VRAM bank the CPU sees at 0x8000-0x9fff, only the lowest bit is used
+    pub vbk: u8,
+    /// OPRI: Object priority mode, only the lowest bit is used
+    pub opri: u8,
+    /// BCPS/OCPS: Index into background/object palette RAM, which increments after each write
+    /// to BCPD/OCPD if bit 7 is set
+    pub bcps: u8,
+    pub ocps: u8,
+    /// CGB palette RAM, read and written through BCPD/OCPD
+    #[serde(with = "crate::utils::byte_array")]
+    pub bg_palette_ram: [u8; 64],
+    #[serde(with = "crate::utils::byte_array")]
+    pub obj_palette_ram: [u8; 64],
+
+    /// The (at most 10) sprites found by the current line's OAM search, in OAM order. Filled in
+    /// during mode 2 and fixed from then until the next line.
+    scanline_objects: Vec<OamData>,
+
+    /// Which of the 384 tiles in tile data were written since the last `take_dirty_tiles`
+    dirty_tiles: Vec<bool>,
+}
+
+impl PpuData {
+    fn new() -> PpuData {
+        PpuData {
+            vram: vec![0; 0x4000],
+            sprite_tiles_table: vec![0; 160],
+            lcd: lcd::Lcd::new(),
+            layers_enabled: [true; 3],
+            cgb_mode: false,
+            vbk: 0,
+            opri: 0,
+            bcps: 0,
+            ocps: 0,
+            bg_palette_ram: [0; 64],
+            obj_palette_ram: [0; 64],
+            scanline_objects: Vec::new(),
+            dirty_tiles: vec![true; 384],
+        }
+    }
+
+    /// False iff the layer has been hidden for debugging, regardless of the LCDC enable bits.
+    pub fn layer_enabled(&self, layer: Layer) -> bool {
+        self.layers_enabled[layer as usize]
+    }
+
+    /// True iff overlapping sprites are prioritized by X coordinate, with the lowest X drawn on
+    /// top. Otherwise the sprite earliest in OAM is on top. A DMG always uses X coordinates, while
+    /// a CGB uses whichever mode OPRI selects.
+    pub fn obj_priority_by_x(&self) -> bool {
+        !self.cgb_mode || self.opri & 1 == 1
+    }
+
+    /// Clears memory and registers back to their power-on state, reusing the allocations. The
+    /// DMG/CGB mode is a setting rather than state, so it's kept.
+    fn reset(&mut self) {
+        self.vram.fill(0);
+        self.sprite_tiles_table.fill(0);
+        self.lcd = lcd::Lcd::new();
+        self.vbk = 0;
+        self.opri = 0;
+        self.bcps = 0;
+        self.ocps = 0;
+        self.bg_palette_ram = [0; 64];
+        self.obj_palette_ram = [0; 64];
+        self.scanline_objects.clear();
+        self.dirty_tiles.fill(true);
+    }
+
+    /// Tile data takes up addresses 0x8000-0x97ff.
+    pub fn tile_data(&self) -> &[u8] {
+        &self.vram[..0x1800]
+    }
+
+    /// Addresses 0x9800-0x9bff and 0x9c00-0x9fff are two 32x32 maps of background tiles.
+    /// Each byte contains the number of a tile to be displayed.
+    pub fn background_map(&self) -> &[u8] {
+        &self.vram[0x1800..0x2000]
+    }
+
+    /// CGB VRAM bank 1, with more tile data at 0x8000-0x97ff and the attributes of each
+    /// background map entry at 0x9800-0x9fff
+    pub fn vram_bank1(&self) -> &[u8] {
+        &self.vram[0x2000..]
+    }
+
+    /// Where the CPU's access to `address` in 0x8000-0x9fff lands in `vram`.
+    fn vram_index(&self, address: Address) -> usize {
+        let bank_offset = if self.cpu_sees_bank1() { 0x2000 } else { 0 };
+        bank_offset + address - 0x8000
+    }
+
+    /// Gets the color index (0-3) of a pixel in one of the 384 tiles stored in tile data.
+    pub fn get_tile_pixel(&self, tile_number: usize, row: usize, col: usize) -> u8 {
+        tile_pixel(self.tile_data(), tile_number, row, col)
+    }
+
+    /// Like `get_tile_pixel`, but on a CGB the tile is taken from VRAM bank 1 if `bank` is 1.
+    pub fn get_banked_tile_pixel(
+        &self,
+        bank: u8,
+        tile_number: usize,
+        row: usize,
+        col: usize,
+    ) -> u8 {
+        if self.cgb_mode && bank == 1 {
+            tile_pixel(&self.vram_bank1()[..0x1800], tile_number, row, col)
+        } else {
+            self.get_tile_pixel(tile_number, row, col)
+        }
+    }
+
+    /// True iff the PPU uses VRAM bank 1 where the CPU accesses 0x8000-0x9fff.
+    fn cpu_sees_bank1(&self) -> bool {
+        self.cgb_mode && self.vbk & 1 == 1
+    }
+
+    /// Gets the color index of a pixel in a background tile map, where `x` and `y` are relative to
+    /// the map's top left corner. On a CGB, the entry's attributes can flip the tile or take it
+    /// from VRAM bank 1. Also returns true iff the attributes give the tile priority over sprites.
+    fn get_map_pixel(&self, map_offset: usize, x: usize, y: usize) -> (u8, bool) {
+        let method = if self.lcd.lcd_control.bg_window_tile_data_area {
+            TileDataAddressingMethod::Method8000
+        } else {
+            TileDataAddressingMethod::Method8800
+        };
+        let entry = map_offset + (y / 8) * 32 + x / 8;
+        let tile_index = method.adjust_tile_index(self.background_map()[entry].into());
+        let attributes = if self.cgb_mode {
+            self.vram_bank1()[0x1800 + entry]
+        } else {
+            0
+        };
+
+        let (mut row, mut col) = (y % 8, x % 8);
+        if attributes & 0x20 != 0 {
+            col = 7 - col;
+        }
+        if attributes & 0x40 != 0 {
+            row = 7 - row;
+        }
+        let tile_data = if attributes & 0x08 != 0 {
+            &self.vram_bank1()[..0x1800]
+        } else {
+            self.tile_data()
+        };
+        let color_index = tile_pixel(tile_data, tile_index, row, col);
+        (color_index, attributes & 0x80 != 0)
+    }
+
+    pub fn obj_height(&self) -> u8 {
+        if self.lcd.lcd_control.obj_size {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// True iff a sprite overlaps the given scanline.
+    fn object_on_line(&self, oam_data: &OamData, ly: u8) -> bool {
+        let height = i16::from(self.obj_height());
+        let top = i16::from(oam_data.y_pos()) - 16;
+        (top..top + height).contains(&i16::from(ly))
+    }
+
+    /// Selects the sprites which overlap the given scanline, in OAM order, as an OAM search of
+    /// the current OAM would. The hardware only draws the first 10 sprites found, even if some of
+    /// them are horizontally off-screen.
+    pub fn get_scanline_objects(&self, ly: u8) -> Vec<OamData> {
+        self.sprite_tiles_table
+            .chunks(4)
+            .map(OamData::new)
+            .filter(|oam_data| self.object_on_line(oam_data, ly))
+            .take(10)
+            .collect()
+    }
+
+    /// The sprites found by the current line's OAM search, which are the ones drawn on it.
+    pub fn scanline_objects(&self) -> &[OamData] {
+        &self.scanline_objects
+    }
+
+    /// Checks one OAM entry as part of the current line's OAM search. Entries read while OAM DMA
+    /// is using the bus aren't seen.
+    fn search_oam_entry(&mut self, index: usize, dma_active: bool) {
+        if dma_active || self.scanline_objects.len() == 10 {
+            return;
+        }
+        let oam_data = OamData::new(&self.sprite_tiles_table[index * 4..index * 4 + 4]);
+        if self.object_on_line(&oam_data, self.lcd.ly) {
+            self.scanline_objects.push(oam_data);
+        }
+    }
+
+    /// Gets the color index of the window at a screen x position on the current scanline, and
+    /// whether it has priority over sprites. The row comes from the window's internal line
+    /// counter rather than LY.
+    pub fn get_window_pixel(&self, x: u8) -> (u8, bool) {
+        let x = usize::from(x + 7 - self.lcd.wx);
+        let y = usize::from(self.lcd.window_line);
+
+        let map_offset = if self.lcd.lcd_control.window_tile_map_area {
+            0x400
+        } else {
+            0
+        };
+        self.get_map_pixel(map_offset, x, y)
+    }
+
+    /// Gets the color index of the background at a screen position, taking scrolling into
+    /// account, and whether it has priority over sprites.
+    pub fn get_bg_pixel(&self, x: u8, y: u8) -> (u8, bool) {
+        let x = usize::from(x.wrapping_add(self.lcd.scx));
+        let y = usize::from(y.wrapping_add(self.lcd.scy));
+
+        let map_offset = if self.lcd.lcd_control.bg_tile_map_area {
+            0x400
+        } else {
+            0
+        };
+        self.get_map_pixel(map_offset, x, y)
+    }
+}
+
+/// Gets the color index (0-3) of a pixel in a tile from a bank of tile data.
+fn tile_pixel(tile_data: &[u8], tile_number: usize, row: usize, col: usize) -> u8 {
+    // Each tile occupies 16 bytes, with 2 bytes per row
+    let address = tile_number * 16 + row * 2;
+    let bit_1 = (tile_data[address] >> (7 - col)) & 1;
+    let bit_2 = (tile_data[address + 1] >> (7 - col)) & 1;
+    (bit_2 << 1) | bit_1
+}
+
+/// Writes a byte of palette RAM at the index selected by a BCPS/OCPS value, then advances the
+/// index if auto-increment (bit 7) is on.
+fn write_palette_data(palette_ram: &mut [u8; 64], specification: &mut u8, value: u8) {
+    let index = *specification & 0x3f;
+    palette_ram[usize::from(index)] = value;
+    if *specification & 0x80 != 0 {
+        *specification = 0x80 | ((index + 1) & 0x3f);
+    }
+}
+
+/// Holds the PPU's memory and LCD state, and drives a graphics engine which does the actual
+/// rendering.
+pub struct BasePpu {
+    data: PpuData,
+    engine: Box<dyn GraphicsEngine>,
+    /// Colors the engine shows the four shades as
+    palette: Palette,
+}
+
+impl BasePpu {
+    pub fn new(engine: Box<dyn GraphicsEngine>) -> BasePpu {
+        let mut ppu = BasePpu {
+            data: PpuData::new(),
+            engine,
+            palette: Palette::default(),
+        };
+        ppu.engine.rebuild_cache(&ppu.data);
+        ppu
+    }
+
+    /// Swaps out the active graphics engine, rebuilding the new engine's caches from the current
+    /// contents of VRAM.
+    pub fn set_engine(&mut self, mut engine: Box<dyn GraphicsEngine>) {
+        engine.set_palette(self.palette);
+        engine.rebuild_cache(&self.data);
+        self.engine = engine;
+    }
+
+    /// Recolors the display. Only affects what is drawn, not emulation.
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        self.engine.set_palette(palette);
+    }
+
+    pub fn palette(&self) -> Palette {
+        self.palette
+    }
+
+    pub fn engine(&self) -> &dyn GraphicsEngine {
+        self.engine.as_ref()
+    }
+
+    pub fn engine_mut(&mut self) -> &mut dyn GraphicsEngine {
+        self.engine.as_mut()
+    }
+
+    pub fn data(&self) -> &PpuData {
+        &self.data
+    }
+
+    /// Runs the current line's whole OAM search at once, rather than over mode 2. Useful for
+    /// drawing a line with the engine directly.
+    pub fn search_oam(&mut self) {
+        self.data.scanline_objects = self.data.get_scanline_objects(self.data.lcd.ly);
+    }
+
+    /// Borrows the PPU's data alongside the engine, so the engine can render it.
+    pub fn split_mut(&mut self) -> (&PpuData, &mut dyn GraphicsEngine) {
+        (&self.data, self.engine.as_mut())
+    }
+
+    fn _read(&mut self, address: Address) -> Result<u8> {
+        let data = &self.data;
+        let value = match address {
+            0x8000..=0x9fff => data.vram[data.vram_index(address)],
+            0xfe00..=0xfe9f => data.sprite_tiles_table[address - 0xfe00],
+            0xff40 => data.lcd.lcd_control.read(),
+            0xff41 => data.lcd.stat(),
+            0xff42 => data.lcd.scy,
+            0xff43 => data.lcd.scx,
+            0xff44 => data.lcd.ly,
+            0xff45 => data.lcd.lyc,
+            0xff47 => data.lcd.bgp,
+            0xff48 => data.lcd.obp0,
+            0xff49 => data.lcd.obp1,
+            0xff4a => data.lcd.wy,
+            0xff4b => data.lcd.wx,
+            0xff4f if data.cgb_mode => 0xfe | data.vbk,
+            0xff4f => 0xff,
+            0xff6c if data.cgb_mode => 0xfe | data.opri,
+            0xff6c => 0xff,
+            // The palette registers only exist on a CGB
+            0xff68..=0xff6b if !data.cgb_mode => 0xff,
+            0xff68 => 0x40 | data.bcps,
+            0xff69 => data.bg_palette_ram[usize::from(data.bcps & 0x3f)],
+            0xff6a => 0x40 | data.ocps,
+            0xff6b => data.obj_palette_ram[usize::from(data.ocps & 0x3f)],
+            _ => return Err(Error::new("Invalid address")),
+        };
+
+        Ok(value)
+    }
+
+    fn _write(&mut self, address: Address, value: u8) -> Result<()> {
+        let data = &mut self.data;
+        match address {
+            0x8000..=0x97ff if !data.cpu_sees_bank1() => {
+                trace!("write to tile data: {:#x} into {:#x}", value, address);
+                data.vram[address - 0x8000] = value;
+                data.dirty_tiles[(address - 0x8000) / 16] = true;
+            }
+            0x8000..=0x9fff => {
+                let index = data.vram_index(address);
+                data.vram[index] = value;
+            }
+            0xfe00..=0xfe9f => {
+                data.sprite_tiles_table[address - 0xfe00] = value;
+            }
+            0xff40 => data.lcd.write_lcdc(value),
+            // Only the interrupt source selection bits can be written
+            0xff41 => data.lcd.stat.0 = (data.lcd.stat.0 & 0b1000_0111) | (value & 0b0111_1000),
+            0xff42 => data.lcd.scy = value,
+            0xff43 => data.lcd.scx = value,
+            0xff45 => data.lcd.lyc = value,
+            0xff47 => data.lcd.bgp = value,
+            0xff48 => data.lcd.obp0 = value,
+            0xff49 => data.lcd.obp1 = value,
+            0xff4a => data.lcd.wy = value,
+            0xff4b => data.lcd.wx = value,
+            0xff4f if data.cgb_mode => data.vbk = value & 1,
+            0xff4f => {}
+            0xff6c => data.opri = value & 1,
+            0xff68..=0xff6b if !data.cgb_mode => {}
+            0xff68 => data.bcps = value & 0xbf,
+            0xff69 => write_palette_data(&mut data.bg_palette_ram, &mut data.bcps, value),
+            0xff6a => data.ocps = value & 0xbf,
+            0xff6b => write_palette_data(&mut data.obj_palette_ram, &mut data.ocps, value),
+            _ => return Err(Error::new("Invalid address")),
+        }
+
+        self.engine.after_write(&self.data, address);
+
+        Ok(())
+    }
+}
+
+impl Addressable for BasePpu {
+    fn read(&mut self, address: Address, data: &mut [u8]) -> Result<()> {
+        for (offset, byte) in data.iter_mut().enumerate() {
+            *byte = self._read(address + offset)?;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, address: Address, data: &[u8]) -> Result<()> {
+        for (offset, byte) in data.iter().enumerate() {
+            self._write(address + offset, *byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Steppable for BasePpu {
+    fn step(&mut self, state: &GameBoyState) -> Result<ElapsedTime> {
+        // OAM search reads one entry every 2 dots over mode 2
+        let dot = self.data.lcd.line_dots();
+        if self.data.lcd.mode() == 2 && dot.is_multiple_of(2) {
+            if dot == 0 {
+                self.data.scanline_objects.clear();
+            }
+            let dma_active = state.memory_bus.borrow().dma_active();
+            self.data.search_oam_entry(dot as usize / 2, dma_active);
+        }
+
+        if self.data.lcd.pushing_pixels() {
+            let (x, y) = (self.data.lcd.scan_x as u8, self.data.lcd.ly);
+            self.engine.place_pixel(&self.data, x, y);
+        }
+
+        let was_transferring = self.data.lcd.transferring_pixels();
+        let elapsed = self.data.lcd.step(state)?;
+        if !was_transferring
+            && self.data.lcd.transferring_pixels()
+            && self.data.lcd.lcd_control.obj_enable
+        {
+            // Fetching each sprite on the line pauses pixel transfer for about 6 dots
+            let objects = self.data.scanline_objects.len() as u32;
+            self.data.lcd.stall_pixel_transfer(6 * objects);
+        }
+        Ok(elapsed)
+    }
+}
+
+impl Ppu for BasePpu {
+    /// T-cycles until the PPU next requests a VBlank or STAT interrupt, assuming no registers are
+    /// written in the meantime. None while the LCD is off.
+    fn cycles_until_next_interrupt(&self) -> Option<u64> {
+        if !self.data.lcd.lcd_control.lcd_ppu_enable {
+            return None;
+        }
+
+        let sprite_stall = |ly| {
+            if self.data.lcd.lcd_control.obj_enable {
+                6 * self.data.get_scanline_objects(ly).len() as u32
+            } else {
+                0
+            }
+        };
+        // The PPU draws one dot per T-cycle
+        Some(self.data.lcd.dots_until_next_interrupt(sprite_stall))
+    }
+
+    fn vram(&self) -> &[u8] {
+        &self.data.vram
+    }
+
+    fn tile_data(&self) -> &[u8] {
+        self.data.tile_data()
+    }
+
+    fn background_map(&self) -> &[u8] {
+        self.data.background_map()
+    }
+
+    fn oam(&self) -> &[u8] {
+        &self.data.sprite_tiles_table
+    }
+
+    fn scanline_sprites(&self) -> Vec<OamData> {
+        let mut sprites = self.data.scanline_objects.clone();
+        if self.data.obj_priority_by_x() {
+            // A stable sort, so sprites at the same X stay in OAM order
+            sprites.sort_by_key(|sprite| sprite.x_pos());
+        }
+        sprites
+    }
+
+    fn vram_blocked(&self) -> bool {
+        self.data.lcd.vram_blocked()
+    }
+
+    fn oam_blocked(&self) -> bool {
+        self.data.lcd.oam_blocked()
+    }
+
+    fn frame_count(&self) -> u64 {
+        self.data.lcd.frame_count
+    }
+
+    fn reset_frame_count(&mut self) {
+        self.data.lcd.frame_count = 0;
+    }
+
+    fn lcd_enabled(&self) -> bool {
+        self.data.lcd.lcd_control.lcd_ppu_enable
+    }
+
+    fn ly(&self) -> u8 {
+        self.data.lcd.ly
+    }
+
+    fn mode(&self) -> u8 {
+        self.data.lcd.mode()
+    }
+
+    fn scanline_dot(&self) -> u16 {
+        self.data.lcd.line_dots() as u16
+    }
+
+    fn set_cgb_mode(&mut self, enabled: bool) {
+        self.data.cgb_mode = enabled;
+    }
+
+    fn bg_palette_ram(&self) -> &[u8] {
+        &self.data.bg_palette_ram
+    }
+
+    fn obj_palette_ram(&self) -> &[u8] {
+        &self.data.obj_palette_ram
+    }
+
+    fn take_hblank_started(&mut self) -> bool {
+        self.data.lcd.take_hblank_started()
+    }
+
+    fn take_dirty_tiles(&mut self) -> Vec<usize> {
+        let dirty_tiles = (0..384)
+            .filter(|tile| self.data.dirty_tiles[*tile])
+            .collect();
+        self.data.dirty_tiles.fill(false);
+        dirty_tiles
+    }
+
+    fn reset(&mut self) {
+        self.data.reset();
+        self.engine.reset();
+        self.engine.rebuild_cache(&self.data);
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(&self.data).expect("error encoding ppu state")
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        let data: PpuData =
+            bincode::deserialize(state).map_err(|_| Error::new("Invalid PPU state"))?;
+        self.data = PpuData {
+            layers_enabled: self.data.layers_enabled,
+            ..data
+        };
+        self.engine.rebuild_cache(&self.data);
+        Ok(())
+    }
+
+    fn screen(&self) -> &[TileColor] {
+        self.engine.screen()
+    }
+
+    fn force_render(&mut self, dma_active: bool) -> Vec<TileColor> {
+        if !self.lcd_enabled() {
+            self.engine.reset();
+            return self.engine.screen().to_vec();
+        }
+
+        let (ly, window_line, window_on_line) = (
+            self.data.lcd.ly,
+            self.data.lcd.window_line,
+            self.data.lcd.window_on_line,
+        );
+        let scanline_objects = std::mem::take(&mut self.data.scanline_objects);
+
+        self.data.lcd.window_line = 0;
+        for y in 0..144 {
+            self.data.lcd.ly = y;
+            self.data.lcd.window_on_line = self.data.lcd.window_visible_on(y);
+            if dma_active {
+                self.data.scanline_objects.clear();
+            } else {
+                self.search_oam();
+            }
+            for x in 0..160 {
+                self.engine.place_pixel(&self.data, x, y);
+            }
+            if self.data.lcd.window_on_line {
+                self.data.lcd.window_line += 1;
+            }
+        }
+
+        self.data.lcd.ly = ly;
+        self.data.lcd.window_line = window_line;
+        self.data.lcd.window_on_line = window_on_line;
+        self.data.scanline_objects = scanline_objects;
+        self.engine.screen().to_vec()
+    }
+
+    fn set_layer_enabled(&mut self, layer: Layer, enabled: bool) {
+        self.data.layers_enabled[layer as usize] = enabled;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::NoGuiEngine;
+
+    /// Counts the dots of mode 3 on the first line, with some sprites on that line and the given
+    /// registers written after the LCD is switched on.
+    fn mode3_dots(sprites: u8, registers: &[(Address, u8)]) -> u32 {
+        let gameboy = GameBoyState::new_raw(&[0x18, 0xfe], 0xc000, 0xc000).unwrap();
+        let mut ppu = gameboy.ppu.borrow_mut();
+        for i in 0..sprites {
+            ppu.write(0xfe00 + 4 * usize::from(i), &[16, 8 + 8 * i, 0, 0])
+                .unwrap();
+        }
+        // LCD and sprites on
+        ppu.write_u8(0xff40, 0x82).unwrap();
+        for (address, value) in registers {
+            ppu.write_u8(*address, *value).unwrap();
+        }
+
+        let mut dots = 0;
+        for _ in 0..456 {
+            ppu.step(&gameboy).unwrap();
+            if ppu.vram_blocked() {
+                dots += 1;
+            }
+        }
+        dots
+    }
+
+    #[test]
+    fn test_cycles_until_next_interrupt_from_start_of_frame() {
+        let (gameboy, _) = GameBoyState::new_headless();
+        let ppu = gameboy.ppu.clone();
+        let interrupt_flag = || gameboy.memory_bus.borrow_mut().read_u8(0xff0f).unwrap() & 0x1f;
+        ppu.borrow_mut().write_u8(0xff40, 0x00).unwrap();
+        assert_eq!(None, ppu.borrow().cycles_until_next_interrupt());
+
+        // LCD on, with the LYC source firing at the start of line 2
+        ppu.borrow_mut().write_u8(0xff40, 0x80).unwrap();
+        ppu.borrow_mut().write_u8(0xff41, 0x40).unwrap();
+        ppu.borrow_mut().write_u8(0xff45, 2).unwrap();
+        assert_eq!(Some(2 * 456), ppu.borrow().cycles_until_next_interrupt());
+
+        // Without STAT sources the only interrupt is VBlank, after 144 lines
+        ppu.borrow_mut().write_u8(0xff41, 0x00).unwrap();
+        let cycles = ppu.borrow().cycles_until_next_interrupt().unwrap();
+        assert_eq!(144 * 456, cycles);
+        for _ in 1..cycles {
+            ppu.borrow_mut().step(&gameboy).unwrap();
+        }
+        assert_eq!(0, interrupt_flag());
+        ppu.borrow_mut().step(&gameboy).unwrap();
+        assert_eq!(0b1, interrupt_flag());
+    }
+
+    #[test]
+    fn test_sprites_lengthen_mode3() {
+        assert_eq!(172, mode3_dots(0, &[]));
+        assert_eq!(172 + 10 * 6, mode3_dots(10, &[]));
+        // Only 10 sprites are fetched per line
+        assert_eq!(172 + 10 * 6, mode3_dots(12, &[]));
+    }
+
+    #[test]
+    fn test_window_lengthens_mode3() {
+        // LCD, window, background and sprites on, with the window at the top left
+        let window = [(0xff40, 0xa3), (0xff4a, 0), (0xff4b, 7)];
+        assert_eq!(172 + 6, mode3_dots(0, &window));
+        // Starting the window on a later line doesn't affect this one
+        let later_window = [(0xff40, 0xa3), (0xff4a, 1), (0xff4b, 7)];
+        assert_eq!(172, mode3_dots(0, &later_window));
+    }
+
+    #[test]
+    fn test_fine_scroll_lengthens_mode3() {
+        // The pixels scrolled off by SCX modulo 8 are fetched and thrown away
+        assert_eq!(172 + 3, mode3_dots(0, &[(0xff43, 3)]));
+        assert_eq!(172 + 7, mode3_dots(0, &[(0xff43, 15)]));
+        assert_eq!(172, mode3_dots(0, &[(0xff43, 8)]));
+    }
+
+    #[test]
+    fn test_oam_search_finishes_before_mode3() {
+        let (gameboy, _) = GameBoyState::new_headless();
+        let ppu = gameboy.ppu.clone();
+        let mut ppu = ppu.borrow_mut();
+        // Tile 1 is entirely color 3, used by a sprite covering x=0..8 on line 0
+        for row in 0..8 {
+            ppu.write(0x8010 + row * 2, &[0xff, 0xff]).unwrap();
+        }
+        ppu.write(0xfe00, &[16, 8, 1, 0]).unwrap();
+        // LCD and sprites on
+        ppu.write_u8(0xff40, 0x82).unwrap();
+        ppu.write_u8(0xff48, 0xe4).unwrap();
+
+        while !ppu.vram_blocked() {
+            ppu.step(&gameboy).unwrap();
+        }
+        assert_eq!(1, ppu.scanline_sprites().len());
+
+        // Moving the sprite during mode 3 doesn't change what's drawn on this line
+        ppu.write_u8(0xfe01, 88).unwrap();
+        for _ in 0..456 {
+            ppu.step(&gameboy).unwrap();
+        }
+        let screen = ppu.screen();
+        assert!(screen[0..8].iter().all(|c| *c == TileColor::Black));
+        assert!(screen[80..88].iter().all(|c| *c == TileColor::White));
+    }
+
+    /// A headless engine which renders the background from a cache of decoded tiles, so it only
+    /// works if its cache is kept in sync with VRAM.
+    struct TileCacheEngine {
+        tiles: Vec<[u8; 64]>,
+        screen: Vec<TileColor>,
+    }
+
+    impl GraphicsEngine for TileCacheEngine {
+        fn after_write(&mut self, data: &PpuData, address: Address) {
+            if let 0x8000..=0x97ff = address {
+                let tile_number = (address - 0x8000) / 16;
+                for row in 0..8 {
+                    for col in 0..8 {
+                        self.tiles[tile_number][row * 8 + col] =
+                            data.get_tile_pixel(tile_number, row, col);
+                    }
+                }
+            }
+        }
+
+        fn place_pixel(&mut self, data: &PpuData, x: u8, y: u8) {
+            let (x, y) = (usize::from(x), usize::from(y));
+            let tile_number = data.background_map()[(y / 8) * 32 + x / 8];
+            let color_index = self.tiles[usize::from(tile_number)][(y % 8) * 8 + x % 8];
+            self.screen[y * 160 + x] = TileColor::from_palette(data.lcd.bgp, color_index);
+        }
+
+        fn screen(&self) -> &[TileColor] {
+            &self.screen
+        }
+
+        fn reset(&mut self) {
+            self.screen.fill(TileColor::White);
+        }
+    }
+
+    #[test]
+    fn test_set_engine_rebuilds_cache_from_vram() {
+        let mut ppu = BasePpu::new(Box::new(NoGuiEngine::new()));
+
+        // Tile 1 is entirely color 3, and fills the first column of the background
+        for row in 0..8 {
+            ppu.write(0x8010 + row * 2, &[0xff, 0xff]).unwrap();
+        }
+        ppu.write_u8(0x9800, 1).unwrap();
+        ppu.write_u8(0xff40, 0x91).unwrap();
+        ppu.write_u8(0xff47, 0xe4).unwrap();
+
+        ppu.set_engine(Box::new(TileCacheEngine {
+            tiles: vec![[0; 64]; 384],
+            screen: vec![TileColor::White; 160 * 144],
+        }));
+
+        let (data, engine) = ppu.split_mut();
+        for x in 0..16 {
+            engine.place_pixel(data, x, 0);
+        }
+        let screen = ppu.engine().screen();
+        assert!(screen[0..8].iter().all(|c| *c == TileColor::Black));
+        assert!(screen[8..16].iter().all(|c| *c == TileColor::White));
+    }
+
+    #[test]
+    fn test_cgb_bg_attributes_flip_tiles_and_set_priority() {
+        let mut ppu = BasePpu::new(Box::new(NoGuiEngine::new()));
+        ppu.set_cgb_mode(true);
+        // 0x8000 tile data. Tile 1's only set pixel is color 3 at the top left.
+        ppu.write_u8(0xff40, 0x91).unwrap();
+        ppu.write(0x8010, &[0x80, 0x80]).unwrap();
+        ppu.write(0x9800, &[1, 1, 1]).unwrap();
+
+        // In bank 1, the first three map entries are flipped horizontally, vertically, and given
+        // priority over sprites
+        ppu.write_u8(0xff4f, 1).unwrap();
+        assert_eq!(0xff, ppu.read_u8(0xff4f).unwrap());
+        ppu.write(0x9800, &[0x20, 0x40, 0x80]).unwrap();
+        ppu.write_u8(0xff4f, 0).unwrap();
+        // Bank 0 is untouched
+        assert_eq!(1, ppu.read_u8(0x9800).unwrap());
+
+        let data = ppu.data();
+        assert_eq!((0, false), data.get_bg_pixel(0, 0));
+        assert_eq!((3, false), data.get_bg_pixel(7, 0));
+        assert_eq!((0, false), data.get_bg_pixel(8, 0));
+        assert_eq!((3, false), data.get_bg_pixel(8, 7));
+        assert_eq!((3, true), data.get_bg_pixel(16, 0));
+    }
+}
@@ -1,8 +1,8 @@
-use crate::component::{Address, Addressable, ElapsedTime, Steppable};
+use crate::component::Address;
 use crate::error::{Error, Result};
-use crate::gameboy::GameBoyState;
-use crate::ppu::{lcd, OamData, Ppu, TileDataAddressingMethod};
-use log::*;
+use crate::ppu::{
+    GraphicsEngine, NoGuiEngine, OamData, Palette, PpuData, TileColor, TileDataAddressingMethod,
+};
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::rect::Rect;
 use sdl2::render::{RenderTarget, Texture, TextureCreator};
@@ -16,58 +16,45 @@ impl Tile {
         Tile(vec![0; 64])
     }
 
-    fn as_rgba(&self) -> Vec<u8> {
+    /// Pixel data for an RGBA8888 texture. Textures store each pixel as a native endian u32, so
+    /// on little endian machines the bytes come out as ABGR.
+    fn as_rgba(&self, palette: &Palette) -> Vec<u8> {
         let mut color_data = vec![0; 64 * 4];
         for (i, pixel) in self.0.iter().enumerate() {
-            let rgba = match pixel {
-                0 => [255, 255, 255, 255],
-                1 => [255, 200, 200, 200],
-                2 => [255, 100, 100, 100],
-                3 => [255, 0, 0, 0],
-                _ => panic!(),
-            };
-            color_data[i * 4..(i + 1) * 4].copy_from_slice(&rgba);
+            let rgba = u32::from_be_bytes(palette.rgba_index(*pixel));
+            color_data[i * 4..(i + 1) * 4].copy_from_slice(&rgba.to_ne_bytes());
         }
         color_data
     }
 
-    fn as_oam_rgba(&self) -> Vec<u8> {
-        let mut color_data = vec![0; 64 * 4];
+    /// Like `as_rgba`, but color index 0 is transparent as it is for sprites.
+    fn as_oam_rgba(&self, palette: &Palette) -> Vec<u8> {
+        let mut color_data = self.as_rgba(palette);
         for (i, pixel) in self.0.iter().enumerate() {
-            let rgba = match pixel {
-                0 => [0, 0, 0, 0],
-                1 => [255, 200, 200, 200],
-                2 => [255, 100, 100, 100],
-                3 => [255, 0, 0, 0],
-                _ => panic!(),
-            };
-            color_data[i * 4..(i + 1) * 4].copy_from_slice(&rgba);
+            if *pixel == 0 {
+                color_data[i * 4..(i + 1) * 4].fill(0);
+            }
         }
         color_data
     }
 }
 
-/// The PPU is responsible for the emulated gameboy's graphics.
-pub struct CanvasPpu {
+/// A graphics engine which additionally renders VRAM to SDL textures for debugging views.
+pub struct CanvasEngine {
     tile_map: Texture,
     oam_tile_map: Texture,
 
-    /// Tile data takes up addresses 0x8000-0x97ff.
-    tile_data: Vec<u8>,
-
     /// Cache of decoded tile data -- the gameboy can store 384 different tiles
     tile_cache: Vec<Tile>,
-    /// Addresses 0x9800-0x9bff are a 32x32 map of background tiles.
-    /// Each byte contains the number of a tile to be displayed.
-    background_map: Vec<u8>,
 
-    /// A table containing data for 40 sprites
-    sprite_tiles_table: Vec<u8>,
+    /// Draws the actual screen
+    screen_engine: NoGuiEngine,
 
-    lcd: lcd::Lcd,
+    /// Colors the tile textures are drawn in
+    palette: Palette,
 }
 
-impl CanvasPpu {
+impl CanvasEngine {
     pub fn new(creator: &TextureCreator<WindowContext>) -> Self {
         let tile_map = creator
             .create_texture_target(PixelFormatEnum::RGBA8888, 128, 192)
@@ -76,23 +63,20 @@ impl CanvasPpu {
             .create_texture_target(PixelFormatEnum::RGBA8888, 128, 192)
             .unwrap();
 
-        let ppu = CanvasPpu {
+        CanvasEngine {
             tile_map,
             oam_tile_map,
 
-            tile_data: vec![0; 0x1800],
             // The gameboy has room for 384 tiles in addresses 0x8000 to 0x97ff
             tile_cache: vec![Tile::new(); 384],
-            background_map: vec![0; 32 * 32],
-            sprite_tiles_table: vec![0; 160],
-            lcd: lcd::Lcd::new(),
-        };
-        ppu
+            screen_engine: NoGuiEngine::new(),
+            palette: Palette::default(),
+        }
     }
 
     /// Update the cached forwards and backwards tile data associated with this memory address.
     /// Called after a write to tile data to keep caches valid.
-    fn update_tile_cache(&mut self, address: Address) {
+    fn update_tile_cache(&mut self, tile_data: &[u8], address: Address) {
         // Translate the address into a relative address from 0x8000
         let address = address - 0x8000;
 
@@ -112,11 +96,11 @@ impl CanvasPpu {
         let byte_1;
         let byte_2;
         if address % 2 == 0 {
-            byte_1 = self.tile_data[address];
-            byte_2 = self.tile_data[address + 1];
+            byte_1 = tile_data[address];
+            byte_2 = tile_data[address + 1];
         } else {
-            byte_1 = self.tile_data[address - 1];
-            byte_2 = self.tile_data[address];
+            byte_1 = tile_data[address - 1];
+            byte_2 = tile_data[address];
         }
 
         for i in 0..8 {
@@ -126,19 +110,25 @@ impl CanvasPpu {
             row_to_update[7 - i] = color_id;
         }
 
+        self.update_tile_textures(tile_index);
+    }
+
+    /// Redraws one tile of the tile map textures from the tile cache.
+    fn update_tile_textures(&mut self, tile_index: usize) {
+        let tile = &self.tile_cache[tile_index];
         let x = (tile_index % 16) * 8;
         let y = tile_index / 16 * 8;
         self.tile_map
             .update(
                 Some(Rect::new(x as i32, y as i32, 8, 8)),
-                &tile.as_rgba(),
+                &tile.as_rgba(&self.palette),
                 8 * 4,
             )
             .unwrap();
         self.oam_tile_map
             .update(
                 Some(Rect::new(x as i32, y as i32, 8, 8)),
-                &tile.as_oam_rgba(),
+                &tile.as_oam_rgba(&self.palette),
                 8 * 4,
             )
             .unwrap();
@@ -146,16 +136,7 @@ impl CanvasPpu {
 
     /// Uses the tile addressing method to adjust the provided index so it can be used with the tile cache.
     pub fn adjust_tile_index(&self, tile_index: usize, method: TileDataAddressingMethod) -> usize {
-        match method {
-            TileDataAddressingMethod::Method8000 => tile_index,
-            TileDataAddressingMethod::Method8800 => {
-                if tile_index <= 127 {
-                    tile_index + 256
-                } else {
-                    tile_index
-                }
-            }
-        }
+        method.adjust_tile_index(tile_index)
     }
 
     pub fn set_tile(
@@ -216,43 +197,6 @@ impl CanvasPpu {
             .map_err(|e| Error::new(&e.to_string()))
     }
 
-    fn _read(&mut self, address: Address) -> Result<u8> {
-        let value = match address {
-            0x8000..=0x97ff => self.tile_data[address - 0x8000],
-            0x9800..=0x9bff => self.background_map[address - 0x9800],
-            0xfe00..=0xfe9f => self.sprite_tiles_table[address - 0xfe00],
-            0xff40 => self.lcd.lcd_control.read(),
-            0xff41 => self.lcd.stat.0,
-            0xff44 => self.lcd.ly,
-            0xff45 => self.lcd.lyc,
-            _ => return Err(Error::new("Invalid address")),
-        };
-
-        Ok(value)
-    }
-
-    fn _write(&mut self, address: Address, data: u8) -> Result<()> {
-        match address {
-            0x8000..=0x97ff => {
-                trace!("write to tile data: {:#x} into {:#x}", data, address);
-                self.tile_data[address - 0x8000] = data;
-                self.update_tile_cache(address);
-            }
-            0x9800..=0x9bff => {
-                self.background_map[address - 0x9800] = data;
-            }
-            0xfe00..=0xfe9f => {
-                self.sprite_tiles_table[address - 0xfe00] = data;
-            }
-            0xff40 => self.lcd.lcd_control.write(data),
-            0xff41 => self.lcd.stat.0 = data,
-            0xff45 => self.lcd.lyc = data,
-            _ => return Err(Error::new("Invalid address")),
-        }
-
-        Ok(())
-    }
-
     pub fn render_tile_map<T: RenderTarget>(
         &mut self,
         texture_canvas: &mut sdl2::render::Canvas<T>,
@@ -264,9 +208,10 @@ impl CanvasPpu {
 
     pub fn render_background_map(
         &mut self,
+        data: &PpuData,
         texture_canvas: &mut sdl2::render::Canvas<Window>,
     ) -> Result<()> {
-        let method = if self.lcd.lcd_control.bg_window_tile_data_area {
+        let method = if data.lcd.lcd_control.bg_window_tile_data_area {
             TileDataAddressingMethod::Method8000
         } else {
             TileDataAddressingMethod::Method8800
@@ -276,7 +221,7 @@ impl CanvasPpu {
         // Render background map
         for row in 0..32 {
             for col in 0..32 {
-                let tile_number = self.background_map[col + row * 32];
+                let tile_number = data.background_map()[col + row * 32];
                 self.set_tile(texture_canvas, row, col, tile_number.into(), method)?;
             }
         }
@@ -286,12 +231,13 @@ impl CanvasPpu {
 
     pub fn render_sprites(
         &mut self,
+        data: &PpuData,
         texture_canvas: &mut sdl2::render::Canvas<Window>,
     ) -> Result<()> {
         for i in 0..40 {
-            let oam_data = OamData::new(&self.sprite_tiles_table[i * 4..i * 4 + 4]);
+            let oam_data = OamData::new(&data.sprite_tiles_table[i * 4..i * 4 + 4]);
 
-            if !self.lcd.lcd_control.obj_size {
+            if !data.lcd.lcd_control.obj_size {
                 // 8x8
                 self.set_sprite(texture_canvas, &oam_data, 0, 0)?;
             } else {
@@ -310,28 +256,29 @@ impl CanvasPpu {
     }
 }
 
-impl Steppable for CanvasPpu {
-    fn step(&mut self, state: &GameBoyState) -> Result<ElapsedTime> {
-        self.lcd.step(state)
+impl GraphicsEngine for CanvasEngine {
+    fn after_write(&mut self, data: &PpuData, address: Address) {
+        if let 0x8000..=0x97ff = address {
+            self.update_tile_cache(data.tile_data(), address);
+        }
     }
-}
 
-impl Addressable for CanvasPpu {
-    fn read(&mut self, address: Address, data: &mut [u8]) -> Result<()> {
-        for (offset, byte) in data.iter_mut().enumerate() {
-            *byte = self._read(address + offset)?;
+    fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        for tile_index in 0..self.tile_cache.len() {
+            self.update_tile_textures(tile_index);
         }
+    }
 
-        Ok(())
+    fn place_pixel(&mut self, data: &PpuData, x: u8, y: u8) {
+        self.screen_engine.place_pixel(data, x, y);
     }
 
-    fn write(&mut self, address: Address, data: &[u8]) -> Result<()> {
-        for (offset, byte) in data.iter().enumerate() {
-            self._write(address + offset, *byte)?;
-        }
+    fn screen(&self) -> &[TileColor] {
+        self.screen_engine.screen()
+    }
 
-        Ok(())
+    fn reset(&mut self) {
+        self.screen_engine.reset();
     }
 }
-
-impl Ppu for CanvasPpu {}
@@ -3,10 +3,14 @@ use crate::error::Result;
 use crate::gameboy::GameBoyState;
 use crate::gameboy::Interrupt;
 use crate::utils::BitField;
+use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 
+/// LY counts 144 visible lines followed by 10 lines of VBlank
+const LINES_PER_FRAME: u8 = 154;
+
 /// Represents the LCD Control register at 0xff40
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct LcdControl {
     pub bg_window_enable: bool,
     pub obj_enable: bool,
@@ -33,14 +37,14 @@ impl LcdControl {
     }
 
     pub fn read(&self) -> u8 {
-        (self.bg_window_enable as u8) + (self.obj_enable as u8)
-            << 1 + (self.obj_size as u8)
-            << 2 + (self.bg_tile_map_area as u8)
-            << 3 + (self.bg_window_tile_data_area as u8)
-            << 4 + (self.window_enable as u8)
-            << 5 + (self.window_tile_map_area as u8)
-            << 6 + (self.lcd_ppu_enable as u8)
-            << 7
+        (self.bg_window_enable as u8)
+            | (self.obj_enable as u8) << 1
+            | (self.obj_size as u8) << 2
+            | (self.bg_tile_map_area as u8) << 3
+            | (self.bg_window_tile_data_area as u8) << 4
+            | (self.window_enable as u8) << 5
+            | (self.window_tile_map_area as u8) << 6
+            | (self.lcd_ppu_enable as u8) << 7
     }
 
     pub fn write(&mut self, value: u8) {
@@ -55,14 +59,14 @@ impl LcdControl {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct PixelData {
     color: u8,
     palette: u8,
     background_priority: bool,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 enum PpuState {
     OamSearch,
     PixelTransfer,
@@ -70,13 +74,34 @@ enum PpuState {
     HBlank,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Lcd {
     /// LY: LCD Y coordinate (read only)
     pub ly: u8,
     /// LYC: LY compare
     pub lyc: u8,
+    /// SCY: Background viewport Y position
+    pub scy: u8,
+    /// SCX: Background viewport X position
+    pub scx: u8,
+    /// BGP: Background palette data
+    pub bgp: u8,
+    /// OBP0: Object palette 0 data
+    pub obp0: u8,
+    /// OBP1: Object palette 1 data
+    pub obp1: u8,
+    /// WY: Window Y position
+    pub wy: u8,
+    /// WX: Window X position plus 7
+    pub wx: u8,
     /// Current x position in scanline
     pub scan_x: u32,
+    /// Internal window line counter: the row of the window drawn on the current scanline. It
+    /// only advances on lines where the window is drawn, so it survives the window being turned
+    /// off and on again, and only resets at the start of a frame.
+    pub window_line: u8,
+    /// True iff the window is drawn on the current scanline
+    pub(super) window_on_line: bool,
     pub lcd_control: LcdControl,
     pub stat: BitField,
     stat_interrupt_line: [bool; 4],
@@ -86,28 +111,202 @@ pub struct Lcd {
 
     state: PpuState,
     dots: u32,
+    /// Dots left before mode 3 starts pushing pixels, while the pixel fetcher is busy
+    pixel_transfer_stall: u32,
+    /// Number of frames completed, counted each time VBlank starts
+    pub frame_count: u64,
+    /// Set on entering HBlank, until taken by `take_hblank_started`
+    hblank_started: bool,
+    /// Dots run while the LCD is off. Frames are still counted every 70224 dots, so frontends
+    /// keep their pace, but no VBlank is requested.
+    off_dots: u32,
 }
 
 impl Lcd {
     pub fn new() -> Lcd {
+        // The boot ROM leaves the LCD on, showing the background from 0x8000 tile data. When
+        // there is a boot ROM to run, `MemoryBus` switches it back off.
+        let mut lcd_control = LcdControl::new();
+        lcd_control.write(0x91);
         Lcd {
             ly: 0,
             lyc: 0,
+            scy: 0,
+            scx: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
             scan_x: 0,
-            lcd_control: LcdControl::new(),
+            window_line: 0,
+            window_on_line: false,
+            lcd_control,
             stat: BitField(0),
             stat_interrupt_line: [false; 4],
             background_queue: VecDeque::new(),
             sprite_queue: VecDeque::new(),
             state: PpuState::OamSearch,
             dots: 0,
+            pixel_transfer_stall: 0,
+            frame_count: 0,
+            hblank_started: false,
+            off_dots: 0,
         }
     }
 }
 
 impl Lcd {
+    /// The current mode as numbered in STAT: 0 for HBlank, 1 for VBlank, 2 for OAM search and 3
+    /// for pixel transfer.
+    pub fn mode(&self) -> u8 {
+        match self.state {
+            PpuState::HBlank => 0,
+            PpuState::VBlank => 1,
+            PpuState::OamSearch => 2,
+            PpuState::PixelTransfer => 3,
+        }
+    }
+
+    /// STAT as the CPU reads it: the interrupt source selection bits along with the LYC=LY flag
+    /// and the current mode, which is 0 while the LCD is off.
+    pub fn stat(&self) -> u8 {
+        let coincidence = if self.ly == self.lyc { 0b100 } else { 0 };
+        (self.stat.0 & 0b0111_1000) | coincidence | self.mode()
+    }
+
+    /// Writes LCDC. Switching the LCD off resets LY to 0 and holds the PPU in mode 0, and
+    /// switching it back on starts a new frame from the OAM search of line 0.
+    pub fn write_lcdc(&mut self, value: u8) {
+        let was_enabled = self.lcd_control.lcd_ppu_enable;
+        self.lcd_control.write(value);
+        match (was_enabled, self.lcd_control.lcd_ppu_enable) {
+            (true, false) => {
+                self.ly = 0;
+                self.window_line = 0;
+                self.window_on_line = false;
+                self.scan_x = 0;
+                self.dots = 0;
+                self.pixel_transfer_stall = 0;
+                self.state = PpuState::HBlank;
+                self.stat_interrupt_line = [false; 4];
+                self.off_dots = 0;
+            }
+            (false, true) => {
+                self.dots = 0;
+                self.state = PpuState::OamSearch;
+                self.stat_interrupt_line[3] = self.ly == self.lyc && self.stat.get_bit(6).unwrap();
+            }
+            _ => {}
+        }
+    }
+
+    /// Dots elapsed since the start of the current line.
+    pub fn line_dots(&self) -> u32 {
+        self.dots
+    }
+
+    /// True iff the PPU is currently in mode 3, pushing pixels to the screen
+    pub fn transferring_pixels(&self) -> bool {
+        self.state == PpuState::PixelTransfer
+    }
+
+    /// True iff the PPU will push the pixel at `scan_x` this dot, i.e. mode 3 isn't stalled
+    pub fn pushing_pixels(&self) -> bool {
+        self.transferring_pixels() && self.pixel_transfer_stall == 0
+    }
+
+    /// Delays the rest of mode 3 by some dots, which lengthens it and shortens the HBlank after.
+    pub fn stall_pixel_transfer(&mut self, dots: u32) {
+        self.pixel_transfer_stall += dots;
+    }
+
+    /// True iff the window is drawn on the current scanline. Decided at the start of mode 3.
+    pub fn window_on_line(&self) -> bool {
+        self.window_on_line
+    }
+
+    /// True iff the current registers put the window on the given line.
+    pub(super) fn window_visible_on(&self, ly: u8) -> bool {
+        // On the DMG, clearing LCDC bit 0 hides the window as well as the background
+        self.lcd_control.window_enable
+            && self.lcd_control.bg_window_enable
+            && ly >= self.wy
+            && self.wx <= 166
+    }
+
+    /// Dots mode 3 is stalled for before the first pixel is pushed, from everything but sprites.
+    /// The first tile fetch takes 12 dots, the pixels scrolled off by SCX are fetched and thrown
+    /// away, and the fetcher restarts when the window starts on the line.
+    fn pixel_transfer_start_stall(&self, ly: u8) -> u32 {
+        12 + u32::from(self.scx % 8) + if self.window_visible_on(ly) { 6 } else { 0 }
+    }
+
+    /// Dots until the next VBlank or STAT interrupt, assuming no registers are written in the
+    /// meantime. `sprite_stall` gives the dots sprites add to mode 3 on a line. STAT blocking is
+    /// ignored, so an enabled source counts even if another source holds the line high.
+    pub fn dots_until_next_interrupt(&self, sprite_stall: impl Fn(u8) -> u32) -> u64 {
+        let hblank_enabled = self.stat.get_bit(3).unwrap();
+        let oam_enabled = self.stat.get_bit(5).unwrap();
+        let lyc_enabled = self.stat.get_bit(6).unwrap();
+
+        // Events on a line happen when its dot counter reaches some value, which for the current
+        // line has already reached `self.dots`
+        let mut line_start = -i64::from(self.dots);
+        let mut ly = self.ly;
+        loop {
+            if ly < 144 && hblank_enabled {
+                let hblank = if ly == self.ly && self.state == PpuState::PixelTransfer {
+                    i64::from(self.pixel_transfer_stall + 160 - self.scan_x)
+                } else {
+                    let mode3 = self.pixel_transfer_start_stall(ly) + sprite_stall(ly) + 160;
+                    line_start + 80 + i64::from(mode3)
+                };
+                if hblank > 0 {
+                    return hblank as u64;
+                }
+            }
+
+            // Changes at the end of the line, when the dot counter reaches 456
+            let line_end = (line_start + 456) as u64;
+            ly = (ly + 1) % LINES_PER_FRAME;
+            // VBlank always interrupts, as does its STAT source if enabled
+            if ly == 144 || (ly == self.lyc && lyc_enabled) {
+                return line_end;
+            }
+            if ly < 144 && oam_enabled {
+                return line_end;
+            }
+            line_start += 456;
+        }
+    }
+
+    /// True iff HBlank has started since the last call. HBlank DMA copies a block at this
+    /// moment, which is also when the HBlank STAT interrupt is requested.
+    pub fn take_hblank_started(&mut self) -> bool {
+        std::mem::take(&mut self.hblank_started)
+    }
+
+    /// Moves from pixel transfer into HBlank, the one place both HBlank's STAT interrupt source
+    /// and HBlank DMA are triggered from.
+    fn enter_hblank(&mut self) -> Option<Interrupt> {
+        self.hblank_started = true;
+        self.change_state(PpuState::HBlank)
+    }
+
+    /// True iff the CPU is currently unable to access VRAM, which happens during mode 3
+    pub fn vram_blocked(&self) -> bool {
+        self.lcd_control.lcd_ppu_enable && self.state == PpuState::PixelTransfer
+    }
+
+    /// True iff the CPU is currently unable to access OAM, which happens during modes 2 and 3
+    pub fn oam_blocked(&self) -> bool {
+        self.lcd_control.lcd_ppu_enable
+            && matches!(self.state, PpuState::OamSearch | PpuState::PixelTransfer)
+    }
+
     fn increment_ly(&mut self) -> Option<Interrupt> {
-        self.ly += 1;
+        self.ly = (self.ly + 1) % LINES_PER_FRAME;
         if self.ly == self.lyc && self.stat.get_bit(6).unwrap() {
             self.update_stat_interrupt_line(3, true)
         } else {
@@ -118,62 +317,65 @@ impl Lcd {
     fn change_state(&mut self, new_state: PpuState) -> Option<Interrupt> {
         self.state = new_state;
 
-        self.update_stat_interrupt_line(
-            0,
-            self.stat.get_bit(3).unwrap() && new_state == PpuState::HBlank,
-        )
-        .and(self.update_stat_interrupt_line(
-            1,
-            self.stat.get_bit(4).unwrap() && new_state == PpuState::VBlank,
-        ))
-        .and(self.update_stat_interrupt_line(
-            2,
-            self.stat.get_bit(5).unwrap() && new_state == PpuState::OamSearch,
-        ))
+        // All three mode sources change at once, so that moving straight from one enabled mode
+        // into another (e.g. HBlank into OAM search) keeps the line high and doesn't interrupt
+        let old_line_value = self.stat_line();
+        self.stat_interrupt_line[0] =
+            self.stat.get_bit(3).unwrap() && new_state == PpuState::HBlank;
+        self.stat_interrupt_line[1] =
+            self.stat.get_bit(4).unwrap() && new_state == PpuState::VBlank;
+        self.stat_interrupt_line[2] =
+            self.stat.get_bit(5).unwrap() && new_state == PpuState::OamSearch;
+
+        self.stat_interrupt_if_rising(old_line_value)
     }
 
     fn update_stat_interrupt_line(&mut self, index: usize, value: bool) -> Option<Interrupt> {
-        if self.stat_interrupt_line[index] == value {
-            return None;
-        }
+        let old_line_value = self.stat_line();
+        self.stat_interrupt_line[index] = value;
+        self.stat_interrupt_if_rising(old_line_value)
+    }
+
+    /// The STAT interrupt line is the OR of every enabled source.
+    fn stat_line(&self) -> bool {
+        self.stat_interrupt_line.iter().any(|source| *source)
+    }
 
-        if value {
-            self.stat_interrupt_line[index] = value;
-            return None;
+    /// A STAT interrupt is only requested when the line goes from low to high, so a source
+    /// becoming active while another is still active doesn't interrupt again ("STAT blocking").
+    fn stat_interrupt_if_rising(&self, old_line_value: bool) -> Option<Interrupt> {
+        if !old_line_value && self.stat_line() {
+            Some(Interrupt::Stat)
         } else {
-            // Or all the stat interrupt line values together
-            let old_line_value = self
-                .stat_interrupt_line
-                .iter()
-                .fold(false, |acc, e| acc | e);
-            self.stat_interrupt_line[index] = value;
-            let new_line_value = self
-                .stat_interrupt_line
-                .iter()
-                .fold(false, |acc, e| acc | e);
-
-            // Since the `value` parameter went from low to high here,
-            // if the old line value is different from the new line value
-            // then it also went from low to high and we should send an interrupt
-            if old_line_value != new_line_value {
-                Some(Interrupt::Stat)
-            } else {
-                None
-            }
+            None
         }
     }
 }
 
 impl Steppable for Lcd {
     fn step(&mut self, state: &GameBoyState) -> Result<ElapsedTime> {
+        if !self.lcd_control.lcd_ppu_enable {
+            self.off_dots += 1;
+            if self.off_dots == 70224 {
+                self.off_dots = 0;
+                self.frame_count += 1;
+            }
+            return Ok(1);
+        }
+
         self.dots += 1;
 
         match self.state {
             PpuState::OamSearch => {
                 if self.dots == 80 {
                     self.change_state(PpuState::PixelTransfer);
+                    self.window_on_line = self.window_visible_on(self.ly);
+                    self.pixel_transfer_stall = self.pixel_transfer_start_stall(self.ly);
                 }
             }
+            PpuState::PixelTransfer if self.pixel_transfer_stall > 0 => {
+                self.pixel_transfer_stall -= 1;
+            }
             PpuState::PixelTransfer => {
                 // TODO: Fetch pixel data into our pixel FIFO.
                 // TODO: Put a pixel (if any) from the FIFO on screen.
@@ -187,7 +389,7 @@ impl Steppable for Lcd {
                 self.scan_x += 1;
                 if self.scan_x == 160 {
                     self.scan_x = 0;
-                    if let Some(interrupt) = self.change_state(PpuState::HBlank) {
+                    if let Some(interrupt) = self.enter_hblank() {
                         state.memory_bus.borrow_mut().interrupt(interrupt)?;
                     }
                 }
@@ -195,10 +397,14 @@ impl Steppable for Lcd {
             PpuState::HBlank => {
                 if self.dots == 456 {
                     self.dots = 0;
+                    if self.window_on_line {
+                        self.window_line += 1;
+                    }
                     if let Some(interrupt) = self.increment_ly() {
                         state.memory_bus.borrow_mut().interrupt(interrupt)?;
                     }
                     if self.ly == 144 {
+                        self.frame_count += 1;
                         if let Some(interrupt) = self.change_state(PpuState::VBlank) {
                             state.memory_bus.borrow_mut().interrupt(interrupt)?;
                         }
@@ -217,8 +423,8 @@ impl Steppable for Lcd {
                     if let Some(interrupt) = self.increment_ly() {
                         state.memory_bus.borrow_mut().interrupt(interrupt)?;
                     }
-                    if self.ly == 153 {
-                        self.ly = 0;
+                    if self.ly == 0 {
+                        self.window_line = 0;
                         //println!("End VBLANK");
                         if let Some(interrupt) = self.change_state(PpuState::OamSearch) {
                             state.memory_bus.borrow_mut().interrupt(interrupt)?;
@@ -231,3 +437,104 @@ impl Steppable for Lcd {
         Ok(1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::LcdControl;
+    use crate::component::Addressable;
+    use crate::gameboy::GameBoyState;
+
+    #[test]
+    fn test_lcd_control_reads_back_what_was_written() {
+        let mut lcd_control = LcdControl::new();
+        for value in 0..=255 {
+            lcd_control.write(value);
+            assert_eq!(value, lcd_control.read());
+        }
+    }
+
+    #[test]
+    fn test_hblank_and_oam_stat_sources_interrupt_once_per_line() {
+        // DI; JR -2
+        let mut gameboy = GameBoyState::new_raw(&[0xf3, 0x18, 0xfe], 0xc000, 0xc000).unwrap();
+        // Enable the HBlank and OAM search STAT sources
+        gameboy
+            .memory_bus
+            .borrow_mut()
+            .write_u8(0xff41, 0x28)
+            .unwrap();
+        gameboy.run_to_frame(1);
+        gameboy.memory_bus.borrow_mut().write_u8(0xff0f, 0).unwrap();
+
+        let mut stat_interrupts = 0;
+        while gameboy.frame_count() < 2 {
+            gameboy.tick();
+            let mut memory_bus = gameboy.memory_bus.borrow_mut();
+            let interrupt_flag = memory_bus.read_u8(0xff0f).unwrap();
+            if interrupt_flag & 0b10 != 0 {
+                stat_interrupts += 1;
+                memory_bus.write_u8(0xff0f, interrupt_flag & !0b10).unwrap();
+            }
+        }
+
+        // Each visible line's HBlank interrupts, but the OAM search which follows it is blocked.
+        // Only line 0's OAM search, coming out of VBlank, interrupts on its own.
+        assert_eq!(145, stat_interrupts);
+    }
+
+    #[test]
+    fn test_lyc_source_interrupts_when_ly_reaches_lyc() {
+        // DI; JR -2
+        let mut gameboy = GameBoyState::new_raw(&[0xf3, 0x18, 0xfe], 0xc000, 0xc000).unwrap();
+        {
+            let mut memory_bus = gameboy.memory_bus.borrow_mut();
+            memory_bus.write_u8(0xff45, 72).unwrap();
+            // Only the LYC=LY source
+            memory_bus.write_u8(0xff41, 0x40).unwrap();
+        }
+        gameboy.run_to_frame(1);
+        gameboy.memory_bus.borrow_mut().write_u8(0xff0f, 0).unwrap();
+
+        let mut interrupt_lines = Vec::new();
+        while gameboy.frame_count() < 2 {
+            gameboy.tick();
+            let mut memory_bus = gameboy.memory_bus.borrow_mut();
+            let interrupt_flag = memory_bus.read_u8(0xff0f).unwrap();
+            if interrupt_flag & 0b10 != 0 {
+                interrupt_lines.push(memory_bus.read_u8(0xff44).unwrap());
+                assert_eq!(0b100, memory_bus.read_u8(0xff41).unwrap() & 0b100);
+                memory_bus.write_u8(0xff0f, interrupt_flag & !0b10).unwrap();
+            }
+        }
+
+        assert_eq!(vec![72], interrupt_lines);
+    }
+
+    #[test]
+    fn test_disabled_lcd_reads_ly_and_mode_as_0() {
+        let gameboy = GameBoyState::new_raw(&[0x18, 0xfe], 0xc000, 0xc000).unwrap();
+        let mut ppu = gameboy.ppu.borrow_mut();
+        ppu.write_u8(0xff45, 0).unwrap();
+
+        // Partway through line 10, in mode 3
+        while ppu.read_u8(0xff44).unwrap() != 10 || ppu.read_u8(0xff41).unwrap() & 0b11 != 3 {
+            ppu.step(&gameboy).unwrap();
+        }
+        assert_eq!(0, ppu.read_u8(0xff41).unwrap() & 0b100);
+
+        // LCD off. LY is 0 and the mode is 0, and LY stays there, matching LYC
+        ppu.write_u8(0xff40, 0x11).unwrap();
+        for _ in 0..1000 {
+            ppu.step(&gameboy).unwrap();
+        }
+        assert_eq!(0, ppu.read_u8(0xff44).unwrap());
+        assert_eq!(0b100, ppu.read_u8(0xff41).unwrap() & 0b111);
+        ppu.write_u8(0xff45, 1).unwrap();
+        assert_eq!(0, ppu.read_u8(0xff41).unwrap() & 0b111);
+
+        // Switching back on starts a frame from line 0's OAM search
+        ppu.write_u8(0xff40, 0x91).unwrap();
+        assert_eq!(0, ppu.read_u8(0xff44).unwrap());
+        assert_eq!(2, ppu.read_u8(0xff41).unwrap() & 0b11);
+    }
+}
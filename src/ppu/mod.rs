@@ -3,14 +3,22 @@
  * representation of the screen.
  */
 
-mod canvas_ppu;
+mod base_ppu;
+mod canvas_engine;
 mod lcd;
-mod no_gui_ppu;
+mod no_gui_engine;
 
-pub use canvas_ppu::CanvasPpu;
-pub use no_gui_ppu::NoGuiPpu;
+pub use base_ppu::{BasePpu, PpuData};
+pub use canvas_engine::CanvasEngine;
+pub use no_gui_engine::NoGuiEngine;
 
-use crate::component::{Addressable, Steppable};
+use std::any::Any;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::component::{Address, Addressable, Steppable};
+use crate::error::{Error, Result};
 
 #[derive(Debug, Clone, Copy)]
 pub enum TileDataAddressingMethod {
@@ -18,7 +26,164 @@ pub enum TileDataAddressingMethod {
     Method8800,
 }
 
-#[derive(Debug, Clone)]
+impl TileDataAddressingMethod {
+    /// Adjusts a tile index read from a tile map so it can be used as an index into the 384 tiles
+    /// stored in tile data.
+    pub fn adjust_tile_index(self, tile_index: usize) -> usize {
+        match self {
+            TileDataAddressingMethod::Method8000 => tile_index,
+            TileDataAddressingMethod::Method8800 => {
+                if tile_index <= 127 {
+                    tile_index + 256
+                } else {
+                    tile_index
+                }
+            }
+        }
+    }
+}
+
+/// A color displayed on the LCD, after palettes have been applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileColor {
+    White,
+    LightGrey,
+    DarkGrey,
+    Black,
+    /// Not a real gameboy color, used to make unexpected pixels stand out
+    Debug,
+}
+
+impl TileColor {
+    /// Looks up a color index (0-3) in a palette register such as BGP, OBP0 or OBP1.
+    pub fn from_palette(palette: u8, color_index: u8) -> TileColor {
+        match (palette >> (2 * color_index)) & 0b11 {
+            0 => TileColor::White,
+            1 => TileColor::LightGrey,
+            2 => TileColor::DarkGrey,
+            3 => TileColor::Black,
+            _ => TileColor::Debug,
+        }
+    }
+
+    /// Looks up a color index (0-3) in one of the eight CGB palettes held in palette RAM. The
+    /// screen only has four shades, so the color is shown as the shade nearest its brightness.
+    pub fn from_cgb_palette(palette_ram: &[u8], palette: u8, color_index: u8) -> TileColor {
+        let offset = usize::from(palette) * 8 + usize::from(color_index) * 2;
+        let (r, g, b) = decode_cgb_color(&palette_ram[offset..offset + 2]);
+        let luminance = (299 * u32::from(r) + 587 * u32::from(g) + 114 * u32::from(b)) / 1000;
+        match luminance {
+            192.. => TileColor::White,
+            128.. => TileColor::LightGrey,
+            64.. => TileColor::DarkGrey,
+            _ => TileColor::Black,
+        }
+    }
+
+    /// Shows `Debug` as White, leaving real colors alone.
+    pub fn hide_debug(self) -> TileColor {
+        match self {
+            TileColor::Debug => TileColor::White,
+            color => color,
+        }
+    }
+}
+
+/// The RGBA colors the four DMG shades are shown as, from White to Black.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    pub colors: [[u8; 4]; 4],
+}
+
+impl Palette {
+    /// Plain shades of grey, the default
+    pub const GREY: Palette = Palette {
+        colors: [
+            [255, 255, 255, 255],
+            [200, 200, 200, 255],
+            [100, 100, 100, 255],
+            [0, 0, 0, 255],
+        ],
+    };
+
+    /// The pea green of the original DMG's screen
+    pub const DMG: Palette = Palette {
+        colors: [
+            [155, 188, 15, 255],
+            [139, 172, 15, 255],
+            [48, 98, 48, 255],
+            [15, 56, 15, 255],
+        ],
+    };
+
+    /// Shades spread further apart than `GREY`, so light and dark grey are easier to tell apart
+    pub const HIGH_CONTRAST: Palette = Palette {
+        colors: [
+            [255, 255, 255, 255],
+            [170, 170, 170, 255],
+            [85, 85, 85, 255],
+            [0, 0, 0, 255],
+        ],
+    };
+
+    /// The RGBA bytes a color is shown as. `TileColor::Debug` is always red.
+    pub fn rgba(&self, color: TileColor) -> [u8; 4] {
+        match color {
+            TileColor::White => self.colors[0],
+            TileColor::LightGrey => self.colors[1],
+            TileColor::DarkGrey => self.colors[2],
+            TileColor::Black => self.colors[3],
+            TileColor::Debug => [255, 0, 0, 255],
+        }
+    }
+
+    /// The RGBA bytes of a color index (0-3) drawn without a palette register.
+    pub fn rgba_index(&self, color_index: u8) -> [u8; 4] {
+        self.colors[usize::from(color_index)]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::GREY
+    }
+}
+
+impl FromStr for Palette {
+    type Err = Error;
+
+    /// Looks up a preset by name: "grey", "dmg" or "high-contrast".
+    fn from_str(name: &str) -> Result<Palette> {
+        match name.to_ascii_lowercase().as_str() {
+            "grey" | "gray" => Ok(Palette::GREY),
+            "dmg" | "green" => Ok(Palette::DMG),
+            "high-contrast" => Ok(Palette::HIGH_CONTRAST),
+            _ => Err(Error::new(&format!("unknown palette {}", name))),
+        }
+    }
+}
+
+/// A layer of the display which can be hidden for debugging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Background,
+    Window,
+    Sprites,
+}
+
+/// The compositing rule between a sprite pixel and the background/window pixel beneath it.
+///
+/// Sprite color index 0 is always transparent. If the sprite's BG-over-OBJ flag is set, the
+/// sprite only shows over background color index 0, while indices 1-3 are drawn on top of it.
+pub fn sprite_has_priority(
+    bg_color_index: u8,
+    obj_color_index: u8,
+    bg_window_over_obj: bool,
+) -> bool {
+    obj_color_index != 0 && (!bg_window_over_obj || bg_color_index == 0)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OamData {
     data: Vec<u8>,
 }
@@ -30,36 +195,224 @@ impl OamData {
         }
     }
 
-    fn y_pos(&self) -> u8 {
+    pub fn y_pos(&self) -> u8 {
         self.data[0]
     }
 
-    fn x_pos(&self) -> u8 {
+    pub fn x_pos(&self) -> u8 {
         self.data[1]
     }
 
-    fn tile_index(&self) -> u8 {
+    pub fn tile_index(&self) -> u8 {
         self.data[2]
     }
 
-    fn palette_number(&self) -> u8 {
+    pub fn palette_number(&self) -> u8 {
         self.data[3] >> 4 & 1
     }
 
+    /// CGB only: which of the eight object palettes (OBP0-7) the sprite uses
+    pub fn cgb_palette_number(&self) -> u8 {
+        self.data[3] & 0b111
+    }
+
+    /// CGB only: which VRAM bank the sprite's tile is fetched from
+    pub fn vram_bank(&self) -> u8 {
+        self.data[3] >> 3 & 1
+    }
+
     /// true iff horizontally mirrored
-    fn x_flip(&self) -> bool {
+    pub fn x_flip(&self) -> bool {
         self.data[3] >> 5 & 1 == 1
     }
 
     /// true iff vertically mirrored
-    fn y_flip(&self) -> bool {
+    pub fn y_flip(&self) -> bool {
         self.data[3] >> 6 & 1 == 1
     }
 
     /// false=No, true=BG and Window colors 1-3 over the OBJ
-    fn bg_window_over_obj(&self) -> bool {
+    pub fn bg_window_over_obj(&self) -> bool {
         self.data[3] >> 7 & 1 == 1
     }
 }
 
-pub trait Ppu: Addressable + Steppable {}
+/// The 8 palettes of 4 colors held in CGB palette RAM, as RGB.
+pub type CgbPalettes = [[(u8, u8, u8); 4]; 8];
+
+/// Decodes 64 bytes of CGB palette RAM, where each color is a little-endian BGR555 value. The
+/// 5 bit components are scaled up to fill 8 bits.
+pub fn decode_cgb_palettes(palette_ram: &[u8]) -> CgbPalettes {
+    let mut palettes = [[(0, 0, 0); 4]; 8];
+    for (i, color) in palette_ram.chunks(2).enumerate() {
+        palettes[i / 4][i % 4] = decode_cgb_color(color);
+    }
+    palettes
+}
+
+/// Decodes one color of CGB palette RAM, see `decode_cgb_palettes`.
+fn decode_cgb_color(color: &[u8]) -> (u8, u8, u8) {
+    let value = u16::from_le_bytes([color[0], color[1]]);
+    let component = |shift: u16| {
+        let c = ((value >> shift) & 0x1f) as u8;
+        (c << 3) | (c >> 2)
+    };
+    (component(0), component(5), component(10))
+}
+
+/// Width of the tile atlas produced by [`render_tile_atlas`], in pixels.
+pub const TILE_ATLAS_WIDTH: usize = 16 * 8;
+/// Height of the tile atlas produced by [`render_tile_atlas`], in pixels.
+pub const TILE_ATLAS_HEIGHT: usize = 24 * 8;
+
+/// Lays out all 384 tiles in tile data as a 128x192 image, 16 tiles wide, matching the tile map
+/// shown in the debug view. Color indices are drawn without applying a palette.
+pub fn render_tile_atlas(tile_data: &[u8]) -> Vec<TileColor> {
+    let mut atlas = vec![TileColor::White; TILE_ATLAS_WIDTH * TILE_ATLAS_HEIGHT];
+    for (tile_index, tile) in tile_data.chunks(16).enumerate() {
+        let tile_x = (tile_index % 16) * 8;
+        let tile_y = tile_index / 16 * 8;
+        for row in 0..8 {
+            for col in 0..8 {
+                let bit_1 = (tile[row * 2] >> (7 - col)) & 1;
+                let bit_2 = (tile[row * 2 + 1] >> (7 - col)) & 1;
+                // An identity palette: 0b11_10_01_00
+                let color = TileColor::from_palette(0xe4, (bit_2 << 1) | bit_1);
+                atlas[(tile_y + row) * TILE_ATLAS_WIDTH + tile_x + col] = color;
+            }
+        }
+    }
+    atlas
+}
+
+pub trait Ppu: Addressable + Steppable {
+    /// Both banks of VRAM (0x8000-0x9fff), always 0x4000 bytes: bank 0 followed by the CGB's
+    /// bank 1, which stays zeroed outside CGB mode.
+    fn vram(&self) -> &[u8];
+
+    /// The raw tile data stored in 0x8000-0x97ff.
+    fn tile_data(&self) -> &[u8];
+
+    /// The two background tile maps stored in 0x9800-0x9fff.
+    fn background_map(&self) -> &[u8];
+
+    /// The raw sprite attribute table stored in OAM, 0xfe00-0xfe9f.
+    fn oam(&self) -> &[u8];
+
+    /// The (up to 10) sprites selected by OAM search for the current line, highest priority
+    /// first.
+    fn scanline_sprites(&self) -> Vec<OamData>;
+
+    /// True iff the PPU's current mode prevents the CPU from accessing VRAM.
+    fn vram_blocked(&self) -> bool;
+
+    /// True iff the PPU's current mode prevents the CPU from accessing OAM.
+    fn oam_blocked(&self) -> bool;
+
+    /// Number of frames the PPU has finished drawing.
+    fn frame_count(&self) -> u64;
+
+    /// Starts counting frames from 0 again.
+    fn reset_frame_count(&mut self);
+
+    /// True iff the LCD and PPU are switched on, bit 7 of LCDC.
+    fn lcd_enabled(&self) -> bool;
+
+    /// The line currently being drawn, LY.
+    fn ly(&self) -> u8;
+
+    /// The PPU's current mode (0-3), as numbered in STAT.
+    fn mode(&self) -> u8;
+
+    /// Dots (0-455) elapsed since the start of the current line.
+    fn scanline_dot(&self) -> u16;
+
+    /// T-cycles until the PPU next requests a VBlank or STAT interrupt, assuming no registers are
+    /// written in the meantime. None while the LCD is off.
+    fn cycles_until_next_interrupt(&self) -> Option<u64>;
+
+    /// True iff HBlank has started since the last call, see `Lcd::take_hblank_started`.
+    fn take_hblank_started(&mut self) -> bool;
+
+    /// Indices of the tiles in `tile_data` written since the last call, in ascending order, so a
+    /// tile viewer only has to redraw those. Every tile counts as written at power on and reset.
+    fn take_dirty_tiles(&mut self) -> Vec<usize>;
+
+    /// Switches between DMG and CGB behaviour.
+    fn set_cgb_mode(&mut self, enabled: bool);
+
+    /// CGB background palette RAM, 8 palettes of 4 little-endian BGR555 colors.
+    fn bg_palette_ram(&self) -> &[u8];
+
+    /// CGB object palette RAM, laid out like `bg_palette_ram`.
+    fn obj_palette_ram(&self) -> &[u8];
+
+    /// Returns the PPU to its power-on state, clearing memory, registers and the screen. Layers
+    /// hidden for debugging stay hidden.
+    fn reset(&mut self);
+
+    /// The pixels displayed on the LCD, 160x144 in row-major order.
+    fn screen(&self) -> &[TileColor];
+
+    /// Draws every line of the frame from the PPU's current state straight away, using the
+    /// active graphics engine, and returns the new screen. The engine's screen is replaced, but
+    /// the PPU's timing and registers are left as they were. The frame is blank while the LCD is
+    /// off, and has no sprites while `dma_active` hides OAM from the search, as in `step`.
+    fn force_render(&mut self, dma_active: bool) -> Vec<TileColor>;
+
+    /// Hides or shows a layer on the display, overriding the enable bits in LCDC. Only affects
+    /// what is drawn, not emulation.
+    fn set_layer_enabled(&mut self, layer: Layer, enabled: bool);
+
+    /// Encodes the PPU's memory, registers and timing for a save state. The screen isn't
+    /// included, since it's redrawn over the next frame.
+    fn save_state(&self) -> Vec<u8>;
+
+    /// Restores a state from `save_state`. Layers hidden for debugging stay hidden.
+    fn load_state(&mut self, state: &[u8]) -> Result<()>;
+}
+
+/// Renders the contents of a [`BasePpu`]. Engines can be swapped while the emulator is running,
+/// so any state they keep must be derivable from [`PpuData`].
+pub trait GraphicsEngine: Any {
+    /// Called after every write to PPU memory, so the engine can keep its caches in sync.
+    fn after_write(&mut self, _data: &PpuData, _address: Address) {}
+
+    /// Rebuilds any cached state from scratch, e.g. when the engine is swapped in.
+    fn rebuild_cache(&mut self, data: &PpuData) {
+        for address in 0x8000..0x9800 {
+            self.after_write(data, address);
+        }
+    }
+
+    /// Changes the colors used by any RGBA output the engine draws itself.
+    fn set_palette(&mut self, _palette: Palette) {}
+
+    /// Draws the pixel at the given screen position.
+    fn place_pixel(&mut self, data: &PpuData, x: u8, y: u8);
+
+    /// Blanks the screen and drops any state kept between pixels.
+    fn reset(&mut self);
+
+    /// The pixels displayed on the LCD, 160x144 in row-major order.
+    fn screen(&self) -> &[TileColor];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oam_data_decodes_cgb_attributes() {
+        // Palette 5, VRAM bank 1, plus the DMG palette bit and x flip
+        let oam_data = OamData::new(&[16, 8, 0, 0b0011_1101]);
+        assert_eq!(5, oam_data.cgb_palette_number());
+        assert_eq!(1, oam_data.vram_bank());
+        assert_eq!(1, oam_data.palette_number());
+        assert!(oam_data.x_flip());
+
+        let oam_data = OamData::new(&[16, 8, 0, 0b1100_0010]);
+        assert_eq!(2, oam_data.cgb_palette_number());
+        assert_eq!(0, oam_data.vram_bank());
+    }
+}
@@ -0,0 +1,415 @@
+use super::{sprite_has_priority, GraphicsEngine, Layer, OamData, PpuData, TileColor};
+
+/// A graphics engine without an attached gui, which renders into an in-memory screen.
+pub struct NoGuiEngine {
+    /// The pixels displayed on the LCD, 160x144 in row-major order
+    screen: Vec<TileColor>,
+}
+
+impl Default for NoGuiEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NoGuiEngine {
+    pub fn new() -> NoGuiEngine {
+        NoGuiEngine {
+            screen: vec![TileColor::White; 160 * 144],
+        }
+    }
+
+    /// Finds the sprite drawn on top at the given screen position, out of those on the current
+    /// scanline with an opaque pixel there. Returns the pixel's color index along with the sprite.
+    fn get_obj_pixel(&self, data: &PpuData, x: u8, y: u8) -> Option<(u8, OamData)> {
+        let height = data.obj_height();
+        let by_x = data.obj_priority_by_x();
+        let x = i16::from(x);
+        let mut top: Option<(u8, OamData)> = None;
+
+        for oam_data in data.scanline_objects() {
+            let left = i16::from(oam_data.x_pos()) - 8;
+            if !(left..left + 8).contains(&x) {
+                continue;
+            }
+
+            let mut col = (x - left) as u8;
+            let mut row = (i16::from(y) - (i16::from(oam_data.y_pos()) - 16)) as u8;
+            if oam_data.x_flip() {
+                col = 7 - col;
+            }
+            if oam_data.y_flip() {
+                row = height - 1 - row;
+            }
+
+            // In 8x16 mode the lowest bit of the tile index is ignored
+            let tile_index = if height == 16 {
+                oam_data.tile_index() & 0xfe
+            } else {
+                oam_data.tile_index()
+            };
+            let color_index = data.get_banked_tile_pixel(
+                oam_data.vram_bank(),
+                usize::from(tile_index) + usize::from(row / 8),
+                usize::from(row % 8),
+                usize::from(col),
+            );
+            if color_index == 0 {
+                continue;
+            }
+            if !by_x {
+                return Some((color_index, oam_data.clone()));
+            }
+            // Objects are in OAM order, so a strict comparison lets the earlier one win ties
+            if top
+                .as_ref()
+                .is_none_or(|(_, top_data)| oam_data.x_pos() < top_data.x_pos())
+            {
+                top = Some((color_index, oam_data.clone()));
+            }
+        }
+
+        top
+    }
+}
+
+impl GraphicsEngine for NoGuiEngine {
+    /// Draws a single pixel of the screen by compositing the background and sprites.
+    fn place_pixel(&mut self, data: &PpuData, x: u8, y: u8) {
+        let lcd = &data.lcd;
+
+        // When the background is disabled it is drawn as white, which sprites always cover
+        let bg_enabled = lcd.lcd_control.bg_window_enable && data.layer_enabled(Layer::Background);
+        let window_enabled = lcd.window_on_line()
+            && u16::from(x) + 7 >= u16::from(lcd.wx)
+            && data.layer_enabled(Layer::Window);
+        let ((bg_color_index, bg_priority), mut color) = if window_enabled {
+            let pixel = data.get_window_pixel(x);
+            (pixel, TileColor::from_palette(lcd.bgp, pixel.0))
+        } else if bg_enabled {
+            let pixel = data.get_bg_pixel(x, y);
+            (pixel, TileColor::from_palette(lcd.bgp, pixel.0))
+        } else {
+            ((0, false), TileColor::White)
+        };
+
+        if lcd.lcd_control.obj_enable && data.layer_enabled(Layer::Sprites) {
+            if let Some((obj_color_index, oam_data)) = self.get_obj_pixel(data, x, y) {
+                // A CGB background tile's priority attribute works like the sprite's own flag
+                if sprite_has_priority(
+                    bg_color_index,
+                    obj_color_index,
+                    oam_data.bg_window_over_obj() || bg_priority,
+                ) {
+                    color = if data.cgb_mode {
+                        TileColor::from_cgb_palette(
+                            &data.obj_palette_ram,
+                            oam_data.cgb_palette_number(),
+                            obj_color_index,
+                        )
+                    } else if oam_data.palette_number() == 0 {
+                        TileColor::from_palette(lcd.obp0, obj_color_index)
+                    } else {
+                        TileColor::from_palette(lcd.obp1, obj_color_index)
+                    };
+                }
+            }
+        }
+
+        self.screen[usize::from(y) * 160 + usize::from(x)] = color;
+    }
+
+    fn screen(&self) -> &[TileColor] {
+        &self.screen
+    }
+
+    fn reset(&mut self) {
+        self.screen.fill(TileColor::White);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::component::Addressable;
+    use crate::gameboy::GameBoyState;
+    use crate::ppu::{BasePpu, Ppu};
+
+    #[test]
+    fn test_bg_over_obj_only_shows_sprite_over_bg_color_0() {
+        let mut ppu = BasePpu::new(Box::new(NoGuiEngine::new()));
+
+        // Tile 1 is entirely color 2 and tile 2 is entirely color 3. Tile 0 stays color 0.
+        for row in 0..8 {
+            ppu.write(0x8010 + row * 2, &[0x00, 0xff]).unwrap();
+            ppu.write(0x8020 + row * 2, &[0xff, 0xff]).unwrap();
+        }
+        // Background: tile 0 at the first column, tile 1 at the second
+        ppu.write_u8(0x9801, 1).unwrap();
+
+        // Two sprites using tile 2 with the BG-over-OBJ flag set, one over each background tile
+        ppu.write(0xfe00, &[16, 8, 2, 0x80, 16, 16, 2, 0x80])
+            .unwrap();
+
+        // LCD on, 0x8000 tile data, sprites and background enabled
+        ppu.write_u8(0xff40, 0x93).unwrap();
+        ppu.write_u8(0xff47, 0xe4).unwrap();
+        ppu.write_u8(0xff48, 0xe4).unwrap();
+
+        ppu.search_oam();
+        let (data, engine) = ppu.split_mut();
+        for x in 0..160 {
+            engine.place_pixel(data, x, 0);
+        }
+
+        let screen = ppu.engine().screen();
+        assert!(screen[0..8].iter().all(|c| *c == TileColor::Black));
+        assert!(screen[8..16].iter().all(|c| *c == TileColor::DarkGrey));
+    }
+
+    #[test]
+    fn test_cgb_sprite_uses_its_vram_bank_and_palette_ram() {
+        let mut ppu = BasePpu::new(Box::new(NoGuiEngine::new()));
+        ppu.set_cgb_mode(true);
+
+        // Tile 1 is entirely color 3 in VRAM bank 1, and color 0 in bank 0
+        ppu.write_u8(0xff4f, 1).unwrap();
+        for row in 0..8 {
+            ppu.write(0x8010 + row * 2, &[0xff, 0xff]).unwrap();
+        }
+        ppu.write_u8(0xff4f, 0).unwrap();
+        // OBJ palette 2's color 3 is white, while all other palette RAM stays black
+        ppu.write_u8(0xff6a, 0x80 | (2 * 8 + 3 * 2)).unwrap();
+        for byte in 0x7fffu16.to_le_bytes() {
+            ppu.write_u8(0xff6b, byte).unwrap();
+        }
+
+        // A sprite using tile 1 from bank 1 with palette 2
+        ppu.write(0xfe00, &[16, 8, 1, 0b0000_1010]).unwrap();
+
+        // LCD on, 0x8000 tile data, sprites and background enabled. The DMG palettes are black.
+        ppu.write_u8(0xff40, 0x93).unwrap();
+        ppu.write_u8(0xff47, 0xff).unwrap();
+        ppu.write_u8(0xff48, 0xff).unwrap();
+
+        ppu.search_oam();
+        let (data, engine) = ppu.split_mut();
+        for x in 0..160 {
+            engine.place_pixel(data, x, 0);
+        }
+
+        let screen = ppu.engine().screen();
+        assert!(screen[0..8].iter().all(|c| *c == TileColor::White));
+        assert!(screen[8..160].iter().all(|c| *c == TileColor::Black));
+    }
+
+    #[test]
+    fn test_sprite_color_0_is_transparent_but_bg_color_0_is_drawn() {
+        let mut ppu = BasePpu::new(Box::new(NoGuiEngine::new()));
+
+        // Tile 1's left half is color 0 and its right half is color 3. Tile 0 stays color 0.
+        for row in 0..8 {
+            ppu.write(0x8010 + row * 2, &[0x0f, 0x0f]).unwrap();
+        }
+        // A sprite using tile 1 over the first background tile
+        ppu.write(0xfe00, &[16, 8, 1, 0]).unwrap();
+
+        // LCD on, 0x8000 tile data, sprites and background enabled
+        ppu.write_u8(0xff40, 0x93).unwrap();
+        // BGP maps color 0 to dark grey, while OBP0 maps color 0 to black and the rest to white
+        ppu.write_u8(0xff47, 0xe6).unwrap();
+        ppu.write_u8(0xff48, 0x03).unwrap();
+
+        ppu.search_oam();
+        let (data, engine) = ppu.split_mut();
+        for x in 0..160 {
+            engine.place_pixel(data, x, 0);
+        }
+
+        let screen = ppu.engine().screen();
+        assert!(screen[0..4].iter().all(|c| *c == TileColor::DarkGrey));
+        assert!(screen[4..8].iter().all(|c| *c == TileColor::White));
+        assert!(screen[8..160].iter().all(|c| *c == TileColor::DarkGrey));
+    }
+
+    #[test]
+    fn test_off_screen_sprites_count_toward_scanline_limit() {
+        let mut ppu = BasePpu::new(Box::new(NoGuiEngine::new()));
+
+        // Tile 1 is entirely color 3
+        for row in 0..8 {
+            ppu.write(0x8010 + row * 2, &[0xff, 0xff]).unwrap();
+        }
+
+        // Five sprites at X=0 and five at X=168 are all invisible, but fill the scanline's budget
+        for i in 0..10 {
+            let x = if i < 5 { 0 } else { 168 };
+            ppu.write(0xfe00 + i * 4, &[16, x, 1, 0]).unwrap();
+        }
+        // An 11th sprite on the same line, which would be visible
+        ppu.write(0xfe28, &[16, 8, 1, 0]).unwrap();
+
+        // LCD on, 0x8000 tile data, sprites and background enabled
+        ppu.write_u8(0xff40, 0x93).unwrap();
+        ppu.write_u8(0xff47, 0xe4).unwrap();
+        ppu.write_u8(0xff48, 0xe4).unwrap();
+
+        ppu.search_oam();
+        let (data, engine) = ppu.split_mut();
+        assert_eq!(10, data.scanline_objects().len());
+        for x in 0..160 {
+            engine.place_pixel(data, x, 0);
+        }
+
+        let screen = ppu.engine().screen();
+        assert!(screen[0..160].iter().all(|c| *c == TileColor::White));
+    }
+
+    #[test]
+    fn test_opri_selects_between_oam_and_x_priority() {
+        let mut ppu = BasePpu::new(Box::new(NoGuiEngine::new()));
+        ppu.set_cgb_mode(true);
+
+        // Tile 1 is entirely color 3 and tile 2 is entirely color 2
+        for row in 0..8 {
+            ppu.write(0x8010 + row * 2, &[0xff, 0xff]).unwrap();
+            ppu.write(0x8020 + row * 2, &[0x00, 0xff]).unwrap();
+        }
+        // The first sprite in OAM covers x=4..12, the second covers x=0..8
+        ppu.write(0xfe00, &[16, 12, 1, 0, 16, 8, 2, 0]).unwrap();
+        // OBJ palette 0 goes from white to black like the DMG palette 0xe4
+        ppu.write_u8(0xff6a, 0x80).unwrap();
+        for color in [0x7fffu16, 0x56b5, 0x294a, 0x0000] {
+            for byte in color.to_le_bytes() {
+                ppu.write_u8(0xff6b, byte).unwrap();
+            }
+        }
+
+        // LCD on, 0x8000 tile data, sprites and background enabled
+        ppu.write_u8(0xff40, 0x93).unwrap();
+        ppu.write_u8(0xff47, 0xe4).unwrap();
+        ppu.write_u8(0xff48, 0xe4).unwrap();
+
+        let overlap_color = |ppu: &mut BasePpu| {
+            ppu.search_oam();
+            let (data, engine) = ppu.split_mut();
+            for x in 0..160 {
+                engine.place_pixel(data, x, 0);
+            }
+            ppu.engine().screen()[4]
+        };
+
+        // OAM index priority
+        ppu.write_u8(0xff6c, 0).unwrap();
+        assert_eq!(0xfe, ppu.read_u8(0xff6c).unwrap());
+        assert_eq!(TileColor::Black, overlap_color(&mut ppu));
+
+        // X coordinate priority
+        ppu.write_u8(0xff6c, 1).unwrap();
+        assert_eq!(TileColor::DarkGrey, overlap_color(&mut ppu));
+    }
+
+    #[test]
+    fn test_dmg_draws_the_smaller_x_sprite_on_top() {
+        let mut ppu = BasePpu::new(Box::new(NoGuiEngine::new()));
+
+        // Tile 1 is entirely color 3 and tile 2 is entirely color 2
+        for row in 0..8 {
+            ppu.write(0x8010 + row * 2, &[0xff, 0xff]).unwrap();
+            ppu.write(0x8020 + row * 2, &[0x00, 0xff]).unwrap();
+        }
+        // The first sprite in OAM covers x=4..12, the second covers x=2..10
+        ppu.write(0xfe00, &[16, 12, 1, 0, 16, 10, 2, 0]).unwrap();
+        // LCD on, 0x8000 tile data, sprites and background enabled
+        ppu.write_u8(0xff40, 0x93).unwrap();
+        ppu.write_u8(0xff47, 0xe4).unwrap();
+        ppu.write_u8(0xff48, 0xe4).unwrap();
+
+        ppu.search_oam();
+        let (data, engine) = ppu.split_mut();
+        for x in 0..160 {
+            engine.place_pixel(data, x, 0);
+        }
+        let screen = ppu.engine().screen();
+        // The later sprite wins the overlap, and the earlier one still shows past its right edge
+        assert!(screen[2..10].iter().all(|c| *c == TileColor::DarkGrey));
+        assert!(screen[10..12].iter().all(|c| *c == TileColor::Black));
+
+        // OPRI only exists on a CGB
+        assert_eq!(0xff, ppu.read_u8(0xff6c).unwrap());
+    }
+
+    #[test]
+    fn test_equal_x_sprites_are_ordered_by_oam_index() {
+        let mut ppu = BasePpu::new(Box::new(NoGuiEngine::new()));
+
+        // Tile 1 is entirely color 3 and tile 2 is entirely color 2
+        for row in 0..8 {
+            ppu.write(0x8010 + row * 2, &[0xff, 0xff]).unwrap();
+            ppu.write(0x8020 + row * 2, &[0x00, 0xff]).unwrap();
+        }
+        // LCD on, 0x8000 tile data, sprites and background enabled
+        ppu.write_u8(0xff40, 0x93).unwrap();
+        ppu.write_u8(0xff47, 0xe4).unwrap();
+        ppu.write_u8(0xff48, 0xe4).unwrap();
+
+        // Two sprites at the same position, in both OAM orders, after an unrelated sprite
+        for (first, second, expected) in [(1, 2, TileColor::Black), (2, 1, TileColor::DarkGrey)] {
+            ppu.write(0xfe00, &[16, 40, 1, 0]).unwrap();
+            ppu.write(0xfe04, &[16, 8, first, 0, 16, 8, second, 0])
+                .unwrap();
+            ppu.search_oam();
+            let (data, engine) = ppu.split_mut();
+            for x in 0..160 {
+                engine.place_pixel(data, x, 0);
+            }
+            let screen = ppu.engine().screen();
+            assert!(screen[0..8].iter().all(|c| *c == expected));
+        }
+    }
+
+    #[test]
+    fn test_window_resumes_from_its_line_counter_when_reenabled() {
+        let gameboy = GameBoyState::new_raw(&[0x18, 0xfe], 0xc000, 0xc000).unwrap();
+        let mut ppu = gameboy.ppu.borrow_mut();
+
+        // Tile 1's top 4 rows are color 3 and its bottom 4 are color 1. Tile 2 is all color 2.
+        for row in 0..8 {
+            let tile_1_row = if row < 4 { [0xff, 0xff] } else { [0xff, 0x00] };
+            ppu.write(0x8010 + row * 2, &tile_1_row).unwrap();
+            ppu.write(0x8020 + row * 2, &[0x00, 0xff]).unwrap();
+        }
+        // The background is tile 2 and the window, from the 0x9c00 map, is tile 1
+        ppu.write(0x9800, &[2; 0x400]).unwrap();
+        ppu.write(0x9c00, &[1; 0x400]).unwrap();
+        ppu.write_u8(0xff47, 0xe4).unwrap();
+        ppu.write_u8(0xff4a, 0).unwrap();
+        ppu.write_u8(0xff4b, 7).unwrap();
+
+        // LCD on with the window, then turn the window off for lines 2-5
+        let window_on = 0xf1;
+        ppu.write_u8(0xff40, window_on).unwrap();
+        for (ly, lcdc) in [(2, window_on & !0x20), (6, window_on)] {
+            while ppu.read_u8(0xff44).unwrap() != ly {
+                ppu.step(&gameboy).unwrap();
+            }
+            ppu.write_u8(0xff40, lcdc).unwrap();
+        }
+        while ppu.frame_count() == 0 {
+            ppu.step(&gameboy).unwrap();
+        }
+
+        let column: Vec<TileColor> = (0..10).map(|y| ppu.screen()[y * 160]).collect();
+        use TileColor::*;
+        assert_eq!(
+            vec![
+                // Window lines 0 and 1
+                Black, Black, // Background
+                DarkGrey, DarkGrey, DarkGrey, DarkGrey,
+                // Window lines 2 to 5, continuing where it left off
+                Black, Black, LightGrey, LightGrey,
+            ],
+            column
+        );
+    }
+}
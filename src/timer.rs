@@ -2,7 +2,9 @@ use crate::component::{Address, Addressable, Steppable};
 use crate::error::Error;
 use crate::gameboy::Interrupt;
 use log::info;
+use serde::{Deserialize, Serialize};
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Timer {
     /// Number of clock cycles per second.
     div_clocksum: u64,
@@ -56,6 +58,23 @@ impl Timer {
         }
     }
 
+    /// The divider register, which the APU's frame sequencer is clocked from.
+    pub fn div(&self) -> u8 {
+        self.div
+    }
+
+    /// T-cycles until TIMA next overflows and requests an interrupt, assuming no registers are
+    /// written in the meantime. None while the timer is disabled.
+    pub fn cycles_until_overflow(&self) -> Option<u64> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let period = self.cpu_clock_speed() / self.get_frequency();
+        // After TAC switches to a faster rate, the count may already be past the new period
+        let until_increment = period.saturating_sub(self.timer_clocksum).max(1);
+        Some(until_increment + (0xff - u64::from(self.tima)) * period)
+    }
+
     fn _read(&mut self, address: Address) -> crate::error::Result<u8> {
         let value = match address {
             DIV => self.div,
@@ -116,7 +135,8 @@ impl Steppable for Timer {
         if self.is_enabled() {
             self.timer_clocksum += 1;
 
-            if self.timer_clocksum == self.cpu_clock_speed() / self.get_frequency() {
+            // The count can already be past the period if TAC just switched to a faster rate
+            if self.timer_clocksum >= self.cpu_clock_speed() / self.get_frequency() {
                 // Increment TIMA
                 self.tima = self.tima.wrapping_add(1);
 
@@ -133,3 +153,61 @@ impl Steppable for Timer {
         Ok(1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::GameBoyState;
+
+    #[test]
+    fn test_tac_selects_tima_frequency() {
+        let gameboy = GameBoyState::new_raw(&[], 0xc000, 0xc000).unwrap();
+        // TAC's low bits, with the T-cycles between TIMA increments
+        for (select, period) in [(0b00, 1024), (0b01, 16), (0b10, 64), (0b11, 256)] {
+            let mut timer = Timer::new();
+            timer.write_u8(TAC, 0b100 | select).unwrap();
+            for increments in 1..=3 {
+                for _ in 0..period - 1 {
+                    timer.step(&gameboy).unwrap();
+                }
+                assert_eq!(
+                    increments - 1,
+                    timer.read_u8(TIMA).unwrap(),
+                    "TAC {:#04b}",
+                    select
+                );
+                timer.step(&gameboy).unwrap();
+                assert_eq!(
+                    increments,
+                    timer.read_u8(TIMA).unwrap(),
+                    "TAC {:#04b}",
+                    select
+                );
+            }
+
+            // Clearing the enable bit stops TIMA
+            timer.write_u8(TAC, select).unwrap();
+            for _ in 0..2048 {
+                timer.step(&gameboy).unwrap();
+            }
+            assert_eq!(3, timer.read_u8(TIMA).unwrap(), "TAC {:#04b}", select);
+        }
+
+        // Switching to a faster rate mid-count increments TIMA straight away, then keeps
+        // counting at the new rate
+        let mut timer = Timer::new();
+        timer.write_u8(TAC, 0b100).unwrap();
+        for _ in 0..1000 {
+            timer.step(&gameboy).unwrap();
+        }
+        timer.write_u8(TAC, 0b101).unwrap();
+        timer.step(&gameboy).unwrap();
+        assert_eq!(1, timer.read_u8(TIMA).unwrap());
+        for increments in 2..=4 {
+            for _ in 0..16 {
+                timer.step(&gameboy).unwrap();
+            }
+            assert_eq!(increments, timer.read_u8(TIMA).unwrap());
+        }
+    }
+}
@@ -0,0 +1,22 @@
+//! Serializes byte arrays longer than the 32 elements serde supports, for use with
+//! `#[serde(with = "crate::utils::byte_array")]`.
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serializer};
+
+pub fn serialize<S: Serializer, const N: usize>(
+    array: &[u8; N],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(array)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+    deserializer: D,
+) -> Result<[u8; N], D::Error> {
+    let bytes = Vec::<u8>::deserialize(deserializer)?;
+    let length = bytes.len();
+    bytes
+        .try_into()
+        .map_err(|_| D::Error::invalid_length(length, &"an array of the right length"))
+}
@@ -1,3 +1,8 @@
+use serde::{Deserialize, Serialize};
+
+pub mod byte_array;
+
+#[derive(Serialize, Deserialize)]
 pub struct BitField(pub u8);
 
 impl BitField {
@@ -1,36 +1,187 @@
-use std::{thread, time::Duration};
+// Each integration test crate only uses some of these helpers
+#![allow(dead_code)]
+
+use std::time::Duration;
 
 use gameboy_emulator::{
     cartridge::Cartridge,
-    emulator::{events::EmulationEvent, GameboyEmulator},
+    emulator::events::EmulationEvent,
+    gameboy::{GameBoyState, Interrupt, FRAMES_PER_SECOND},
+    JoypadInput, TileColor,
 };
 
-pub fn test_rom(path: &str, target_serial_data: &[u8], mut timeout_duration: Duration) {
+pub const SCREEN_WIDTH: usize = 160;
+
+/// Runs a ROM until it writes `target_serial_data` to the serial port, failing if that doesn't
+/// happen within `timeout` of emulated time. Emulation runs on the calling thread and serial
+/// output is checked after every frame, so the result doesn't depend on the host's speed.
+pub fn test_rom(path: &str, target_serial_data: &[u8], timeout: Duration) {
     let bytes = std::fs::read(path).unwrap();
     let cartridge = Cartridge::cartridge_from_data(&bytes).expect("failed to build cartridge");
 
-    let (_, control_event_sender, event_receiver) =
-        GameboyEmulator::gameboy_thread_no_gui(cartridge).unwrap();
-
-    let sleep_duration = Duration::from_secs(1);
+    let (mut gameboy, receiver) = GameBoyState::new_headless();
+    gameboy.load_cartridge(cartridge).unwrap();
 
+    let frames = (timeout.as_secs_f64() * FRAMES_PER_SECOND).ceil() as u64;
     let mut serial_port_output = Vec::new();
-    let mut pass = false;
-
-    'outer: while !timeout_duration.is_zero() {
-        thread::sleep(sleep_duration.clone());
-        timeout_duration = timeout_duration.saturating_sub(sleep_duration);
-
-        while let Ok(event) = event_receiver.try_recv() {
+    for _ in 0..frames {
+        gameboy.tick_until_vblank();
+        for event in receiver.try_iter() {
             if let EmulationEvent::SerialData(byte) = event {
                 serial_port_output.push(byte);
                 if serial_port_output.ends_with(target_serial_data) {
-                    pass = true;
-                    break 'outer;
+                    print!("{}", readable_serial_output(&serial_port_output));
+                    return;
                 }
-                print!("{}", byte as char);
             }
         }
     }
-    assert!(pass);
+    panic!(
+        "{:?} not written to the serial port within {:?} of emulated time, got:\n{}",
+        String::from_utf8_lossy(target_serial_data),
+        timeout,
+        readable_serial_output(&serial_port_output)
+    );
+}
+
+/// Decodes serial output as UTF-8 for test logs, replacing invalid sequences. Control characters
+/// other than newlines are escaped so they can't garble the log.
+pub fn readable_serial_output(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .chars()
+        .map(|c| {
+            if c.is_control() && c != '\n' {
+                c.escape_default().to_string()
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Finds the first pixel (in row-major order) where two screens differ, returning its (x, y)
+/// coordinate along with the actual and expected colors.
+pub fn first_screen_difference(
+    actual: &[TileColor],
+    expected: &[TileColor],
+) -> Option<((usize, usize), TileColor, TileColor)> {
+    actual
+        .iter()
+        .zip(expected)
+        .position(|(a, e)| a != e)
+        .map(|i| ((i % SCREEN_WIDTH, i / SCREEN_WIDTH), actual[i], expected[i]))
+}
+
+/// Asserts that two screens are identical, reporting the first differing pixel on failure.
+pub fn assert_screen_matches(actual: &[TileColor], expected: &[TileColor]) {
+    assert_eq!(
+        expected.len(),
+        actual.len(),
+        "screens contain a different number of pixels"
+    );
+    if let Some(((x, y), actual_color, expected_color)) = first_screen_difference(actual, expected)
+    {
+        panic!(
+            "screens differ at ({}, {}): got {:?}, expected {:?}",
+            x, y, actual_color, expected_color
+        );
+    }
+}
+
+/// A joypad input pressed or released at the start of a frame, numbered from 1.
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayInput {
+    pub frame: u64,
+    pub input: JoypadInput,
+    pub pressed: bool,
+}
+
+/// A stable FNV-1a hash of a screen, suitable for storing as a golden value.
+pub fn screen_hash(screen: &[TileColor]) -> u64 {
+    screen.iter().fold(0xcbf29ce484222325, |hash, color| {
+        (hash ^ *color as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Powers on a headless gameboy with a ROM inserted. Emulation events are discarded.
+pub fn boot_rom(rom: &[u8]) -> GameBoyState {
+    let cartridge = Cartridge::cartridge_from_data(rom).expect("failed to build cartridge");
+    let (mut gameboy, _) = GameBoyState::new_headless();
+    gameboy.load_cartridge(cartridge).unwrap();
+    gameboy
+}
+
+/// Runs a ROM from power on for some frames, applying the logged inputs, and returns the hash of
+/// the screen after each frame.
+pub fn replay_frame_hashes(rom: &[u8], input_log: &[ReplayInput], frames: u64) -> Vec<u64> {
+    let mut gameboy = boot_rom(rom);
+
+    let mut hashes = Vec::new();
+    for frame in 1..=frames {
+        for input in input_log.iter().filter(|input| input.frame == frame) {
+            let mut joypad = gameboy.joypad.borrow_mut();
+            if !input.pressed {
+                joypad.key_released(input.input);
+            } else if !joypad.key_pressed(input.input) {
+                drop(joypad);
+                gameboy
+                    .memory_bus
+                    .borrow_mut()
+                    .interrupt(Interrupt::Joypad)
+                    .unwrap();
+            }
+        }
+        gameboy.run_to_frame(frame);
+        hashes.push(screen_hash(&gameboy.screen()));
+    }
+    hashes
+}
+
+/// Replays a ROM's input log, asserting that the screen after each frame has the expected hash.
+/// Panics with the first frame which diverges.
+pub fn verify_replay(rom: &[u8], input_log: &[ReplayInput], expected_frame_hashes: &[u64]) {
+    let hashes = replay_frame_hashes(rom, input_log, expected_frame_hashes.len() as u64);
+    if let Some(i) = hashes
+        .iter()
+        .zip(expected_frame_hashes)
+        .position(|(actual, expected)| actual != expected)
+    {
+        panic!(
+            "replay diverged at frame {}: got hash {:#018x}, expected {:#018x}",
+            i + 1,
+            hashes[i],
+            expected_frame_hashes[i]
+        );
+    }
+}
+
+/// Runs a mooneye test ROM until it finishes, which it signals by executing `LD B,B`, and
+/// asserts that it passed. Passing ROMs leave the Fibonacci numbers 3, 5, 8, 13, 21, 34 in
+/// B, C, D, E, H and L, while failing ones fill them with 0x42.
+pub fn test_mooneye_rom(path: &str, timeout: Duration) {
+    let bytes = std::fs::read(path).unwrap();
+    let mut gameboy = boot_rom(&bytes);
+
+    let frames = (timeout.as_secs_f64() * FRAMES_PER_SECOND).ceil() as u64;
+    while gameboy.frame_count() < frames {
+        let pc = gameboy.get_pc();
+        let opcode = gameboy.memory_bus.borrow_mut().peek(pc.into()).unwrap();
+        if opcode == 0x40 {
+            let cpu = gameboy.cpu_snapshot();
+            let registers = [cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l];
+            assert_eq!(
+                [3, 5, 8, 13, 21, 34],
+                registers,
+                "{} failed, registers B-L: {:02x?}",
+                path,
+                registers
+            );
+            return;
+        }
+        gameboy.tick();
+    }
+    panic!(
+        "{} didn't finish within {:?} of emulated time",
+        path, timeout
+    );
 }
@@ -0,0 +1,106 @@
+mod common;
+
+use common::ReplayInput;
+use gameboy_emulator::{JoypadInput, TileColor};
+
+fn screen_with_pixel(x: usize, y: usize, color: TileColor) -> Vec<TileColor> {
+    let mut screen = vec![TileColor::White; 160 * 144];
+    screen[y * 160 + x] = color;
+    screen
+}
+
+#[test]
+fn test_first_screen_difference_reports_coordinate() {
+    let actual = screen_with_pixel(37, 90, TileColor::Black);
+    let expected = screen_with_pixel(0, 0, TileColor::White);
+
+    assert_eq!(
+        Some(((37, 90), TileColor::Black, TileColor::White)),
+        common::first_screen_difference(&actual, &expected)
+    );
+    assert_eq!(None, common::first_screen_difference(&actual, &actual));
+}
+
+#[test]
+#[should_panic(expected = "screens differ at (37, 90): got Black, expected White")]
+fn test_assert_screen_matches_panics_on_mismatch() {
+    let actual = screen_with_pixel(37, 90, TileColor::Black);
+    let expected = screen_with_pixel(0, 0, TileColor::White);
+    common::assert_screen_matches(&actual, &expected);
+}
+
+#[test]
+fn test_readable_serial_output_escapes_control_characters() {
+    let output = b"cpu_instrs\n\x1b[0mPassed\t\xff";
+    assert_eq!(
+        "cpu_instrs\n\\u{1b}[0mPassed\\t\u{fffd}",
+        common::readable_serial_output(output)
+    );
+}
+
+/// A ROM which turns on the LCD, then keeps copying the action buttons into the background
+/// palette, so pressing A changes the screen.
+fn joypad_palette_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    let code = [
+        0x3e, 0x91, 0xe0, 0x40, // LD A,0x91; LDH (LCDC),A
+        0x3e, 0x10, 0xe0, 0x00, // LD A,0x10; LDH (P1),A -- select the action buttons
+        0xf0, 0x00, 0xe0, 0x47, // LDH A,(P1); LDH (BGP),A
+        0x18, 0xfa, // JR -6
+    ];
+    rom[0x100..0x100 + code.len()].copy_from_slice(&code);
+    rom
+}
+
+fn press_a_log() -> Vec<ReplayInput> {
+    vec![
+        ReplayInput {
+            frame: 2,
+            input: JoypadInput::A,
+            pressed: true,
+        },
+        ReplayInput {
+            frame: 4,
+            input: JoypadInput::A,
+            pressed: false,
+        },
+    ]
+}
+
+#[test]
+fn test_verify_replay_passes_on_identical_run() {
+    let rom = joypad_palette_rom();
+    let golden = common::replay_frame_hashes(&rom, &press_a_log(), 5);
+    // The input changes the screen, so the hashes depend on it
+    assert_ne!(golden[0], golden[2]);
+
+    common::verify_replay(&rom, &press_a_log(), &golden);
+}
+
+#[test]
+#[should_panic(expected = "replay diverged at frame 2")]
+fn test_verify_replay_reports_first_diverging_frame() {
+    let rom = joypad_palette_rom();
+    let golden = common::replay_frame_hashes(&rom, &press_a_log(), 5);
+    common::verify_replay(&rom, &[], &golden);
+}
+
+#[test]
+fn test_scheduler_matches_per_cycle_stepping() {
+    // The halt bug test spends most of its time halted, waiting on timer interrupts
+    let rom = std::fs::read("tests/blargg/gb-test-roms-master/halt_bug.gb").unwrap();
+    let frame_hashes = |scheduler_enabled| {
+        let mut gameboy = common::boot_rom(&rom);
+        gameboy.set_scheduler_enabled(scheduler_enabled);
+        (1..=120)
+            .map(|frame| {
+                gameboy.run_to_frame(frame);
+                common::screen_hash(&gameboy.screen())
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let per_cycle = frame_hashes(false);
+    assert_ne!(per_cycle[0], per_cycle[119]);
+    assert_eq!(per_cycle, frame_hashes(true));
+}
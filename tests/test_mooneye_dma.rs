@@ -0,0 +1,35 @@
+mod common;
+
+use std::time::Duration;
+
+fn oam_dma_test(name: &str) {
+    common::test_mooneye_rom(
+        &format!("tests/mooneye/acceptance/{}.gb", name),
+        Duration::from_secs(10),
+    );
+}
+
+#[test]
+fn mooneye_oam_dma_basic() {
+    oam_dma_test("oam_dma/basic");
+}
+
+#[test]
+fn mooneye_oam_dma_reg_read() {
+    oam_dma_test("oam_dma/reg_read");
+}
+
+#[test]
+fn mooneye_oam_dma_restart() {
+    oam_dma_test("oam_dma_restart");
+}
+
+#[test]
+fn mooneye_oam_dma_start() {
+    oam_dma_test("oam_dma_start");
+}
+
+#[test]
+fn mooneye_oam_dma_timing() {
+    oam_dma_test("oam_dma_timing");
+}
@@ -0,0 +1,40 @@
+use gameboy_emulator::{gameboy::GameBoyState, Address, Addressable, Result};
+
+/// A register which reads back the last value written to it, plus one.
+struct IncrementingRegister {
+    value: u8,
+}
+
+impl Addressable for IncrementingRegister {
+    fn read(&mut self, _address: Address, data: &mut [u8]) -> Result<()> {
+        data.fill(self.value.wrapping_add(1));
+        Ok(())
+    }
+
+    fn write(&mut self, _address: Address, data: &[u8]) -> Result<()> {
+        self.value = *data.last().unwrap_or(&self.value);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_cpu_reaches_a_peripheral_defined_outside_the_crate() {
+    // LD A,0x41; LDH (0x03),A; LDH A,(0x03); LD B,A; LDH (0x03),A; LDH A,(0x03); JR -2
+    let code = [
+        0x3e, 0x41, 0xe0, 0x03, 0xf0, 0x03, 0x47, 0xe0, 0x03, 0xf0, 0x03, 0x18, 0xfe,
+    ];
+    let mut gameboy = GameBoyState::new_raw(&code, 0xc000, 0xc000).unwrap();
+    let register = IncrementingRegister { value: 0 };
+    gameboy
+        .memory_bus
+        .borrow_mut()
+        .map_peripheral(0xff03..=0xff03, Box::new(register))
+        .unwrap();
+
+    while gameboy.get_pc() != 0xc00b {
+        gameboy.tick();
+    }
+    let cpu = gameboy.cpu_snapshot();
+    assert_eq!(0x42, cpu.b);
+    assert_eq!(0x43, cpu.a);
+}